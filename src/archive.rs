@@ -0,0 +1,73 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+// The emitted tree is staged in a scratch directory and packed at the end.
+pub fn staging_directory() -> PathBuf {
+    env::temp_dir().join(format!("ccthief-{}", process::id()))
+}
+
+pub fn write(staging: &Path, archive: &Path) {
+    let name = archive.file_name().unwrap().to_str().unwrap();
+    let archive = env::current_dir().unwrap().join(archive);
+
+    let mut command = if name.ends_with(".zip") {
+        let mut command = Command::new("zip");
+        command.arg("-qr").arg(&archive).arg(".").current_dir(staging);
+        command
+    } else {
+        let flags = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            "-czf"
+        } else if name.ends_with(".tar.xz") {
+            "-cJf"
+        } else if name.ends_with(".tar.bz2") {
+            "-cjf"
+        } else {
+            "-cf"
+        };
+        let mut command = Command::new("tar");
+        command.arg(flags).arg(&archive).arg("-C").arg(staging).arg(".");
+        command
+    };
+
+    let status = command.status().unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", command, why));
+    if !status.success() {
+        panic!("Couldn't create {:?}: {:?} exited with {}", archive, command, status);
+    }
+
+    fs::remove_dir_all(staging).unwrap();
+}
+
+pub fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// Streams every staged file to stdout, each one preceded by a `==> path <==` line.
+pub fn stream(staging: &Path) {
+    let mut files = vec![];
+    collect_files(staging, &mut files);
+    files.sort();
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    for file in files {
+        let contents = fs::read(&file).unwrap();
+        writeln!(stdout, "==> {} <==", file.strip_prefix(staging).unwrap().to_str().unwrap()).unwrap();
+        stdout.write_all(&contents).unwrap();
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            writeln!(stdout).unwrap();
+        }
+    }
+
+    fs::remove_dir_all(staging).unwrap();
+}
@@ -0,0 +1,45 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The symbols an assembly source exports with .globl or .global. Line and
+// block comments are left alone, a stray .globl in one is rare enough.
+pub fn globals(path: &Path) -> Vec<String> {
+    let contents = fs::read_to_string(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    let mut ret = vec![];
+
+    // Several statements can share a line, separated by ;
+    for statement in contents.lines().flat_map(|line| line.split(';')) {
+        let mut words = statement.trim().splitn(2, char::is_whitespace);
+        if !matches!(words.next(), Some(".globl") | Some(".global")) {
+            continue
+        }
+        for name in words.next().unwrap_or("").split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if !ret.iter().any(|n| n == name) {
+                ret.push(String::from(name));
+            }
+        }
+    }
+
+    ret
+}
+
+// The assembly `sources` defining any of `undefined`, with the names each one
+// provides. Mach-O prefixes every C name with an underscore.
+pub fn providing(sources: &[PathBuf], undefined: &BTreeSet<String>) -> BTreeMap<PathBuf, Vec<String>> {
+    let mut ret = BTreeMap::new();
+
+    for source in sources {
+        let provided = globals(source).into_iter()
+            .filter_map(|name| {
+                let name = if undefined.contains(&name) { name } else { String::from(name.strip_prefix('_')?) };
+                Some(name).filter(|name| undefined.contains(name))
+            })
+            .collect::<Vec<_>>();
+        if !provided.is_empty() {
+            ret.insert(source.clone(), provided);
+        }
+    }
+
+    ret
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use json;
+
+// Flags whose value is a path, attached (`-Ifoo`) or as the next argument.
+const PATH_FLAGS: &[&str] = &["-I", "-iquote", "-isystem", "-idirafter", "-include", "-isysroot", "--sysroot="];
+// Other flags that change how the sources parse.
+const KEPT_FLAGS: &[&str] = &["-D", "-U", "-std=", "-f", "-m", "-W"];
+
+// Bazel's execution root, where the relative paths of its actions point into.
+fn execution_root() -> PathBuf {
+    let output = Command::new("bazel")
+        .args(["info", "execution_root"])
+        .output()
+        .unwrap_or_else(|why| panic!("Couldn't run bazel: {}", why));
+    if !output.status.success() {
+        panic!("bazel info execution_root exited with {}", output.status);
+    }
+    PathBuf::from(String::from_utf8_lossy(&output.stdout).trim())
+}
+
+// Sandboxed actions see the execution root as their working directory, often
+// spelled /proc/self/cwd to keep the paths hermetic.
+fn resolve(path: &str, root: &Path) -> String {
+    let path = path.strip_prefix("/proc/self/cwd/").unwrap_or(path);
+    if Path::new(path).is_absolute() {
+        String::from(path)
+    } else {
+        String::from(root.join(path).to_str().unwrap())
+    }
+}
+
+fn add(arg: String, clang_args: &mut Vec<String>) {
+    if !clang_args.contains(&arg) {
+        clang_args.push(arg);
+    }
+}
+
+// The source and the flags worth keeping of one compiler invocation.
+fn compile_command(arguments: &[String], root: &Path, clang_args: &mut Vec<String>) -> Option<String> {
+    let mut source = None;
+    let mut args = arguments.iter().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "-c" {
+            source = args.next().map(|s| resolve(s, root));
+        } else if arg == "-o" || arg == "-MF" || arg == "-MT" {
+            args.next();
+        } else if let Some(flag) = PATH_FLAGS.iter().find(|f| arg.starts_with(*f)) {
+            let value = if arg.len() > flag.len() { arg[flag.len()..].to_string() } else { args.next()?.clone() };
+            add(format!("{}{}", flag, resolve(&value, root)), clang_args);
+        } else if KEPT_FLAGS.iter().any(|f| arg.starts_with(f)) && !arg.starts_with("-fdebug-prefix-map") {
+            add(arg.clone(), clang_args);
+        } else if !arg.starts_with('-') && source.is_none() && [".c", ".cc", ".cpp", ".m", ".mm"].iter().any(|ext| arg.ends_with(ext)) {
+            source = Some(resolve(arg, root));
+        }
+    }
+
+    source
+}
+
+fn strings(values: &[json::Value]) -> Vec<String> {
+    values.iter().filter_map(json::Value::as_str).map(String::from).collect()
+}
+
+// Reads the sources and flags of a Bazel build from either the output of
+// `bazel aquery --output=jsonproto 'mnemonic("CppCompile", ...)'` or a
+// compile_commands.json made by one of the Bazel extractors. Since the
+// extraction uses one set of flags for everything, the flags of all the
+// compile actions are merged.
+pub fn load(path: &Path, root: Option<&Path>) -> (Vec<String>, Vec<String>) {
+    let contents = fs::read_to_string(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    let database = json::parse(&contents).unwrap_or_else(|why| panic!("Couldn't parse {:?}: {}", path, why));
+    let root = root.map_or_else(execution_root, Path::to_path_buf);
+
+    let mut sources = vec![];
+    let mut clang_args = vec![];
+
+    if let Some(actions) = database.get("actions").and_then(json::Value::as_array) {
+        for action in actions {
+            if action.get("mnemonic").and_then(json::Value::as_str) != Some("CppCompile") {
+                continue
+            }
+            let arguments = strings(action.get("arguments").and_then(json::Value::as_array).map_or(&[][..], |a| a));
+            if let Some(source) = compile_command(&arguments, &root, &mut clang_args) {
+                sources.push(source);
+            }
+        }
+    } else if let Some(entries) = database.as_array() {
+        for entry in entries {
+            let arguments = match entry.get("arguments").and_then(json::Value::as_array) {
+                Some(arguments) => strings(arguments),
+                None => entry.get("command").and_then(json::Value::as_str).unwrap_or_default()
+                    .split_whitespace().map(String::from).collect(),
+            };
+            // The extractors use the execution root as the directory
+            let directory = entry.get("directory").and_then(json::Value::as_str).map_or_else(|| root.clone(), PathBuf::from);
+            if let Some(source) = compile_command(&arguments, &directory, &mut clang_args) {
+                sources.push(source);
+            }
+        }
+    } else {
+        panic!("{:?} is neither aquery output nor a compilation database", path);
+    }
+
+    sources.sort();
+    sources.dedup();
+    eprintln!("Found {} sources in {:?}", sources.len(), path);
+    (sources, clang_args)
+}
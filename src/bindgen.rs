@@ -0,0 +1,62 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clang::*;
+
+use compile_db::absolute_args;
+use emit;
+use rename::Renamer;
+use stubs::prototype;
+
+pub const HEADER_NAME: &str = "ccthief.h";
+pub const FILE_NAME: &str = "bindings.rs";
+
+// Writes an umbrella header with everything the extracted API consists of:
+// the emitted headers, plus prototypes of the target functions that are
+// only declared in sources.
+pub fn write_header(target_directory: &Path, headers: &BTreeSet<PathBuf>, functions: &[Entity], renamer: Option<&Renamer>) {
+    let mut contents = String::from("/* Umbrella header of the extracted API. */\n\n");
+    contents.push_str("#ifndef CCTHIEF_H\n#define CCTHIEF_H\n\n");
+
+    for header in headers {
+        contents.push_str(&format!("#include \"{}\"\n", header.to_str().unwrap()));
+    }
+
+    let prototypes = functions.iter()
+        .map(|function| {
+            let name = renamer.and_then(|r| r.renamed(function)).unwrap_or_else(|| function.get_name().unwrap());
+            prototype(function, &name).0
+        })
+        .collect::<BTreeSet<_>>();
+
+    if !prototypes.is_empty() {
+        contents.push('\n');
+    }
+    for prototype in prototypes {
+        contents.push_str(&format!("{};\n", prototype));
+    }
+
+    contents.push_str("\n#endif\n");
+    emit::write_if_changed(&target_directory.join(HEADER_NAME), contents);
+}
+
+// Runs bindgen on the umbrella header with the flags of the extraction,
+// their paths made absolute since bindgen runs in the output directory.
+pub fn run(target_directory: &Path, clang_args: &[String]) {
+    let clang_args = absolute_args(clang_args, &env::current_dir().unwrap());
+    let mut command = Command::new("bindgen");
+    command.arg(HEADER_NAME)
+        .arg("-o").arg(FILE_NAME)
+        .arg("--")
+        .arg("-I.")
+        .args(&clang_args)
+        .current_dir(target_directory);
+
+    eprintln!("Generating {}", FILE_NAME);
+    let status = command.status().unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", command, why));
+    if !status.success() {
+        panic!("Couldn't generate {}: {:?} exited with {}", FILE_NAME, command, status);
+    }
+}
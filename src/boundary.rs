@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use canonicalize;
+
+// `*` and `?` stop at a `/`, `**` doesn't.
+pub fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') =>
+            (0..=text.len()).any(|i| glob(&pattern[2..], &text[i..])),
+        Some(b'*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob(&pattern[1..], &text[i..])),
+        Some(b'?') => text.first().is_some_and(|&c| c != b'/') && glob(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob(&pattern[1..], &text[1..]),
+    }
+}
+
+// Headers the closure must not cross, e.g. `libfoo/*.h`. Symbols declared
+// there are left to the real library: the include stays and nothing they
+// depend on gets extracted.
+// With `--tracked-only` every file of the checkout git doesn't know is one too.
+pub struct Boundaries {
+    patterns: Vec<String>,
+    // Include directories of installed libraries
+    roots: Vec<PathBuf>,
+    // The checkout root and the files tracked in it
+    tracked: Option<(PathBuf, HashSet<PathBuf>)>,
+}
+
+impl Boundaries {
+    pub fn new(patterns: &[String]) -> Self {
+        Boundaries { patterns: patterns.to_vec(), roots: vec![], tracked: None }
+    }
+
+    pub fn add_roots(&mut self, roots: &[PathBuf]) {
+        self.roots.extend(roots.iter().cloned());
+    }
+
+    pub fn tracked_only(&mut self, root: PathBuf, tracked: HashSet<PathBuf>) {
+        self.tracked = Some((root, tracked));
+    }
+
+    // A pattern may match any trailing part of the path, so that it doesn't
+    // depend on where the sources are checked out.
+    pub fn contains(&self, path: &Path) -> bool {
+        if self.roots.iter().any(|root| path.starts_with(root)) {
+            return true
+        }
+
+        if let Some((ref root, ref tracked)) = self.tracked {
+            let path = canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+            if path.starts_with(root) && !tracked.contains(&path) {
+                return true
+            }
+        }
+
+        // Patterns are written with `/` everywhere
+        let path = path.to_str().unwrap().replace('\\', "/");
+        let path = path.as_bytes();
+        let suffixes = (0..path.len()).filter(|&i| i == 0 || path[i - 1] == b'/');
+
+        suffixes.flat_map(|i| self.patterns.iter().map(move |p| (i, p)))
+            .any(|(i, pattern)| glob(pattern.as_bytes(), &path[i..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_star_stays_in_a_directory() {
+        assert!(glob(b"libfoo/*.h", b"libfoo/foo.h"));
+        assert!(glob(b"libfoo/*.h", b"libfoo/.h"));
+        assert!(!glob(b"libfoo/*.h", b"libfoo/sub/foo.h"));
+        assert!(!glob(b"libfoo/*.h", b"libfoo/foo.c"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(glob(b"libfoo/**.h", b"libfoo/sub/dir/foo.h"));
+        assert!(glob(b"**/foo.h", b"a/b/foo.h"));
+        assert!(!glob(b"**/foo.h", b"a/b/bar.h"));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        assert!(glob(b"foo?.h", b"foo1.h"));
+        assert!(!glob(b"foo?.h", b"foo.h"));
+        assert!(!glob(b"foo?.h", b"foo12.h"));
+        assert!(!glob(b"a?b", b"a/b"));
+    }
+
+    #[test]
+    fn literal() {
+        assert!(glob(b"", b""));
+        assert!(glob(b"a/b.h", b"a/b.h"));
+        assert!(!glob(b"a/b.h", b"a/b.hh"));
+        assert!(!glob(b"a/b.h", b"a/b"));
+    }
+}
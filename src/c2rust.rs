@@ -0,0 +1,22 @@
+use std::path::Path;
+use std::process::Command;
+
+use compile_db;
+
+pub const OUTPUT_DIRECTORY: &str = "rust";
+
+// Transpiles the emitted tree into a Rust crate next to it.
+pub fn transpile(target_directory: &Path) {
+    let mut command = Command::new("c2rust");
+    command.arg("transpile")
+        .arg(compile_db::FILE_NAME)
+        .arg("--emit-build-files")
+        .arg("--output-dir").arg(OUTPUT_DIRECTORY)
+        .current_dir(target_directory);
+
+    eprintln!("Transpiling into {:?}", target_directory.join(OUTPUT_DIRECTORY));
+    let status = command.status().unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", command, why));
+    if !status.success() {
+        panic!("c2rust failed: {:?} exited with {}", command, status);
+    }
+}
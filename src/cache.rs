@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::iter;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use clang::*;
+
+use emit;
+use graph::{node_key, node_label};
+use json;
+use options::Options;
+use parse;
+use query;
+use sha256;
+use symbol_table::SymbolTable;
+use in_system_header;
+
+pub fn content_hash(path: &Path) -> Option<String> {
+    let contents = fs::read(path).ok()?;
+    Some(sha256::digest(&contents))
+}
+
+// Every translation unit gets its own entry, keyed by the contents of the
+// source and the flags it was parsed with rather than by where it is, so that
+// other checkouts of the same revision share it.
+fn entry_key(source: &str, clang_args: &[String]) -> Option<String> {
+    let (contents, path) = (content_hash(Path::new(source))?, relative(Path::new(source)));
+    let fields = iter::once(contents.as_bytes()).chain(iter::once(path.as_bytes())).chain(clang_args.iter().map(|arg| arg.as_bytes()));
+    Some(sha256::digest_all(fields))
+}
+
+// Paths are kept relative to the working directory, which is the root of the
+// checkout for whoever shares the cache.
+fn relative(path: &Path) -> String {
+    let directory = env::current_dir().unwrap();
+    String::from(path.strip_prefix(&directory).unwrap_or(path).to_str().unwrap())
+}
+
+// Where the entries live: a directory, possibly on a shared drive, or an HTTP
+// server answering GET and PUT of `URL/KEY.json`.
+#[derive(Debug)]
+pub enum Store {
+    Directory(PathBuf),
+    Http(String),
+}
+
+impl Store {
+    pub fn new(location: &str) -> Self {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Store::Http(String::from(location.trim_end_matches('/')))
+        } else {
+            Store::Directory(PathBuf::from(location))
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        match *self {
+            Store::Directory(ref directory) => fs::read_to_string(directory.join(format!("{}.json", key))).ok(),
+            Store::Http(ref url) => {
+                let output = Command::new("curl").arg("-sf").arg(format!("{}/{}.json", url, key)).output().ok()?;
+                if output.status.success() { String::from_utf8(output.stdout).ok() } else { None }
+            },
+        }
+    }
+
+    fn put(&self, key: &str, contents: String) {
+        match *self {
+            Store::Directory(ref directory) => {
+                fs::create_dir_all(directory).unwrap();
+                // Others may be reading the same entry, so it appears all at once
+                let temporary = directory.join(format!("{}.{}.tmp", key, process::id()));
+                fs::write(&temporary, contents).unwrap();
+                fs::rename(&temporary, directory.join(format!("{}.json", key))).unwrap();
+            },
+            Store::Http(ref url) => {
+                let mut child = Command::new("curl")
+                    .arg("-sf").arg("-T").arg("-")
+                    .arg(format!("{}/{}.json", url, key))
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .unwrap_or_else(|why| panic!("Couldn't run curl: {}", why));
+                child.stdin.take().unwrap().write_all(contents.as_bytes()).unwrap();
+                // A cache that can't be written to only costs time
+                if !child.wait().unwrap().success() {
+                    eprintln!("warning: couldn't upload {} to the cache at {}", key, url);
+                }
+            },
+        }
+    }
+}
+
+// The dependency graph of the parsed sources with entities identified by
+// their keys, and labelled, which is all the queries need.
+#[derive(Default)]
+pub struct Analysis {
+    // key -> (name, label)
+    pub names: BTreeMap<String, (String, String)>,
+    // (from, to, kind)
+    pub edges: BTreeSet<(String, String, String)>,
+}
+
+impl Analysis {
+    // Same as `query::rdeps`, minus the parsing.
+    pub fn rdeps(&self, name: &str) -> Option<BTreeSet<String>> {
+        let seeds = self.names.iter().filter(|(_, (n, _))| n == name).map(|(k, _)| k).collect::<HashSet<_>>();
+        if seeds.is_empty() {
+            return None
+        }
+
+        let mut reverse: HashMap<&String, Vec<&String>> = HashMap::new();
+        for (from, to, _) in &self.edges {
+            reverse.entry(to).or_default().push(from);
+        }
+
+        let mut visited = seeds.clone();
+        let mut q = seeds.iter().cloned().collect::<VecDeque<_>>();
+        while let Some(key) = q.pop_front() {
+            for user in reverse.get(key).into_iter().flatten() {
+                if visited.insert(user) {
+                    q.push_back(user);
+                }
+            }
+        }
+
+        Some(visited.difference(&seeds).map(|k| self.names[*k].1.clone()).collect())
+    }
+}
+
+// Saves the graph of a freshly parsed translation unit. Has to run before
+// declarations get attached to definitions from other translation units.
+pub fn store<'a>(
+    store: &Store,
+    tu: &'a TranslationUnit<'a>,
+    source: &str,
+    clang_args: &[String],
+    sym_table: &SymbolTable<'a>
+) {
+    let children = tu.get_entity().get_children();
+
+    let files = parse::files(tu, Path::new(source)).into_iter()
+        .filter_map(|path| content_hash(&path).map(|hash| (path, hash)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut analysis = Analysis::default();
+    for child in children.iter().filter(|c| sym_table.contains_key(c) && !in_system_header(c)) {
+        let from = node_key(child);
+        analysis.names.insert(from.clone(), (child.get_name().unwrap_or_default(), node_label(child)));
+
+        for (dep, kind) in query::edges(sym_table, child) {
+            let dep = match dep.get_kind() {
+                EntityKind::InclusionDirective => continue,
+                EntityKind::MacroExpansion => match dep.get_reference() {
+                    Some(definition) => definition,
+                    None => continue,
+                },
+                _ => dep,
+            };
+            let to = node_key(&dep);
+            analysis.names.insert(to.clone(), (dep.get_name().unwrap_or_default(), node_label(&dep)));
+            analysis.edges.insert((from.clone(), to, String::from(kind)));
+        }
+    }
+
+    let contents = json::object(&[
+        ("files", json::array(files.iter().map(|(path, hash)| json::object(&[
+            ("path", json::string(&relative(path))),
+            ("hash", json::string(hash)),
+        ])))),
+        ("names", json::array(analysis.names.iter().map(|(key, (name, label))| json::object(&[
+            ("key", json::string(key)),
+            ("name", json::string(name)),
+            ("label", json::string(label)),
+        ])))),
+        ("edges", json::array(analysis.edges.iter().map(|(from, to, kind)| json::object(&[
+            ("from", json::string(from)),
+            ("to", json::string(to)),
+            ("kind", json::string(kind)),
+        ])))),
+    ]);
+
+    if let Some(key) = entry_key(source, clang_args) {
+        store.put(&key, contents + "\n");
+    }
+}
+
+// The cached graph of all `sources`, as long as none of the files they were
+// built from changed since.
+pub fn load(store: &Store, sources: &[String], clang_args: &[String]) -> Option<Analysis> {
+    let mut ret = Analysis::default();
+
+    for source in sources {
+        let entry = store.get(&entry_key(source, clang_args)?)?;
+        let entry = json::parse(&entry).ok()?;
+        let field = |value: &json::Value, key: &str| value.get(key).and_then(json::Value::as_str).map(String::from);
+
+        for file in entry.get("files")?.as_array()? {
+            if content_hash(Path::new(&field(file, "path")?)) != field(file, "hash") {
+                return None
+            }
+        }
+        for node in entry.get("names")?.as_array()? {
+            ret.names.insert(field(node, "key")?, (field(node, "name")?, field(node, "label")?));
+        }
+        for edge in entry.get("edges")?.as_array()? {
+            ret.edges.insert((field(edge, "from")?, field(edge, "to")?, field(edge, "kind")?));
+        }
+    }
+
+    Some(ret)
+}
+
+// Where a whole extraction is kept: everything it was run with, in this
+// directory since the extracted files record absolute paths.
+fn extraction_key(options: &Options) -> String {
+    let (version, directory) = (get_version(), env::current_dir().unwrap());
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let fields = iter::once(version.as_str())
+        .chain(iter::once(directory.to_str().unwrap()))
+        .chain(args.iter().map(String::as_str))
+        // Targets and flags may come from files named on the command line
+        .chain(iter::once(""))
+        .chain(options.targets.iter().map(String::as_str))
+        .chain(iter::once(""))
+        .chain(options.clang_args.iter().map(String::as_str));
+    sha256::digest_all(fields.map(str::as_bytes))
+}
+
+// Saves the files an extraction wrote under the output directory, along with
+// every file it read so that the entry is only used while they're unchanged.
+pub fn store_extraction(store: &Store, options: &Options, tus: &[TranslationUnit], sources: &[String]) {
+    let read = tus.iter().zip(sources)
+        .flat_map(|(tu, source)| parse::files(tu, Path::new(source)))
+        .chain(options.asm_sources.iter().cloned())
+        .chain(options.roots_from_ld.iter().cloned())
+        .filter_map(|path| content_hash(&path).map(|hash| (path, hash)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut outputs = vec![];
+    for path in emit::written() {
+        let relative = match path.strip_prefix(&options.output) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => continue,
+        };
+        match String::from_utf8(fs::read(&path).unwrap()) {
+            Ok(contents) => outputs.push((relative, contents)),
+            Err(_) => {
+                eprintln!("Note: {:?} isn't UTF-8, the extraction isn't cached", path);
+                return
+            },
+        }
+    }
+
+    let contents = json::object(&[
+        ("files", json::array(read.iter().map(|(path, hash)| json::object(&[
+            ("path", json::string(path.to_str().unwrap())),
+            ("hash", json::string(hash)),
+        ])))),
+        ("outputs", json::array(outputs.iter().map(|(path, contents)| json::object(&[
+            ("path", json::string(path.to_str().unwrap())),
+            ("contents", json::string(contents)),
+        ])))),
+    ]);
+    store.put(&extraction_key(options), contents + "\n");
+}
+
+// The files a previous run with the same options extracted, relative to the
+// output directory, as long as nothing it read changed since.
+pub fn load_extraction(store: &Store, options: &Options) -> Option<Vec<(PathBuf, String)>> {
+    let entry = json::parse(&store.get(&extraction_key(options))?).ok()?;
+    let field = |value: &json::Value, key: &str| value.get(key).and_then(json::Value::as_str).map(String::from);
+
+    for file in entry.get("files")?.as_array()? {
+        if content_hash(Path::new(&field(file, "path")?)) != field(file, "hash") {
+            return None
+        }
+    }
+    entry.get("outputs")?.as_array()?.iter()
+        .map(|output| Some((PathBuf::from(field(output, "path")?), field(output, "contents")?)))
+        .collect()
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use json;
+
+const API: &str = ".cmake/api/v1";
+
+fn read(path: &Path) -> json::Value {
+    let contents = fs::read_to_string(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    json::parse(&contents).unwrap_or_else(|why| panic!("Couldn't parse {:?}: {}", path, why))
+}
+
+fn string<'v>(value: &'v json::Value, key: &str) -> Option<&'v str> {
+    value.get(key).and_then(json::Value::as_str)
+}
+
+fn array<'v>(value: &'v json::Value, key: &str) -> &'v [json::Value] {
+    value.get(key).and_then(json::Value::as_array).map_or(&[], |a| a)
+}
+
+// The newest reply index, asking CMake for a codemodel first if there is none.
+fn reply_index(build_directory: &Path) -> PathBuf {
+    let reply = build_directory.join(API).join("reply");
+
+    let newest = || {
+        fs::read_dir(&reply).ok()?
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap().to_str().unwrap().starts_with("index-"))
+            .max()
+    };
+
+    if let Some(index) = newest() {
+        return index
+    }
+
+    let query = build_directory.join(API).join("query");
+    fs::create_dir_all(&query).unwrap();
+    fs::write(query.join("codemodel-v2"), "").unwrap();
+
+    eprintln!("Running CMake in {:?} to get its codemodel", build_directory);
+    let status = Command::new("cmake")
+        .arg(build_directory)
+        .status()
+        .unwrap_or_else(|why| panic!("Couldn't run cmake: {}", why));
+    if !status.success() {
+        panic!("cmake exited with {}", status);
+    }
+
+    newest().unwrap_or_else(|| panic!("CMake left no reply in {:?}", reply))
+}
+
+// Reads the sources of `target`, or of every target, and their flags from
+// the replies of the CMake File API in `build_directory`. Like everywhere
+// else, the flags of all the compile groups are merged into one set.
+pub fn load(build_directory: &Path, target: Option<&str>) -> (Vec<String>, Vec<String>) {
+    let reply = build_directory.join(API).join("reply");
+    let index = read(&reply_index(build_directory));
+
+    let codemodel = array(&index, "objects").iter()
+        .find(|object| string(object, "kind") == Some("codemodel"))
+        .and_then(|object| string(object, "jsonFile"))
+        .map(|file| read(&reply.join(file)))
+        .unwrap_or_else(|| panic!("No codemodel in the CMake reply of {:?}", build_directory));
+
+    let source_directory = codemodel.get("paths").and_then(|p| string(p, "source")).map(PathBuf::from).unwrap();
+    let configuration = &array(&codemodel, "configurations")[0];
+
+    let mut sources = vec![];
+    let mut clang_args = vec![];
+    let mut found = false;
+
+    for entry in array(configuration, "targets") {
+        let name = string(entry, "name").unwrap();
+        if target.is_some_and(|target| target != name) {
+            continue
+        }
+        found = true;
+
+        let model = read(&reply.join(string(entry, "jsonFile").unwrap()));
+        let groups = array(&model, "compileGroups");
+
+        for source in array(&model, "sources") {
+            let group = match source.get("compileGroupIndex").and_then(json::Value::as_u64) {
+                Some(group) => &groups[group as usize],
+                // Headers and other files that aren't compiled
+                None => continue,
+            };
+            if !matches!(string(group, "language"), Some("C") | Some("CXX")) {
+                continue
+            }
+
+            let path = source_directory.join(string(source, "path").unwrap());
+            sources.push(String::from(path.to_str().unwrap()));
+
+            let fragments = array(group, "compileCommandFragments").iter()
+                .filter_map(|f| string(f, "fragment"))
+                .flat_map(|f| f.split_whitespace().map(String::from).collect::<Vec<_>>());
+            let includes = array(group, "includes").iter().map(|include| {
+                let flag = if include.get("isSystem") == Some(&json::Value::Bool(true)) { "-isystem" } else { "-I" };
+                format!("{}{}", flag, string(include, "path").unwrap())
+            });
+            let defines = array(group, "defines").iter().map(|define| format!("-D{}", string(define, "define").unwrap()));
+            let sysroot = group.get("sysroot").and_then(|s| string(s, "path")).map(|path| format!("--sysroot={}", path));
+
+            for arg in fragments.chain(includes).chain(defines).chain(sysroot) {
+                if !clang_args.contains(&arg) {
+                    clang_args.push(arg);
+                }
+            }
+        }
+    }
+
+    if let Some(target) = target.filter(|_| !found) {
+        eprintln!("No CMake target {} in {:?}", target, build_directory);
+        process::exit(1);
+    }
+
+    sources.sort();
+    sources.dedup();
+    eprintln!("Found {} sources in the CMake build {:?}", sources.len(), build_directory);
+    (sources, clang_args)
+}
@@ -0,0 +1,69 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use canonicalize;
+use emit;
+use json;
+
+pub const FILE_NAME: &str = "compile_commands.json";
+
+// Flags followed by a path, attached like -Iinclude or as the next argument.
+const PATH_FLAGS: &[&str] = &[
+    "-I", "-isystem", "-iquote", "-idirafter", "-include", "-imacros", "-isysroot", "-F", "--sysroot",
+];
+
+// `clang_args` with the paths in them made absolute against `directory`,
+// for running them from anywhere else.
+pub fn absolute_args(clang_args: &[String], directory: &Path) -> Vec<String> {
+    let absolute = |path: &str| directory.join(path).to_str().unwrap().to_string();
+    let mut ret = vec![];
+    let mut args = clang_args.iter();
+
+    while let Some(arg) = args.next() {
+        if PATH_FLAGS.contains(&arg.as_str()) {
+            ret.push(arg.clone());
+            ret.extend(args.next().map(|path| absolute(path)));
+            continue
+        }
+        if let Some(path) = arg.strip_prefix("--sysroot=") {
+            ret.push(format!("--sysroot={}", absolute(path)));
+            continue
+        }
+        // Longest first, -isystem over -I
+        let attached = PATH_FLAGS.iter()
+            .filter(|flag| arg.starts_with(*flag) && arg.len() > flag.len())
+            .max_by_key(|flag| flag.len());
+        match attached {
+            Some(flag) => ret.push(format!("{}{}", flag, absolute(&arg[flag.len()..]))),
+            None => ret.push(arg.clone()),
+        }
+    }
+
+    ret
+}
+
+// Writes compile_commands.json next to the emitted tree so that clangd and
+// friends see the same flags the extraction was done with. Some consumers,
+// c2rust among them, want `file` to be an absolute path.
+pub fn write(target_directory: &Path, emitted_sources: &[PathBuf], clang_args: &[String], absolute: bool) {
+    let directory = canonicalize(target_directory).unwrap();
+    // The flags were written for the directory ccthief runs in
+    let clang_args = absolute_args(clang_args, &env::current_dir().unwrap());
+
+    let entries = emitted_sources.iter().map(|source| {
+        let source = if absolute { directory.join(source) } else { source.clone() };
+        let file = source.to_str().unwrap();
+        let arguments = vec![String::from("cc"), String::from("-c"), String::from(file)].into_iter()
+            .chain(clang_args.iter().cloned())
+            .map(|arg| json::string(&arg));
+
+        json::object(&[
+            ("directory", json::string(directory.to_str().unwrap())),
+            ("file", json::string(file)),
+            ("arguments", json::array(arguments)),
+        ])
+    });
+
+    let contents = format!("{}\n", json::array(entries));
+    emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+}
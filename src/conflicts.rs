@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, HashMap};
+
+use clang::*;
+
+use source_file::SourceFile;
+use symbol_table::SymbolTable;
+use {get_location, get_path, in_system_header, sorted, symbol_text};
+
+fn text(entity: &Entity) -> Vec<String> {
+    symbol_text(entity, &SourceFile::open(&get_path(entity)), None).0
+}
+
+// Finds external symbols defined by more than one translation unit and keeps
+// a single definition for each: the one in the file given by `preferred`, or
+// the first one otherwise. Every edge to a dropped definition is redirected
+// to the kept one so that users in the other translation units still link.
+// Fails, leaving `sym_table` alone, when a preferred file has no definition.
+pub fn resolve<'a>(sym_table: &mut SymbolTable<'a>, preferred: &HashMap<String, String>) -> Result<(), String> {
+    let mut definitions = BTreeMap::new();
+
+    for entity in sorted(sym_table.keys()) {
+        if !entity.is_definition() || in_system_header(&entity) || entity.get_linkage() != Some(Linkage::External) {
+            continue
+        }
+        if !matches!(entity.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl) {
+            continue
+        }
+        if let Some(usr) = sym_table.usr(&entity) {
+            let copies = definitions.entry(usr).or_insert_with(Vec::new);
+            // A definition in a header is seen once per translation unit including it
+            if copies.iter().all(|c| get_location(c) != get_location(&entity)) {
+                copies.push(entity);
+            }
+        }
+    }
+
+    let mut replacements = HashMap::new();
+
+    for copies in definitions.values().filter(|c| c.len() > 1) {
+        let name = copies[0].get_name().unwrap();
+        let kept = match preferred.get(&name) {
+            Some(file) => match copies.iter().find(|c| get_path(c).ends_with(file)) {
+                Some(kept) => *kept,
+                None => return Err(format!("No definition of {} in {}", name, file)),
+            },
+            None => copies[0],
+        };
+
+        let identical = copies.iter().all(|c| text(c) == text(&copies[0]));
+        eprintln!("warning: {} is defined {} times{}:", name, copies.len(),
+            if identical { " (identical copies)" } else { "" });
+        for copy in copies {
+            eprintln!("  {}{}", get_location(copy), if *copy == kept { " (emitted)" } else { "" });
+        }
+        if !preferred.contains_key(&name) {
+            eprintln!("  use --prefer-definition {}=FILE to pick another one", name);
+        }
+
+        for copy in copies.iter().filter(|c| **c != kept) {
+            replacements.insert(*copy, kept);
+        }
+    }
+
+    for dropped in replacements.keys() {
+        sym_table.remove(dropped);
+    }
+
+    for desc in sym_table.values_mut() {
+        for (dropped, kept) in &replacements {
+            if desc.definitions.remove(dropped) {
+                desc.definitions.insert(*kept);
+            }
+            if let Some(kinds) = desc.deps.remove(dropped) {
+                desc.deps.entry(*kept).or_default().extend(kinds);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::iter;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use graph::{EdgeKind, Graph};
+use symbol_table::SymbolTable;
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+const SCHEMA: &str = "\
+CREATE TABLE symbols (
+    id TEXT PRIMARY KEY,
+    label TEXT NOT NULL,
+    name TEXT NOT NULL,
+    usr TEXT,
+    kind TEXT NOT NULL,
+    file TEXT NOT NULL,
+    start_line INTEGER NOT NULL,
+    start_column INTEGER NOT NULL,
+    end_line INTEGER NOT NULL,
+    end_column INTEGER NOT NULL
+);
+CREATE TABLE edges (
+    source TEXT NOT NULL REFERENCES symbols(id),
+    target TEXT NOT NULL REFERENCES symbols(id),
+    kind TEXT NOT NULL
+);
+CREATE INDEX symbols_name ON symbols(name);
+CREATE INDEX symbols_usr ON symbols(usr);
+CREATE INDEX symbols_file ON symbols(file);
+CREATE INDEX edges_source ON edges(source);
+CREATE INDEX edges_target ON edges(target);
+";
+
+// Writes every parsed symbol and every edge between them into a fresh SQLite
+// database, built by piping SQL into the sqlite3 shell.
+pub fn write<'a>(path: &Path, sym_table: &SymbolTable<'a>) {
+    // Along with whatever they point to, so that edges to macros get a row as well
+    let everything = sym_table.iter()
+        .flat_map(|(entity, desc)| iter::once(*entity).chain(desc.deps.keys().cloned()).chain(desc.definitions.iter().cloned()))
+        .collect::<HashSet<_>>();
+    let graph = Graph::new(&everything, sym_table, &EdgeKind::ALL.iter().cloned().collect());
+
+    let mut sql = String::from("BEGIN;\n");
+    sql.push_str(SCHEMA);
+
+    for (key, node) in &graph.nodes {
+        let name = node.label.split('@').next().unwrap();
+        sql.push_str(&format!("INSERT INTO symbols VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {});\n",
+            quote(key), quote(&node.label), quote(name), node.usr.as_ref().map_or_else(|| String::from("NULL"), |usr| quote(usr)),
+            quote(&node.kind), quote(&node.file), node.range.0, node.range.1, node.range.2, node.range.3));
+    }
+    for (source, target, kind) in &graph.edges {
+        sql.push_str(&format!("INSERT INTO edges VALUES ({}, {}, {});\n", quote(source), quote(target), quote(kind)));
+    }
+    sql.push_str("COMMIT;\n");
+
+    let _ = fs::remove_file(path);
+
+    let mut child = Command::new("sqlite3")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|why| panic!("Couldn't run sqlite3: {}", why));
+    child.stdin.take().unwrap().write_all(sql.as_bytes()).unwrap();
+
+    let status = child.wait().unwrap();
+    if !status.success() {
+        panic!("Couldn't write {:?}: sqlite3 exited with {}", path, status);
+    }
+    eprintln!("Wrote {} symbols and {} edges to {:?}", graph.nodes.len(), graph.edges.len(), path);
+}
@@ -0,0 +1,38 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+
+use clang::*;
+
+use graph::node_key;
+use symbol_table::SymbolTable;
+use {get_path, in_system_header};
+
+// Writes every parsed top-level symbol that didn't make it into the closure,
+// grouped by file, so that things like registration tables that are only
+// reachable through the linker don't go missing unnoticed.
+pub fn write<'a>(path: &Path, sym_table: &SymbolTable<'a>, extracted: &HashSet<Entity<'a>>) {
+    let extracted = extracted.iter().map(node_key).collect::<HashSet<_>>();
+    let mut per_file = BTreeMap::new();
+
+    for entity in sym_table.keys().filter(|e| !in_system_header(e)) {
+        if extracted.contains(&node_key(entity)) {
+            continue
+        }
+
+        let line = entity.get_location().unwrap().get_file_location().line;
+        let name = entity.get_name().unwrap_or_else(|| String::from("<anonymous>"));
+        per_file.entry(get_path(entity)).or_insert_with(BTreeSet::new)
+            .insert((line, name, format!("{:?}", entity.get_kind())));
+    }
+
+    let mut contents = String::new();
+    for (file, symbols) in per_file {
+        contents.push_str(&format!("{}:\n", file.to_str().unwrap()));
+        for (line, name, kind) in symbols {
+            contents.push_str(&format!("  {:6} {} ({})\n", line, name, kind));
+        }
+    }
+
+    fs::write(path, contents).unwrap();
+}
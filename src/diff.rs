@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use archive::collect_files;
+use compile_db;
+use incremental;
+use json;
+use manifest;
+use source_file;
+use source_map;
+use vendor;
+
+// Generated alongside the sources and bound to where and when they were
+// written, like the absolute directory in compile_commands.json, so they
+// differ between any two runs.
+pub const METADATA: &[&str] = &[
+    compile_db::FILE_NAME, source_map::FILE_NAME, manifest::FILE_NAME, incremental::FILE_NAME, vendor::FILE_NAME,
+];
+
+// (output file, symbol name, occurrence in that file) -> emitted lines
+type Symbols = BTreeMap<(String, String, usize), Vec<String>>;
+
+fn files(directory: &Path) -> BTreeSet<PathBuf> {
+    let mut files = vec![];
+    collect_files(directory, &mut files);
+    files.iter()
+        .map(|f| f.strip_prefix(directory).unwrap().to_path_buf())
+        .filter(|f| !METADATA.iter().any(|m| f == Path::new(m)))
+        .collect()
+}
+
+// Recovers the emitted symbols of an extraction from its source map.
+fn symbols(directory: &Path) -> Symbols {
+    let mut ret = BTreeMap::new();
+
+    let map = match fs::read_to_string(directory.join(source_map::FILE_NAME)) {
+        Ok(map) => json::parse(&map).unwrap_or_else(|why| panic!("Couldn't parse source map: {}", why)),
+        Err(_) => {
+            eprintln!("No {} in {:?}, only comparing files", source_map::FILE_NAME, directory);
+            return ret
+        },
+    };
+
+    let mut occurrences = BTreeMap::new();
+    let mut file_lines = BTreeMap::new();
+
+    for mapping in map.as_array().unwrap() {
+        let symbol = match mapping.get("symbol").and_then(json::Value::as_str) {
+            Some(symbol) => String::from(symbol),
+            None => continue,
+        };
+        let output = String::from(mapping.get("output").and_then(json::Value::as_str).unwrap());
+        let lines = mapping.get("output_lines").and_then(json::Value::as_array).unwrap();
+        let (start, end) = (lines[0].as_u64().unwrap() as usize, lines[1].as_u64().unwrap() as usize);
+
+        let text = file_lines.entry(output.clone()).or_insert_with(|| {
+            fs::read(directory.join(&output)).map(|bytes| source_file::decode(&bytes)).unwrap_or_default()
+                .lines().map(String::from).collect::<Vec<_>>()
+        });
+        let lines = text.get(start - 1 .. end).map_or(vec![], |l| l.to_vec());
+
+        let occurrence = occurrences.entry((output.clone(), symbol.clone())).or_insert(0);
+        ret.insert((output, symbol, *occurrence), lines);
+        *occurrence += 1;
+    }
+
+    ret
+}
+
+// Prints what changed in `current` compared to the `baseline` extraction.
+pub fn report(current: &Path, baseline: &Path) {
+    let (old_files, new_files) = (files(baseline), files(current));
+
+    println!("Files:");
+    for file in new_files.difference(&old_files) {
+        println!("  + {}", file.to_str().unwrap());
+    }
+    for file in old_files.difference(&new_files) {
+        println!("  - {}", file.to_str().unwrap());
+    }
+    for file in new_files.intersection(&old_files) {
+        if fs::read(current.join(file)).unwrap() != fs::read(baseline.join(file)).unwrap() {
+            println!("  ~ {}", file.to_str().unwrap());
+        }
+    }
+
+    let (old_symbols, new_symbols) = (symbols(baseline), symbols(current));
+
+    println!("Symbols:");
+    for (key, lines) in &new_symbols {
+        let (file, symbol, _) = key;
+        match old_symbols.get(key) {
+            None => println!("  + {} ({})", symbol, file),
+            Some(old_lines) if old_lines != lines => println!("  ~ {} ({})", symbol, file),
+            _ => (),
+        }
+    }
+    for (file, symbol, _) in old_symbols.keys().filter(|k| !new_symbols.contains_key(k)) {
+        println!("  - {} ({})", symbol, file);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use source_file;
+use source_map::{Mapping, SourceMap};
+
+// Every file written so far, changed or not, for caching what the run produced
+static WRITTEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+// Leaves `path` alone when it already has the right contents, so that
+// rebuilding an unchanged extraction doesn't touch its timestamps.
+pub fn write_if_changed<C: AsRef<[u8]>>(path: &Path, contents: C) {
+    if fs::read(path).ok().as_deref() != Some(contents.as_ref()) {
+        fs::write(path, contents).unwrap();
+    }
+    WRITTEN.lock().unwrap().insert(path.to_path_buf());
+}
+
+pub fn written() -> BTreeSet<PathBuf> {
+    WRITTEN.lock().unwrap().clone()
+}
+
+// Writes the emitted files on threads of its own, so that extractions of
+// hundreds of files don't wait on each one in turn. Everything sent to it is
+// on disk once it's dropped.
+pub struct Writer {
+    sender: Option<mpsc::SyncSender<(PathBuf, Vec<u8>)>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Writer {
+    pub fn new(jobs: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<(PathBuf, Vec<u8>)>(jobs * 4);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..jobs.max(1)).map(|_| {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let next = receiver.lock().unwrap().recv();
+                let (path, contents) = match next {
+                    Ok(file) => file,
+                    Err(_) => break,
+                };
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                write_if_changed(&path, contents);
+            })
+        }).collect();
+
+        Writer { sender: Some(sender), workers }
+    }
+
+    pub fn write(&self, path: PathBuf, contents: Vec<u8>) {
+        self.sender.as_ref().unwrap().send((path, contents)).unwrap();
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Hanging up lets the workers run out of files
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() && !thread::panicking() {
+                panic!("Couldn't write the emitted files");
+            }
+        }
+    }
+}
+
+// An emitted file that keeps track of its own line count so that every
+// chunk copied from the original sources can be recorded in the source map.
+// The contents are written out when it gets dropped.
+pub struct OutputFile<'m> {
+    destination: PathBuf,
+    contents: String,
+    // Taken from the first original file copied from
+    newline: Option<&'static str>,
+    path: PathBuf,
+    line: u32,
+    // With #line markers, the original file and line the next line copied carries on from
+    line_markers: bool,
+    next_original: Option<(PathBuf, u32)>,
+    source_map: &'m mut SourceMap,
+    writer: &'m Writer,
+}
+
+impl<'m> OutputFile<'m> {
+    pub fn create(target_directory: &Path, path: PathBuf, source_map: &'m mut SourceMap, writer: &'m Writer) -> Self {
+        OutputFile {
+            destination: target_directory.join(&path),
+            contents: String::new(),
+            newline: None,
+            path,
+            line: 1,
+            line_markers: false,
+            next_original: None,
+            source_map,
+            writer,
+        }
+    }
+
+    // Puts a #line marker ahead of every chunk that doesn't carry on from the
+    // previous one, so that __FILE__, __LINE__ and the diagnostics of the
+    // compiler point at the original sources.
+    pub fn set_line_markers(&mut self, line_markers: bool) {
+        self.line_markers = line_markers;
+    }
+
+    // Writes lines copied from `original_lines` of `original`.
+    pub fn write_symbol(&mut self, lines: &[String], original: &Path, original_lines: (u32, u32), symbol: Option<String>) {
+        if lines.is_empty() {
+            return
+        }
+
+        if self.newline.is_none() {
+            self.newline = Some(source_file::newline(original));
+        }
+
+        if self.line_markers {
+            let start = (original.to_path_buf(), original_lines.0);
+            if self.next_original.as_ref() != Some(&start) {
+                let path = original.to_str().unwrap().replace('\\', "/").replace('"', "\\\"");
+                self.write_lines(&[format!("#line {} \"{}\"", original_lines.0, path)]);
+            }
+            self.next_original = Some((start.0, original_lines.0 + lines.len() as u32));
+        }
+
+        self.source_map.add(Mapping {
+            output: self.path.clone(),
+            output_lines: (self.line, self.line + lines.len() as u32 - 1),
+            original: original.to_path_buf(),
+            original_lines,
+            symbol,
+        });
+
+        self.write_lines(lines);
+    }
+
+    // Writes lines that the extractor made up and that have no origin.
+    pub fn write_synthetic(&mut self, lines: &[String]) {
+        self.write_lines(lines);
+        self.next_original = None;
+    }
+
+    fn write_lines(&mut self, lines: &[String]) {
+        for line in lines {
+            self.contents.push_str(line);
+            self.contents.push('\n');
+        }
+        self.line += lines.len() as u32;
+    }
+}
+
+impl<'m> Drop for OutputFile<'m> {
+    fn drop(&mut self) {
+        let contents = match self.newline {
+            Some(newline) if newline != "\n" => self.contents.replace('\n', newline),
+            _ => self.contents.clone(),
+        };
+        self.writer.write(self.destination.clone(), source_file::encode(&contents));
+    }
+}
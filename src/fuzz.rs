@@ -0,0 +1,111 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use clang::*;
+
+use emit;
+use rename::Renamer;
+use stubs::{declarator, prototype};
+
+pub const FILE_NAME: &str = "fuzz_target.c";
+
+// How a parameter is made out of the fuzzer's input.
+enum Param {
+    // Copied from the next sizeof bytes
+    Scalar,
+    // The rest of the input, with its length as the following parameter
+    Buffer,
+    // The rest of the input, NUL terminated
+    String,
+    // Anything else is zeroed
+    Zero,
+}
+
+fn classify(ty: &Type) -> Param {
+    let ty = ty.get_canonical_type();
+    if ty.is_integer() || matches!(ty.get_kind(), TypeKind::Float | TypeKind::Double | TypeKind::LongDouble | TypeKind::Enum) {
+        return Param::Scalar
+    }
+    match ty.get_pointee_type().map(|p| p.get_canonical_type().get_kind()) {
+        Some(TypeKind::CharS) | Some(TypeKind::CharU) | Some(TypeKind::SChar) => Param::String,
+        Some(TypeKind::UChar) | Some(TypeKind::Void) => Param::Buffer,
+        _ => Param::Zero,
+    }
+}
+
+// Writes a libFuzzer entry point calling `function` with parameters taken
+// from the input: scalars byte by byte, then the rest of the input for a
+// buffer or a string. Whatever else it takes is zeroed, which is only a
+// starting point for a real harness.
+pub fn write(target_directory: &Path, function: &Entity, includes: &BTreeSet<String>, renamer: Option<&Renamer>) {
+    let name = renamer.and_then(|r| r.renamed(function)).unwrap_or_else(|| function.get_name().unwrap());
+    let arguments = function.get_arguments().unwrap_or_default();
+    let types = arguments.iter().map(|a| a.get_type().unwrap()).collect::<Vec<_>>();
+
+    let mut source = format!("/* libFuzzer harness for {}. Build it with\n", name);
+    source.push_str(" *   clang -g -fsanitize=fuzzer,address -I. fuzz_target.c <the other sources but main>\n */\n\n");
+    source.push_str("#include <stddef.h>\n#include <stdint.h>\n#include <stdlib.h>\n#include <string.h>\n");
+    for include in includes {
+        source.push_str(&format!("{}\n", include));
+    }
+    source.push_str(&format!("\n{};\n\n", prototype(function, &name).0));
+    source.push_str("int LLVMFuzzerTestOneInput(const uint8_t *data, size_t size) {\n");
+
+    let mut values = vec![];
+    let mut frees = vec![];
+    let mut i = 0;
+    while i < types.len() {
+        let ty = types[i].get_display_name();
+        // Locals get written to before the call
+        let local = ty.trim_start_matches("const ");
+        let param = format!("p{}", i);
+
+        match classify(&types[i]) {
+            Param::Scalar => {
+                source.push_str(&format!("    {};\n", declarator(local, &param)));
+                source.push_str(&format!("    memset(&{0}, 0, sizeof {0});\n", param));
+                source.push_str(&format!("    if (size >= sizeof {0}) {{\n", param));
+                source.push_str(&format!("        memcpy(&{0}, data, sizeof {0});\n", param));
+                source.push_str(&format!("        data += sizeof {0};\n        size -= sizeof {0};\n", param));
+                source.push_str("    }\n");
+            },
+            Param::Buffer if types.get(i + 1).is_some_and(|t| t.get_canonical_type().is_integer()) => {
+                // A copy, since the function may well write to it
+                source.push_str(&format!("    uint8_t *{} = malloc(size + 1);\n", param));
+                source.push_str(&format!("    memcpy({}, data, size);\n", param));
+                source.push_str(&format!("    size_t {}_size = size;\n", param));
+                // Whatever comes after gets nothing rather than the same bytes again
+                source.push_str("    data += size;\n    size = 0;\n");
+                values.push(format!("({}){}", ty, param));
+                values.push(format!("({}){}_size", types[i + 1].get_display_name(), param));
+                frees.push(param);
+                i += 2;
+                continue
+            },
+            Param::Buffer | Param::String => {
+                source.push_str(&format!("    char *{} = malloc(size + 1);\n", param));
+                source.push_str(&format!("    memcpy({}, data, size);\n", param));
+                source.push_str(&format!("    {}[size] = 0;\n", param));
+                source.push_str("    data += size;\n    size = 0;\n");
+                values.push(format!("({}){}", ty, param));
+                frees.push(param);
+                i += 1;
+                continue
+            },
+            Param::Zero => {
+                source.push_str(&format!("    {};\n", declarator(local, &param)));
+                source.push_str(&format!("    memset(&{0}, 0, sizeof {0});\n", param));
+            },
+        }
+        values.push(param);
+        i += 1;
+    }
+
+    source.push_str(&format!("\n    {}({});\n\n", name, values.join(", ")));
+    for param in frees {
+        source.push_str(&format!("    free({});\n", param));
+    }
+    source.push_str("    return 0;\n}\n");
+
+    emit::write_if_changed(&target_directory.join(FILE_NAME), source);
+}
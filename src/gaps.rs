@@ -0,0 +1,56 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use clang::*;
+
+// A dependency the closure left out on purpose, by USR: its name as
+// written in the comments and why it's missing.
+pub struct Cut {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+pub fn add<'a>(cuts: &mut HashMap<String, Cut>, entities: impl IntoIterator<Item = &'a Entity<'a>>, reason: &'static str) {
+    for entity in entities {
+        let (usr, name) = match (entity.get_usr(), entity.get_name()) {
+            (Some(usr), Some(name)) => (usr.0, name),
+            _ => continue,
+        };
+        let name = if entity.get_kind() == EntityKind::FunctionDecl { format!("{}()", name) } else { name };
+        cuts.entry(usr).or_insert(Cut { name, reason });
+    }
+}
+
+// Marks the lines of `entity`, the first of which is `first_line` in
+// `lines`, where it uses one of the `cuts`, so that the gap reads as
+// deliberate.
+pub fn annotate(entity: &Entity, lines: &mut [String], first_line: u32, cuts: &HashMap<String, Cut>) {
+    let file = entity.get_location().and_then(|l| l.get_file_location().file);
+    let mut uses: BTreeMap<u32, BTreeSet<(&str, &str)>> = BTreeMap::new();
+
+    entity.visit_children(|child, _| {
+        let usr = child.get_reference().and_then(|r| r.get_usr());
+        if let Some(cut) = usr.and_then(|usr| cuts.get(&usr.0)) {
+            // Uses spelled in a macro get marked where it expands
+            let location = child.get_location().unwrap().get_expansion_location();
+            if location.file == file {
+                uses.entry(location.line).or_default().insert((&cut.name, cut.reason));
+            }
+        }
+        EntityVisitResult::Recurse
+    });
+
+    for (line, cut) in uses {
+        let line = match lines.get_mut(line.wrapping_sub(first_line) as usize) {
+            Some(line) => line,
+            None => continue,
+        };
+        for (name, reason) in cut {
+            let comment = format!("/* ccthief: dependency {} {}, see manifest */", name, reason);
+            // Ahead of a line continuation, which has to stay last
+            match line.strip_suffix('\\') {
+                Some(continued) => *line = format!("{} {} \\", continued.trim_end(), comment),
+                None => *line = format!("{} {}", line.trim_end(), comment),
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use canonicalize;
+
+// Where the extracted sources came from.
+pub struct Provenance {
+    pub remote: Option<String>,
+    pub commit: String,
+    // Whether the checkout had local changes, so `commit` doesn't tell the whole story
+    pub dirty: bool,
+}
+
+fn git(directory: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(directory).args(args).output().ok()?;
+    if !output.status.success() {
+        return None
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+// The root of the git checkout `directory` is in, if any.
+pub fn root(directory: &Path) -> Option<PathBuf> {
+    git(directory, &["rev-parse", "--show-toplevel"]).map(|root| canonicalize(Path::new(&root)).unwrap())
+}
+
+pub fn provenance(directory: &Path) -> Option<Provenance> {
+    let commit = git(directory, &["rev-parse", "HEAD"])?;
+    let remote = git(directory, &["remote", "get-url", "origin"]);
+    let dirty = git(directory, &["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    Some(Provenance { remote, commit, dirty })
+}
+
+// Canonical paths of the files tracked in the checkout at `root`.
+pub fn tracked_files(root: &Path) -> HashSet<PathBuf> {
+    let files = git(root, &["ls-files", "-z"]).unwrap_or_else(|| panic!("Couldn't list the files tracked in {:?}", root));
+    files.split('\0')
+        .filter(|f| !f.is_empty())
+        .filter_map(|f| canonicalize(&root.join(f)).ok())
+        .collect()
+}
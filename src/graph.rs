@@ -0,0 +1,240 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use clang::*;
+
+use json;
+use symbol_table::SymbolTable;
+use {get_location, get_path, in_system_header, symbol_range};
+
+// Why an edge of the dependency graph exists.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EdgeKind {
+    Call,
+    // A function used as a value, e.g. stored in a table of callbacks
+    AddressTaken,
+    Use,
+    Type,
+    Macro,
+    Include,
+}
+
+impl EdgeKind {
+    pub const ALL: [EdgeKind; 6] = [
+        EdgeKind::Call, EdgeKind::AddressTaken, EdgeKind::Use,
+        EdgeKind::Type, EdgeKind::Macro, EdgeKind::Include,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Call => "call",
+            EdgeKind::AddressTaken => "address-taken",
+            EdgeKind::Use => "use",
+            EdgeKind::Type => "type",
+            EdgeKind::Macro => "macro",
+            EdgeKind::Include => "include",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        EdgeKind::ALL.iter().cloned().find(|kind| kind.name() == name)
+    }
+}
+
+// What people get to read, a struct and its typedef may well share one.
+pub fn node_label(entity: &Entity) -> String {
+    let name = entity.get_name().unwrap_or_else(|| format!("{:?}", entity.get_kind()));
+    format!("{}@{}", name, get_location(entity))
+}
+
+// What tells entities apart: the USR, which every translation unit agrees on,
+// or the kind and exact location for entities without one.
+pub fn node_key(entity: &Entity) -> String {
+    match entity.get_usr() {
+        Some(usr) if !usr.0.is_empty() => usr.0,
+        _ => {
+            let location = entity.get_location().unwrap().get_file_location();
+            format!("{:?}@{}:{}", entity.get_kind(), get_location(entity), location.column)
+        },
+    }
+}
+
+// Escapes a DOT quoted string, where only quotes and backslashes are special.
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub struct Node {
+    pub label: String,
+    pub usr: Option<String>,
+    pub kind: String,
+    pub file: String,
+    // (start line, start column, end line, end column)
+    pub range: (u32, u32, u32, u32),
+    defined: bool,
+}
+
+impl Node {
+    fn new(entity: &Entity) -> Self {
+        let range = symbol_range(entity);
+        let start = range.get_start().get_file_location();
+        let end = range.get_end().get_file_location();
+
+        Node {
+            label: node_label(entity),
+            usr: entity.get_usr().map(|usr| usr.0),
+            kind: format!("{:?}", entity.get_kind()),
+            file: String::from(get_path(entity).to_str().unwrap()),
+            range: (start.line, start.column, end.line, end.column),
+            defined: entity.is_definition(),
+        }
+    }
+}
+
+// The extracted part of the dependency graph. Entities seen by several
+// translation units, and the declarations of one symbol, collapse into one
+// node since they share a key.
+pub struct Graph {
+    pub nodes: BTreeMap<String, Node>,
+    pub edges: BTreeSet<(String, String, &'static str)>,
+}
+
+impl Graph {
+    // Only edges of the given `kinds` are kept, definition edges always are.
+    pub fn new<'a>(
+        extracted: &HashSet<Entity<'a>>,
+        sym_table: &SymbolTable<'a>,
+        kinds: &BTreeSet<EdgeKind>
+    ) -> Self
+    {
+        let mut graph = Graph { nodes: BTreeMap::new(), edges: BTreeSet::new() };
+
+        for entity in extracted {
+            let from = node_key(entity);
+            graph.add_node(&from, entity);
+
+            let desc = match sym_table.get(entity) {
+                Some(desc) => desc,
+                None => continue,
+            };
+
+            for (dep, dep_kinds) in desc.deps.iter().filter(|(d, _)| extracted.contains(d)) {
+                for kind in dep_kinds.intersection(kinds) {
+                    graph.edges.insert((from.clone(), node_key(dep), kind.name()));
+                }
+            }
+            for def in desc.definitions.iter().filter(|d| extracted.contains(d)) {
+                let to = node_key(def);
+                if to != from {
+                    graph.edges.insert((from.clone(), to, "definition"));
+                }
+            }
+        }
+
+        graph
+    }
+
+    // Where a symbol is both declared and defined, the node shows the definition.
+    fn add_node(&mut self, key: &str, entity: &Entity) {
+        match self.nodes.get(key) {
+            Some(node) if node.defined || !entity.is_definition() => {},
+            _ => {
+                self.nodes.insert(String::from(key), Node::new(entity));
+            },
+        }
+    }
+
+    // Function -> called function edges over everything that was parsed,
+    // ignoring type and macro dependencies. Functions are identified by USR
+    // so that calls through prototypes land on the definition.
+    pub fn call_graph<'a>(sym_table: &SymbolTable<'a>) -> Self {
+        let mut graph = Graph { nodes: BTreeMap::new(), edges: BTreeSet::new() };
+        let mut functions: HashMap<String, Entity<'a>> = HashMap::new();
+
+        for entity in sym_table.keys().filter(|e| e.get_kind() == EntityKind::FunctionDecl) {
+            if let Some(usr) = entity.get_usr() {
+                let known = functions.entry(usr.0).or_insert(*entity);
+                if entity.is_definition() && !known.is_definition() {
+                    *known = *entity;
+                }
+            }
+        }
+
+        for function in functions.values().filter(|f| f.is_definition() && !in_system_header(f)) {
+            let from = node_key(function);
+            graph.add_node(&from, function);
+
+            function.visit_children(|child, _| {
+                if child.get_kind() == EntityKind::CallExpr {
+                    let callee = child.get_reference()
+                        .and_then(|c| c.get_usr())
+                        .and_then(|usr| functions.get(&usr.0));
+
+                    if let Some(callee) = callee {
+                        let to = node_key(callee);
+                        graph.add_node(&to, callee);
+                        graph.edges.insert((from.clone(), to, EdgeKind::Call.name()));
+                    }
+                }
+                EntityVisitResult::Recurse
+            });
+        }
+
+        graph
+    }
+
+    // Picks the format from the extension, DOT unless it is `.json`.
+    pub fn write(&self, path: &Path) {
+        if path.extension().is_some_and(|ext| ext == "json") {
+            self.write_json(path);
+        } else {
+            self.write_dot(path);
+        }
+    }
+
+    pub fn write_dot(&self, path: &Path) {
+        let ids = self.nodes.keys().enumerate().map(|(i, n)| (n, i)).collect::<BTreeMap<_, _>>();
+
+        let mut contents = String::from("digraph deps {\n    node [shape=box];\n");
+        for (key, id) in &ids {
+            contents.push_str(&format!("    n{} [label={}];\n", id, dot_string(&self.nodes[*key].label)));
+        }
+        for &(ref from, ref to, kind) in &self.edges {
+            contents.push_str(&format!("    n{} -> n{} [label={}];\n", ids[from], ids[to], dot_string(kind)));
+        }
+        contents.push_str("}\n");
+
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn write_json(&self, path: &Path) {
+        let nodes = self.nodes.iter().map(|(key, node)| {
+            json::object(&[
+                ("id", json::string(key)),
+                ("label", json::string(&node.label)),
+                ("usr", json::optional(node.usr.as_ref().map(|usr| json::string(usr)))),
+                ("kind", json::string(&node.kind)),
+                ("file", json::string(&node.file)),
+                ("range", json::object(&[
+                    ("start", json::array(vec![node.range.0.to_string(), node.range.1.to_string()])),
+                    ("end", json::array(vec![node.range.2.to_string(), node.range.3.to_string()])),
+                ])),
+            ])
+        });
+
+        let edges = self.edges.iter().map(|&(ref from, ref to, kind)| {
+            json::object(&[
+                ("from", json::string(from)),
+                ("to", json::string(to)),
+                ("kind", json::string(kind)),
+            ])
+        });
+
+        let contents = json::object(&[
+            ("nodes", json::array(nodes)),
+            ("edges", json::array(edges)),
+        ]);
+        fs::write(path, contents + "\n").unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+use clang::*;
+use clang::token::TokenKind;
+
+use emit;
+use internalize::{make_static, set_storage};
+use rename::Renamer;
+use source_file::SourceFile;
+use stubs::declarator;
+use {function_usr, is_redundant_prototype, symbol_text};
+
+pub const FILE_NAME: &str = "ccthief_single.h";
+// Defined by the one file that gets the definitions of the global variables
+pub const IMPLEMENTATION_MACRO: &str = "CCTHIEF_IMPLEMENTATION";
+
+fn is_global_variable(entity: &Entity) -> bool {
+    entity.get_kind() == EntityKind::VarDecl &&
+        entity.get_linkage() == Some(Linkage::External) &&
+        entity.get_semantic_parent().is_some_and(|p| p.get_kind() == EntityKind::TranslationUnit)
+}
+
+// Whether `entity` is declared with the inline keyword, not just some name
+// like `inline_size` in its text.
+fn is_inline(entity: &Entity) -> bool {
+    entity.get_range().map(|range| range.tokenize()).unwrap_or_default().iter()
+        .take_while(|t| t.get_spelling() != "{")
+        .any(|t| t.get_kind() == TokenKind::Keyword && matches!(t.get_spelling().as_str(), "inline" | "__inline" | "__inline__"))
+}
+
+// The text of `entity` the way it has to look in a header that may be
+// included by any number of translation units. Prototypes of the functions
+// without a body in it, `defined` by neither, stay extern.
+fn rewrite(entity: &Entity, mut lines: Vec<String>, renamer: Option<&Renamer>, defined: &HashSet<String>) -> Vec<String> {
+    match entity.get_kind() {
+        EntityKind::FunctionDecl if entity.get_linkage() == Some(Linkage::External) => {
+            if !function_usr(entity).is_some_and(|usr| defined.contains(&usr)) {
+                return lines
+            }
+            if is_inline(entity) {
+                make_static(entity, &mut lines);
+            } else {
+                set_storage(entity, &mut lines, "static inline");
+            }
+        },
+        EntityKind::FunctionDecl if entity.is_definition() && !is_inline(entity) => {
+            if let Some(index) = lines[0].find("static") {
+                lines[0].insert_str(index + "static".len(), " inline");
+            }
+        },
+        EntityKind::VarDecl if entity.is_definition() && is_global_variable(entity) => {
+            // Only one translation unit may define it, the others see a declaration
+            let name = renamer.and_then(|r| r.renamed(entity)).unwrap_or_else(|| entity.get_name().unwrap());
+            let ty = entity.get_type().unwrap().get_display_name();
+            let mut guarded = vec![format!("#ifdef {}", IMPLEMENTATION_MACRO)];
+            guarded.extend(lines);
+            guarded.push(String::from("#else"));
+            guarded.push(format!("extern {};", declarator(&ty, &name)));
+            guarded.push(String::from("#endif"));
+            return guarded
+        },
+        _ => (),
+    }
+    lines
+}
+
+// Writes the whole closure as one header: every function with a body becomes `static
+// inline`, and global variables are only defined where
+// CCTHIEF_IMPLEMENTATION is defined before including it. `files` are the
+// original files in dependency order along with their extracted symbols,
+// `includes` what stays an include. With `drop_prototypes`, prototypes of
+// functions defined further up are left out.
+pub fn write(
+    target_directory: &Path,
+    files: &[(PathBuf, Vec<Entity>)],
+    includes: &BTreeSet<String>,
+    renamer: Option<&Renamer>,
+    drop_prototypes: bool
+) {
+    let mut contents = String::from("/* Header-only build of the extracted sources. Define CCTHIEF_IMPLEMENTATION\n");
+    contents.push_str(" * in exactly one file before including it to get the global variables. */\n\n");
+    contents.push_str("#ifndef CCTHIEF_SINGLE_H\n#define CCTHIEF_SINGLE_H\n\n");
+
+    for include in includes {
+        contents.push_str(&format!("{}\n", include));
+    }
+
+    // Functions with a body somewhere in the header
+    let bodies = files.iter()
+        .flat_map(|(_, symbols)| symbols)
+        .filter(|s| s.is_definition())
+        .filter_map(function_usr)
+        .collect::<HashSet<_>>();
+
+    let mut defined_functions = HashSet::new();
+    for (file, symbols) in files {
+        let source = SourceFile::open(file);
+        contents.push_str(&format!("\n/* {} */\n", file.file_name().unwrap().to_str().unwrap()));
+
+        for symbol in symbols.iter().filter(|s| s.get_kind() != EntityKind::InclusionDirective) {
+            if is_redundant_prototype(symbol, &defined_functions) {
+                continue
+            }
+            if drop_prototypes && symbol.is_definition() {
+                defined_functions.extend(function_usr(symbol));
+            }
+
+            let (lines, _) = symbol_text(symbol, &source, renamer);
+            for line in rewrite(symbol, lines, renamer, &bodies) {
+                contents.push_str(&line);
+                contents.push('\n');
+            }
+        }
+    }
+
+    contents.push_str("\n#endif\n");
+    emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clang::*;
+
+use cache::content_hash;
+use json;
+use parse;
+use sha256;
+
+pub const FILE_NAME: &str = "ccthief-state.json";
+
+// Where the translation unit of `source` is saved between runs, named after
+// everything that makes the parse come out the way it did.
+fn saved_ast(source: &str, clang_args: &[String]) -> PathBuf {
+    let version = get_version();
+    let directory = env::current_dir().unwrap();
+    let fields = iter::once(version.as_str())
+        .chain(iter::once(directory.to_str().unwrap()))
+        .chain(iter::once(source))
+        .chain(clang_args.iter().map(String::as_str));
+    let name = sha256::digest_all(fields.map(str::as_bytes));
+    env::temp_dir().join("ccthief-asts").join(format!("{}.ast", name))
+}
+
+// The translation unit of `source` saved by an earlier run, as long as none
+// of the files it was made of changed since.
+pub fn load_ast<'i>(index: &'i Index, source: &str, clang_args: &[String]) -> Option<TranslationUnit<'i>> {
+    let ast = saved_ast(source, clang_args);
+    let inputs = json::parse(&fs::read_to_string(ast.with_extension("inputs")).ok()?).ok()?;
+    for input in inputs.as_array()? {
+        let path = input.get("path")?.as_str()?;
+        if content_hash(Path::new(path)).as_deref() != input.get("hash")?.as_str() {
+            return None
+        }
+    }
+    TranslationUnit::from_ast(index, &ast).ok()
+}
+
+// Saves `tu` for the next run along with the hashes of the files it was made of.
+pub fn save_ast(tu: &TranslationUnit, source: &str, clang_args: &[String]) {
+    let ast = saved_ast(source, clang_args);
+    fs::create_dir_all(ast.parent().unwrap()).unwrap();
+
+    let inputs = parse::files(tu, Path::new(source)).into_iter()
+        .filter_map(|path| content_hash(&path).map(|hash| (path, hash)))
+        .map(|(path, hash)| json::object(&[("path", json::string(path.to_str().unwrap())), ("hash", json::string(&hash))]));
+
+    // Another run may be reading them, so they only ever appear whole
+    let temporary = ast.with_extension(format!("{}.tmp", process::id()));
+    if tu.save(&temporary).is_err() {
+        let _ = fs::remove_file(&temporary);
+        return
+    }
+    fs::rename(&temporary, &ast).unwrap();
+    fs::write(&temporary, json::array(inputs) + "\n").unwrap();
+    fs::rename(&temporary, ast.with_extension("inputs")).unwrap();
+}
+
+fn arguments_hash() -> String {
+    let arguments = env::args().skip(1).collect::<Vec<_>>();
+    sha256::digest_all(arguments.iter().map(|arg| arg.as_bytes()))
+}
+
+// What an extraction was made from and what it wrote, kept next to the
+// output so that a rerun can tell whether there is anything to do.
+pub struct State {
+    arguments: String,
+    // file -> content hash
+    inputs: BTreeMap<PathBuf, String>,
+    // Relative to the output directory
+    outputs: BTreeSet<PathBuf>,
+}
+
+impl State {
+    pub fn new(tus: &[TranslationUnit], sources: &[String], outputs: BTreeSet<PathBuf>) -> Self {
+        let included = tus.iter()
+            .flat_map(|tu| tu.get_entity().get_children())
+            .filter(|c| c.get_kind() == EntityKind::InclusionDirective)
+            .filter_map(|c| c.get_file().map(|f| f.get_path()));
+
+        let inputs = sources.iter().map(PathBuf::from)
+            .chain(included)
+            .filter_map(|path| content_hash(&path).map(|hash| (path, hash)))
+            .collect();
+
+        State { arguments: arguments_hash(), inputs, outputs }
+    }
+
+    pub fn load(target_directory: &Path) -> Option<Self> {
+        let state = json::parse(&fs::read_to_string(target_directory.join(FILE_NAME)).ok()?).ok()?;
+        let string = |value: &json::Value| value.as_str().map(String::from);
+
+        let mut inputs = BTreeMap::new();
+        for input in state.get("inputs")?.as_array()? {
+            inputs.insert(PathBuf::from(string(input.get("path")?)?), string(input.get("hash")?)?);
+        }
+        let outputs = state.get("outputs")?.as_array()?.iter()
+            .map(|output| string(output).map(PathBuf::from))
+            .collect::<Option<_>>()?;
+
+        Some(State { arguments: string(state.get("arguments")?)?, inputs, outputs })
+    }
+
+    // Same arguments and none of the inputs changed.
+    pub fn is_fresh(&self) -> bool {
+        self.arguments == arguments_hash() &&
+            self.inputs.iter().all(|(path, hash)| content_hash(path).as_ref() == Some(hash))
+    }
+
+    // Deletes whatever `previous` wrote that this extraction didn't.
+    pub fn remove_stale(&self, previous: &State, target_directory: &Path) {
+        for output in previous.outputs.difference(&self.outputs) {
+            eprintln!("Removing stale {:?}", output);
+            let _ = fs::remove_file(target_directory.join(output));
+        }
+    }
+
+    pub fn write(&self, target_directory: &Path) {
+        let contents = json::object(&[
+            ("arguments", json::string(&self.arguments)),
+            ("inputs", json::array(self.inputs.iter().map(|(path, hash)| json::object(&[
+                ("path", json::string(path.to_str().unwrap())),
+                ("hash", json::string(hash)),
+            ])))),
+            ("outputs", json::array(self.outputs.iter().map(|output| json::string(output.to_str().unwrap())))),
+        ]);
+        fs::write(target_directory.join(FILE_NAME), contents + "\n").unwrap();
+    }
+}
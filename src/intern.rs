@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// A string kept by an `Interner`, as small as an index and as cheap to compare.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Name(u32);
+
+// libclang hands out a fresh String every time a name or a USR is asked for.
+// This keeps a single copy of each instead.
+#[derive(Default)]
+pub struct Interner {
+    ids: HashMap<Rc<str>, Name>,
+    strings: Vec<Rc<str>>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, s: &str) -> Name {
+        if let Some(&name) = self.ids.get(s) {
+            return name
+        }
+        let string = Rc::<str>::from(s);
+        let name = Name(self.strings.len() as u32);
+        self.strings.push(string.clone());
+        self.ids.insert(string, name);
+        name
+    }
+
+    // The name of `s` if it was ever interned, without interning it.
+    pub fn get(&self, s: &str) -> Option<Name> {
+        self.ids.get(s).cloned()
+    }
+
+    pub fn resolve(&self, name: Name) -> &str {
+        &self.strings[name.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_name_per_string() {
+        let mut interner = Interner::default();
+        let (a, b) = (interner.intern("main"), interner.intern("c:@F@main"));
+        assert_ne!(a, b);
+        assert_eq!(interner.intern("main"), a);
+        assert_eq!(interner.get("c:@F@main"), Some(b));
+        assert_eq!(interner.get("other"), None);
+        assert_eq!(interner.resolve(b), "c:@F@main");
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use clang::*;
+use clang::token::TokenKind;
+
+use source_file::{decode, encode};
+use symbol_table::SymbolTable;
+use {CanonicalPath, get_path, is_target};
+
+// Decides which extracted helpers can be turned into `static` functions.
+//
+// A helper is only internalized when every extracted user lives in the same
+// file as its definition, otherwise making it static would break the link.
+// Prototypes living in other files (typically headers) are dropped there and
+// re-emitted as static prototypes at the top of the defining file.
+#[derive(Default)]
+pub struct Internalizer<'a> {
+    statics: HashSet<Entity<'a>>,
+    dropped: HashSet<Entity<'a>>,
+    moved: HashMap<CanonicalPath, BTreeMap<(CanonicalPath, u32), Entity<'a>>>,
+}
+
+impl<'a> Internalizer<'a> {
+    pub fn new(
+        extracted: &HashSet<Entity<'a>>,
+        sym_table: &SymbolTable<'a>,
+        targets: &[String]
+    ) -> Self
+    {
+        let mut ret: Internalizer = Default::default();
+        let mut functions = HashMap::new();
+
+        for entity in extracted {
+            if entity.get_kind() != EntityKind::FunctionDecl || entity.get_linkage() != Some(Linkage::External) {
+                continue
+            }
+            if is_target(entity, targets) {
+                continue
+            }
+            if let Some(usr) = sym_table.usr_name(entity) {
+                functions.entry(usr).or_insert_with(Vec::new).push(*entity);
+            }
+        }
+
+        for (usr, decls) in functions {
+            let definition = match decls.iter().find(|d| d.is_definition()) {
+                Some(definition) => *definition,
+                None => continue,
+            };
+            let def_file = CanonicalPath::new(get_path(&definition));
+
+            let used_elsewhere = extracted.iter()
+                .filter(|e| sym_table.contains_key(e) && !decls.contains(e))
+                .filter(|e| sym_table[e].deps.keys().any(|d| sym_table.usr_name(d) == Some(usr)))
+                .any(|e| CanonicalPath::new(get_path(e)) != def_file);
+
+            if used_elsewhere {
+                continue
+            }
+
+            for decl in decls {
+                let file = CanonicalPath::new(get_path(&decl));
+                if file == def_file {
+                    ret.statics.insert(decl);
+                } else {
+                    let line = decl.get_location().unwrap().get_file_location().line;
+                    ret.dropped.insert(decl);
+                    ret.moved.entry(def_file.clone()).or_default().insert((file, line), decl);
+                }
+            }
+        }
+
+        ret
+    }
+
+    pub fn is_dropped(&self, entity: &Entity<'a>) -> bool {
+        self.dropped.contains(entity)
+    }
+
+    // Prototypes that have to be re-emitted as static in `file`.
+    pub fn moved_to(&self, file: &CanonicalPath) -> Vec<Entity<'a>> {
+        self.moved.get(file).map_or(vec![], |m| m.values().cloned().collect())
+    }
+
+    // Prepends `static` to the first line of the symbol if it needs it.
+    pub fn apply(&self, entity: &Entity<'a>, lines: &mut [String]) {
+        if self.statics.contains(entity) || self.dropped.contains(entity) {
+            make_static(entity, lines);
+        }
+    }
+}
+
+pub fn make_static(entity: &Entity, lines: &mut [String]) {
+    set_storage(entity, lines, "static");
+}
+
+// Gives the declaration of `entity` the `storage` class, in place of its
+// `extern` if it has one. Declarations starting inside a macro are left
+// alone, their first line is where the macro was used.
+pub fn set_storage(entity: &Entity, lines: &mut [String], storage: &str) {
+    let range = entity.get_range().unwrap();
+    let (expansion, spelling) = (range.get_start().get_expansion_location(), range.get_start().get_spelling_location());
+    if (expansion.file, expansion.offset) != (spelling.file, spelling.offset) {
+        return
+    }
+    let start = range.get_start().get_file_location();
+
+    let tokens = range.tokenize();
+    let extern_keyword = tokens.iter()
+        .take_while(|t| t.get_spelling() != "{")
+        .find(|t| t.get_kind() == TokenKind::Keyword && t.get_spelling() == "extern")
+        .map(|t| t.get_location().get_file_location());
+
+    // Columns count bytes of the original line
+    let (index, column, length, text) = match extern_keyword {
+        Some(location) => ((location.line - start.line) as usize, location.column, "extern".len(), String::from(storage)),
+        None => (0, start.column, 0, format!("{} ", storage)),
+    };
+    let mut bytes = match lines.get(index) {
+        Some(line) => encode(line),
+        None => return,
+    };
+    let column = column as usize - 1;
+    if column + length > bytes.len() {
+        return
+    }
+    bytes.splice(column .. column + length, text.bytes());
+    lines[index] = decode(&bytes);
+}
@@ -0,0 +1,92 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use clang::*;
+
+use symbol_table::SymbolTable;
+use {CanonicalPath, directive, get_path, in_system_header, sorted};
+
+// Files reachable from `file` through includes, `file` included.
+fn provided(file: &CanonicalPath, included: &HashMap<CanonicalPath, HashSet<CanonicalPath>>) -> HashSet<CanonicalPath> {
+    let mut ret = HashSet::new();
+    let mut stack = vec![file.clone()];
+
+    while let Some(file) = stack.pop() {
+        if ret.insert(file.clone()) {
+            stack.extend(included.get(&file).into_iter().flatten().cloned());
+        }
+    }
+
+    ret
+}
+
+// The includes that provide nothing the including file uses, going by the
+// dependencies of its symbols, or only of those in `symbols` when given. A
+// header needed just by an `#if` or by another macro counts as unused as well.
+pub fn unused<'a>(
+    sym_table: &SymbolTable<'a>,
+    includes: &HashSet<Entity<'a>>,
+    symbols: Option<&HashSet<Entity<'a>>>
+) -> Vec<Entity<'a>>
+{
+    let mut included: HashMap<CanonicalPath, HashSet<CanonicalPath>> = HashMap::new();
+    for include in includes {
+        if let Some(file) = include.get_file() {
+            included.entry(CanonicalPath::new(get_path(include))).or_default()
+                .insert(CanonicalPath::new(file.get_path()));
+        }
+    }
+
+    let mut used: HashMap<CanonicalPath, HashSet<CanonicalPath>> = HashMap::new();
+    for (entity, desc) in sym_table.iter().filter(|(e, _)| !in_system_header(e) && symbols.is_none_or(|s| s.contains(e))) {
+        let files = used.entry(CanonicalPath::new(get_path(entity))).or_default();
+        for dep in desc.deps.keys() {
+            let dep = match dep.get_kind() {
+                EntityKind::InclusionDirective => continue,
+                EntityKind::MacroExpansion => match dep.get_reference() {
+                    Some(definition) => definition,
+                    None => continue,
+                },
+                _ => *dep,
+            };
+            if let Some(file) = dep.get_location().and_then(|l| l.get_file_location().file) {
+                files.insert(CanonicalPath::new(file.get_path()));
+            }
+        }
+    }
+
+    let mut ret = vec![];
+    for include in sorted(includes).into_iter().filter(|i| !in_system_header(i)) {
+        let file = match include.get_file() {
+            Some(file) => CanonicalPath::new(file.get_path()),
+            None => continue,
+        };
+        let including = CanonicalPath::new(get_path(&include));
+        let uses = used.get(&including);
+
+        if uses.is_none_or(|uses| provided(&file, &included).is_disjoint(uses)) {
+            ret.push(include);
+        }
+    }
+
+    ret
+}
+
+// Writes the includes of the original sources that provide nothing the
+// including file uses.
+pub fn write<'a>(path: &Path, sym_table: &SymbolTable<'a>, includes: &HashSet<Entity<'a>>) {
+    let mut flagged = BTreeSet::new();
+    for include in unused(sym_table, includes, None) {
+        let including = CanonicalPath::new(get_path(&include));
+        let line = include.get_location().unwrap().get_file_location().line;
+        flagged.insert((including, line, directive(&include), include.get_name().unwrap()));
+    }
+
+    let mut contents = String::new();
+    for (file, line, directive, name) in flagged {
+        contents.push_str(&format!("{}:{}: #{} \"{}\" provides nothing used here\n", file.0.to_str().unwrap(), line, directive, name));
+    }
+
+    fs::write(path, contents).unwrap();
+}
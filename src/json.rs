@@ -0,0 +1,253 @@
+// Just enough JSON to read and write our artifacts without pulling in a serializer.
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub fn string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+pub fn optional(value: Option<String>) -> String {
+    value.unwrap_or_else(|| String::from("null"))
+}
+
+pub fn array<I: IntoIterator<Item = String>>(items: I) -> String {
+    let items = items.into_iter().collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+pub fn object(fields: &[(&str, String)]) -> String {
+    let fields = fields.iter()
+        .map(|&(key, ref value)| format!("{}: {}", string(key), value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(", "))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref fields) => fields.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n) if n >= 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match *self {
+            Value::Array(ref items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'s> {
+    chars: Peekable<Chars<'s>>,
+}
+
+impl<'s> Parser<'s> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        for c in expected.chars() {
+            if self.chars.next() != Some(c) {
+                return Err(format!("expected {:?}", expected));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('n') => self.expect("null").map(|_| Value::Null),
+            Some('t') => self.expect("true").map(|_| Value::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.chars.next();
+                let mut items = vec![];
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.peek() == Some(&']') {
+                        self.chars.next();
+                        return Ok(Value::Array(items));
+                    }
+                    if !items.is_empty() {
+                        self.expect(",")?;
+                    }
+                    items.push(self.value()?);
+                }
+            },
+            Some('{') => {
+                self.chars.next();
+                let mut fields = BTreeMap::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.peek() == Some(&'}') {
+                        self.chars.next();
+                        return Ok(Value::Object(fields));
+                    }
+                    if !fields.is_empty() {
+                        self.expect(",")?;
+                        self.skip_whitespace();
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.insert(key, self.value()?);
+                }
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while self.chars.peek().is_some_and(|c| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                    number.push(self.chars.next().unwrap());
+                }
+                number.parse().map(Value::Number).map_err(|_| format!("bad number {:?}", number))
+            },
+            c => Err(format!("unexpected {:?}", c)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut ret = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => ret.push('\n'),
+                    Some('r') => ret.push('\r'),
+                    Some('t') => ret.push('\t'),
+                    Some('b') => ret.push('\u{8}'),
+                    Some('f') => ret.push('\u{c}'),
+                    Some('u') => ret.push(self.unicode_escape()?),
+                    Some(c) => ret.push(c),
+                    None => return Err(String::from("unterminated string")),
+                },
+                Some(c) => ret.push(c),
+                None => return Err(String::from("unterminated string")),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let code = self.chars.by_ref().take(4).collect::<String>();
+        if code.len() != 4 || !code.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("bad escape {:?}", code));
+        }
+        Ok(u32::from_str_radix(&code, 16).unwrap())
+    }
+
+    // What follows `\u`. Characters outside the basic plane come as two
+    // escapes, a high surrogate and a low one; lone halves become U+FFFD.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return Ok(char::from_u32(high).unwrap_or('\u{fffd}'));
+        }
+
+        let mut ahead = Parser { chars: self.chars.clone() };
+        if ahead.chars.next() == Some('\\') && ahead.chars.next() == Some('u') {
+            let low = ahead.hex()?;
+            if (0xdc00..0xe000).contains(&low) {
+                self.chars = ahead.chars;
+                return Ok(char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)).unwrap());
+            }
+        }
+        Ok('\u{fffd}')
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: text.chars().peekable() };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("trailing {:?}", c)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        let text = "quote \" backslash \\ newline \n tab \t bell \u{7} é";
+        assert_eq!(parse(&string(text)), Ok(Value::String(String::from(text))));
+        assert_eq!(parse(r#""\/\b\f\u00e9""#), Ok(Value::String(String::from("/\u{8}\u{c}é"))));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(parse(r#""\ud83d\ude00""#), Ok(Value::String(String::from("\u{1f600}"))));
+        assert_eq!(parse(r#""\ud83dx""#), Ok(Value::String(String::from("\u{fffd}x"))));
+        assert_eq!(parse(r#""\ude00""#), Ok(Value::String(String::from("\u{fffd}"))));
+        // The second escape isn't a low surrogate, so it stands on its own
+        assert_eq!(parse(r#""\ud83d\u0041""#), Ok(Value::String(String::from("\u{fffd}A"))));
+    }
+
+    #[test]
+    fn nesting() {
+        let value = parse(r#" {"a": [1, {"b": null}, [true, false]], "c": {"d": "e"}} "#).unwrap();
+        assert_eq!(value.get("a").and_then(Value::as_array).map(Vec::len), Some(3));
+        assert_eq!(value.get("a").unwrap().as_array().unwrap()[1].get("b"), Some(&Value::Null));
+        assert_eq!(value.get("c").and_then(|c| c.get("d")).and_then(Value::as_str), Some("e"));
+
+        let written = object(&[("x", array(vec![object(&[("y", string("z"))])]))]);
+        assert_eq!(parse(&written).unwrap().get("x").unwrap().as_array().unwrap()[0].get("y").and_then(Value::as_str), Some("z"));
+    }
+
+    #[test]
+    fn malformed() {
+        for text in &[
+            "", "\"unterminated", "[1, 2", "[1 2]", "{\"a\" 1}", "{\"a\": 1,}", "nul", "1 2",
+            r#""\u12""#, r#""\u12g4""#, r#""\u+123""#, "--1",
+        ] {
+            assert!(parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
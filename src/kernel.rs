@@ -0,0 +1,81 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::Path;
+
+use clang::*;
+use clang::token::TokenKind;
+
+use in_system_header;
+
+// Macros whose argument is reachable from outside the translation unit:
+// through the symbol table of the kernel or its initcall sections. That is
+// how __init and __exit functions get in, their section attributes copied
+// along with the rest of the text.
+const ROOT_MACROS: &[&str] = &[
+    "EXPORT_SYMBOL", "EXPORT_SYMBOL_GPL", "EXPORT_SYMBOL_NS", "EXPORT_SYMBOL_NS_GPL",
+    "module_init", "module_exit",
+    "early_initcall", "core_initcall", "postcore_initcall", "arch_initcall",
+    "subsys_initcall", "fs_initcall", "device_initcall", "late_initcall",
+];
+
+// Headers made by the kernel build from its configuration. They are kept as
+// includes into the kernel tree rather than picked apart.
+pub const GENERATED_HEADERS: &[&str] = &["include/generated/**", "include/config/**"];
+
+// The flags Kbuild parses a driver with, give or take the per-file ones.
+pub fn clang_args(tree: &Path, arch: &str) -> Vec<String> {
+    let path = |relative: &str| String::from(tree.join(relative).to_str().unwrap());
+
+    vec![
+        String::from("-nostdinc"),
+        format!("-I{}", path(&format!("arch/{}/include", arch))),
+        format!("-I{}", path(&format!("arch/{}/include/generated", arch))),
+        format!("-I{}", path("include")),
+        format!("-I{}", path(&format!("arch/{}/include/uapi", arch))),
+        format!("-I{}", path(&format!("arch/{}/include/generated/uapi", arch))),
+        format!("-I{}", path("include/uapi")),
+        format!("-I{}", path("include/generated/uapi")),
+        String::from("-include"), path("include/linux/compiler-version.h"),
+        String::from("-include"), path("include/linux/kconfig.h"),
+        String::from("-include"), path("include/linux/compiler_types.h"),
+        String::from("-D__KERNEL__"),
+        String::from("-DMODULE"),
+    ]
+}
+
+// The kernel's name for the architecture of this machine.
+pub fn host_arch() -> &'static str {
+    match env::consts::ARCH {
+        "x86" | "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "riscv64" => "riscv",
+        "powerpc64" => "powerpc",
+        arch => panic!("Don't know the kernel architecture for {}, use --kernel-arch", arch),
+    }
+}
+
+// Names passed to EXPORT_SYMBOL, module_init and friends in the parsed
+// sources, which are the entry points of a driver.
+pub fn roots(tus: &[TranslationUnit]) -> BTreeSet<String> {
+    let mut roots = BTreeSet::new();
+
+    for tu in tus {
+        for expansion in tu.get_entity().get_children() {
+            if expansion.get_kind() != EntityKind::MacroExpansion || in_system_header(&expansion) {
+                continue
+            }
+            if !expansion.get_name().is_some_and(|name| ROOT_MACROS.contains(&name.as_str())) {
+                continue
+            }
+
+            // NAME ( symbol ...
+            let tokens = expansion.get_range().unwrap().tokenize();
+            if let Some(symbol) = tokens.get(2).filter(|t| t.get_kind() == TokenKind::Identifier) {
+                roots.insert(symbol.get_spelling());
+            }
+        }
+    }
+
+    roots
+}
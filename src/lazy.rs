@@ -0,0 +1,193 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+// What a source defines and mentions, read off its text without parsing it.
+// Both are overestimates, which only ever costs an extra parse, except for
+// the names macros make up: a source invoking macros outside of any function
+// may define those, so it counts as defining whatever no other source does.
+struct Summary {
+    defined: HashSet<String>,
+    used: HashSet<String>,
+    invokes_macros: bool,
+}
+
+enum Token {
+    Identifier(String),
+    Punctuation(u8),
+}
+
+fn is_identifier(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+// Identifiers and punctuation, leaving out comments, literals and the preprocessor.
+fn tokens(text: &[u8]) -> Vec<Token> {
+    let mut ret = vec![];
+    let mut line_start = true;
+    let mut i = 0;
+
+    while i < text.len() {
+        let byte = text[i];
+        match byte {
+            b'\n' => {
+                line_start = true;
+                i += 1;
+                continue
+            },
+            b'#' if line_start => {
+                while i < text.len() && text[i] != b'\n' {
+                    i += if text[i] == b'\\' { 2 } else { 1 };
+                }
+            },
+            b'/' if text.get(i + 1) == Some(&b'/') => {
+                while i < text.len() && text[i] != b'\n' {
+                    i += 1;
+                }
+            },
+            b'/' if text.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < text.len() && !text[i..].starts_with(b"*/") {
+                    i += 1;
+                }
+                i += 2;
+            },
+            b'"' | b'\'' => {
+                i += 1;
+                while i < text.len() && text[i] != byte && text[i] != b'\n' {
+                    i += if text[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            },
+            _ if byte.is_ascii_digit() => {
+                while i < text.len() && (is_identifier(text[i]) || text[i] == b'.') {
+                    i += 1;
+                }
+            },
+            _ if is_identifier(byte) => {
+                let start = i;
+                while i < text.len() && is_identifier(text[i]) {
+                    i += 1;
+                }
+                ret.push(Token::Identifier(String::from_utf8_lossy(&text[start..i]).into_owned()));
+            },
+            _ if byte.is_ascii_whitespace() => {
+                i += 1;
+                continue
+            },
+            _ => {
+                ret.push(Token::Punctuation(byte));
+                i += 1;
+            },
+        }
+        line_start = false;
+    }
+
+    ret
+}
+
+// Functions with a body and variables outside of any braces count as defined.
+fn summarize(path: &Path) -> Summary {
+    let text = fs::read(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    let tokens = tokens(&text);
+    let mut summary = Summary { defined: HashSet::new(), used: HashSet::new(), invokes_macros: false };
+    let mut depth = 0usize;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match *token {
+            Token::Punctuation(b'{') => {
+                depth += 1;
+                continue
+            },
+            Token::Punctuation(b'}') => {
+                depth = depth.saturating_sub(1);
+                continue
+            },
+            Token::Punctuation(_) => continue,
+            Token::Identifier(ref name) => name,
+        };
+        summary.used.insert(name.clone());
+        if depth > 0 {
+            continue
+        }
+
+        let defined = match tokens.get(i + 1) {
+            Some(&Token::Punctuation(b'(')) => {
+                // Nothing ahead of it, so not a declaration but a macro, e.g. DEFINE_GETTER(int, count);
+                let statement_start = i == 0 || matches!(tokens[i - 1], Token::Punctuation(b';' | b'}'));
+
+                // A body comes before the end of the declaration, past any
+                // attributes or parameter declarations after the parameters
+                let mut parens = 0;
+                let end = tokens[i + 1..].iter().find(|t| {
+                    match **t {
+                        Token::Punctuation(b'(') => parens += 1,
+                        Token::Punctuation(b')') => parens -= 1,
+                        _ => (),
+                    }
+                    parens == 0 && matches!(**t, Token::Punctuation(b'{' | b';'))
+                });
+                let body = end.is_some_and(|t| matches!(*t, Token::Punctuation(b'{')));
+
+                if statement_start && !body {
+                    summary.invokes_macros = true;
+                }
+                body
+            },
+            Some(&Token::Punctuation(b'=' | b';' | b'[' | b',')) => true,
+            _ => false,
+        };
+        if defined {
+            summary.defined.insert(name.clone());
+        }
+    }
+
+    summary
+}
+
+// The sources that can contribute to the closure of `targets`: the ones
+// defining them and, in turn, the ones defining anything those mention.
+pub fn select(sources: &[String], targets: &[String]) -> Vec<String> {
+    let summaries = sources.iter().map(|source| summarize(Path::new(source))).collect::<Vec<_>>();
+
+    let mut definers: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, summary) in summaries.iter().enumerate() {
+        for name in &summary.defined {
+            definers.entry(name).or_default().push(i);
+        }
+    }
+
+    let mut selected = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut wanted = targets.iter().map(String::as_str).collect::<VecDeque<_>>();
+
+    while let Some(name) = wanted.pop_front() {
+        if !seen.insert(name) {
+            continue
+        }
+
+        let mut files = definers.get(name).cloned().unwrap_or_default();
+        if files.is_empty() {
+            // Defined through a macro perhaps, by one of the sources invoking them
+            files = (0..sources.len()).filter(|&i| summaries[i].invokes_macros).collect();
+            if targets.iter().any(|t| t == name) {
+                // or at least somewhere mentioning it
+                files.extend((0..sources.len()).filter(|&i| summaries[i].used.contains(name)));
+            }
+        }
+
+        for i in files {
+            if selected.insert(i) {
+                wanted.extend(summaries[i].used.iter().map(String::as_str));
+            }
+        }
+    }
+
+    if selected.is_empty() {
+        eprintln!("Warning: none of the sources seems to define the targets, parsing all of them");
+        return sources.to_vec()
+    }
+
+    eprintln!("Parsing {} of {} sources", selected.len(), sources.len());
+    selected.into_iter().map(|i| sources[i].clone()).collect()
+}
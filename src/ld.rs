@@ -0,0 +1,82 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use clang::*;
+
+use boundary::glob;
+use symbol_table::SymbolTable;
+use in_system_header;
+
+// What a linker script keeps alive on its own: the ENTRY symbol, and the
+// sections named inside KEEP(), e.g. `.isr_vector` or `.init_array.*`.
+fn parse(script: &str) -> (Vec<String>, Vec<String>) {
+    // Comments are C style
+    let mut text = String::new();
+    let mut rest = script;
+    while let Some(start) = rest.find("/*") {
+        text.push_str(&rest[..start]);
+        rest = rest[start..].find("*/").map_or("", |end| &rest[start + end + 2..]);
+    }
+    text.push_str(rest);
+
+    let argument = |start: usize| -> Option<&str> {
+        // Up to the matching parenthesis
+        let mut depth = 0;
+        for (i, c) in text[start..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 1 => return Some(&text[start..start + i]),
+                ')' => depth -= 1,
+                _ => (),
+            }
+        }
+        None
+    };
+
+    let mut symbols = vec![];
+    let mut sections = vec![];
+
+    for (start, _) in text.match_indices("ENTRY") {
+        if let Some(symbol) = argument(start) {
+            symbols.push(symbol.trim_start_matches("ENTRY").trim_matches(|c: char| c == '(' || c.is_whitespace()).to_string());
+        }
+    }
+    for (start, _) in text.match_indices("KEEP") {
+        let patterns = argument(start).unwrap_or("");
+        let words = patterns.split(|c: char| c.is_whitespace() || c == '(' || c == ')');
+        sections.extend(words.filter(|w| w.starts_with('.')).map(String::from));
+    }
+
+    (symbols, sections)
+}
+
+// The section a definition is put in with `__attribute__((section("...")))`.
+fn section(entity: &Entity) -> Option<String> {
+    let tokens = entity.get_range()?.tokenize();
+    let position = tokens.iter().position(|t| t.get_spelling() == "section" || t.get_spelling() == "__section__")?;
+    let literal = tokens.get(position + 2)?.get_spelling();
+    Some(literal.trim_matches('"').to_string())
+}
+
+// Names of the symbols the linker `script` keeps, to be used as extra targets
+// so that vector tables and handlers registered by section survive.
+pub fn roots(script: &Path, sym_table: &SymbolTable) -> BTreeSet<String> {
+    let contents = fs::read_to_string(script).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", script, why));
+    let (symbols, sections) = parse(&contents);
+
+    let mut roots = symbols.into_iter().collect::<BTreeSet<_>>();
+
+    for entity in sym_table.keys().filter(|e| e.is_definition() && !in_system_header(e)) {
+        if !matches!(entity.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl) {
+            continue
+        }
+        let kept = section(entity).is_some_and(|name| sections.iter().any(|s| glob(s.as_bytes(), name.as_bytes())));
+        if kept {
+            roots.extend(entity.get_name());
+        }
+    }
+
+    eprintln!("{:?} keeps {} symbols: {}", script, roots.len(), roots.iter().cloned().collect::<Vec<_>>().join(", "));
+    roots
+}
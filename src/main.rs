@@ -2,31 +2,148 @@
 extern crate clang;
 
 use std::collections::{HashMap, HashSet, BTreeMap, VecDeque, BTreeSet};
-use std::ops::Bound::Included;
-use std::iter::FromIterator;
+use std::env;
+use std::iter;
+use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::path::{PathBuf};
+use std::path::{Component, Path, PathBuf, Prefix};
 use std::fs;
-use std::io::{BufRead};
-use std::io::prelude::*;
+use std::process;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::slice;
+use std::time::Instant;
 use std::io;
 use clang::*;
+use clang::diagnostic::Severity;
+use clang::source::SourceRange;
+use clang::token::{Token, TokenKind};
+
+mod archive;
+mod asm;
+mod bazel;
+mod bindgen;
+mod c2rust;
+mod boundary;
+mod cache;
+mod cmake;
+mod compile_db;
+mod conflicts;
+mod db;
+mod dead;
+mod diff;
+mod emit;
+mod fuzz;
+mod gaps;
+mod git;
+mod graph;
+mod header_only;
+mod incremental;
+mod intern;
+mod internalize;
+mod iwyu;
+mod json;
+mod kernel;
+mod lazy;
+mod ld;
+mod makefile;
+mod manifest;
+mod mocks;
+mod msvc;
+mod options;
+mod parse;
+mod path_map;
+mod pch;
+mod prepass;
+mod pkg_config;
+mod query;
+mod reduce;
+mod regions;
+mod rename;
+mod run;
+mod server;
+mod sha256;
+mod source_file;
+mod source_map;
+mod stats;
+mod stubs;
+mod symbol_list;
+mod symbol_table;
+mod sysroot;
+mod timings;
+mod trim;
+mod unity;
+mod vendor;
+mod verify;
+
+use boundary::Boundaries;
+use emit::OutputFile;
+use graph::EdgeKind;
+use internalize::Internalizer;
+use options::{Command, Direction, IncludePolicy, Options, Spacing, Symlinks};
+use rename::Renamer;
+use source_file::{SourceFile, read_lines};
+use symbol_table::SymbolTable;
+use source_map::{Mapping, SourceMap};
+use timings::Timings;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+struct CanonicalPath(Rc<Path>);
+
+// Paths are interned here, names and USRs by the symbol table.
+thread_local! {
+    // Every path is canonicalized once and shared afterwards
+    static CANONICAL_PATHS: RefCell<HashMap<PathBuf, Rc<Path>>> = RefCell::new(HashMap::new());
+}
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-struct CanonicalPath(PathBuf);
+// Settings of the run, the same on the parse threads as on the main one
+static SYMLINKS: OnceLock<Symlinks> = OnceLock::new();
+static CASE_INSENSITIVE: AtomicBool = AtomicBool::new(false);
+// Globs of the headers to count as system and as project ones whatever clang says
+static HEADER_CLASSES: OnceLock<(Boundaries, Boundaries)> = OnceLock::new();
 
 impl CanonicalPath {
     fn new(path: PathBuf) -> Self {
-        CanonicalPath(path.canonicalize().unwrap())
+        match Self::find(path.clone()) {
+            Ok(canonical) => canonical,
+            Err(why) => panic!("Couldn't find {:?}: {}", path, why),
+        }
+    }
+
+    fn find(path: PathBuf) -> io::Result<Self> {
+        CANONICAL_PATHS.with(|paths| {
+            let mut paths = paths.borrow_mut();
+            if let Some(canonical) = paths.get(&path) {
+                return Ok(CanonicalPath(canonical.clone()))
+            }
+
+            // Paths can come from a build done elsewhere, e.g. absolute includes in generated sources
+            let canonical = canonicalize(&path)
+                .or_else(|_| canonicalize(&path_map::map(&path)))?;
+            let canonical = match paths.get(&canonical) {
+                Some(interned) => interned.clone(),
+                None => Rc::from(canonical.as_path()),
+            };
+            paths.insert(canonical.to_path_buf(), canonical.clone());
+            paths.insert(path, canonical.clone());
+            Ok(CanonicalPath(canonical))
+        })
     }
 }
 
 #[derive(Default)]
-struct SymbolDesc<'a> {
-    deps: HashSet<Entity<'a>>,
+pub struct SymbolDesc<'a> {
+    deps: HashMap<Entity<'a>, BTreeSet<EdgeKind>>,
     definitions: HashSet<Entity<'a>>,
 }
 
+impl<'a> SymbolDesc<'a> {
+    fn add_dep(&mut self, dep: Entity<'a>, kind: EdgeKind) {
+        self.deps.entry(dep).or_default().insert(kind);
+    }
+}
+
 fn get_name(entity: &Entity) -> String {
     match entity.get_name() {
         Some(name) =>
@@ -36,6 +153,37 @@ fn get_name(entity: &Entity) -> String {
     }
 }
 
+// Whether `path` holds system headers, given what clang thinks of it, after
+// --treat-as-system and --treat-as-project had their say.
+fn is_system_file(path: &Path, in_system_directory: bool) -> bool {
+    match HEADER_CLASSES.get() {
+        Some((_, project)) if in_system_directory => !project.contains(path),
+        Some((system, _)) => system.contains(path),
+        None => in_system_directory,
+    }
+}
+
+fn in_system_header(entity: &Entity) -> bool {
+    let in_system_directory = entity.is_in_system_header();
+    match entity.get_location().and_then(|location| location.get_expansion_location().file) {
+        Some(file) => is_system_file(&file.get_path(), in_system_directory),
+        None => in_system_directory,
+    }
+}
+
+// The USR of `entity` if it's a function, the same for its prototypes and its definition.
+fn function_usr(entity: &Entity) -> Option<String> {
+    match entity.get_kind() {
+        EntityKind::FunctionDecl => entity.get_usr().map(|usr| usr.0),
+        _ => None,
+    }
+}
+
+// Whether `entity` is a prototype of one of the `defined` functions.
+fn is_redundant_prototype(entity: &Entity, defined: &HashSet<String>) -> bool {
+    !entity.is_definition() && function_usr(entity).is_some_and(|usr| defined.contains(&usr))
+}
+
 fn get_path(entity: &Entity) -> PathBuf {
     let location = entity.get_location().unwrap().get_file_location();
     location.file.unwrap().get_path()
@@ -48,10 +196,326 @@ fn get_location(entity: &Entity) -> String {
     format!("{}:{}", path, location.line)
 }
 
+// The deepest directory holding all of the `files`.
+fn common_ancestor<'p, I: IntoIterator<Item = &'p Path>>(files: I) -> Option<PathBuf> {
+    let mut ret: Option<PathBuf> = None;
+    for file in files {
+        let directory = file.parent().unwrap();
+        ret = Some(match ret {
+            None => directory.to_path_buf(),
+            Some(ancestor) => ancestor.components().zip(directory.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    ret
+}
+
+// Windows canonicalizes to extended-length paths (\\?\C:\...), which never
+// share a prefix with the C:\... ones that users and compilers spell.
+// Under --logical-paths only `.` and `..` go away, the symlinks stay, and
+// under --case-insensitive-includes the case is the one on disk.
+fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    let canonical = match SYMLINKS.get().copied().unwrap_or(Symlinks::Follow) {
+        Symlinks::Follow => fs::canonicalize(path)?,
+        Symlinks::Logical => {
+            fs::metadata(path)?;
+            let mut ret = if path.is_absolute() { PathBuf::new() } else { env::current_dir()? };
+            for component in path.components() {
+                match component {
+                    Component::CurDir => (),
+                    Component::ParentDir => { ret.pop(); },
+                    other => ret.push(other),
+                }
+            }
+            ret
+        },
+    };
+    let canonical = if CASE_INSENSITIVE.load(AtomicOrdering::Relaxed) { actual_case(&canonical) } else { canonical };
+    let mut components = canonical.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix,
+        _ => return Ok(canonical),
+    };
+
+    let mut ret = match prefix.kind() {
+        Prefix::VerbatimDisk(disk) => PathBuf::from(format!("{}:", disk as char)),
+        Prefix::VerbatimUNC(server, share) => {
+            PathBuf::from(format!(r"\\{}\{}", server.to_str().unwrap(), share.to_str().unwrap()))
+        },
+        _ => return Ok(canonical),
+    };
+    ret.extend(components);
+    Ok(ret)
+}
+
+// An absolute `path` turned into a relative one, e.g. /usr/include/stdio.h
+// into usr/include/stdio.h and C:\include\stdio.h into C/include/stdio.h.
+fn relative_components(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => Some(PathBuf::from((disk as char).to_string())),
+                _ => Some(PathBuf::from(prefix.as_os_str().to_str().unwrap().trim_matches(|c| c == '\\' || c == '?'))),
+            },
+            Component::RootDir => None,
+            other => Some(PathBuf::from(other.as_os_str())),
+        })
+        .collect()
+}
+
+// `path` spelled the way the disk has it, for case-insensitive file systems
+// where "Foo.h" opens foo.h but would otherwise be a different file.
+fn actual_case(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            other => {
+                ret.push(other);
+                continue
+            },
+        };
+
+        let directory = if ret.as_os_str().is_empty() { Path::new(".") } else { &ret };
+        let entries = fs::read_dir(directory).into_iter().flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name())
+            .collect::<Vec<_>>();
+        let actual = entries.iter().find(|entry| *entry == name)
+            .or_else(|| entries.iter().find(|entry| entry.to_string_lossy().eq_ignore_ascii_case(&name.to_string_lossy())));
+        ret.push(actual.map_or(name, |entry| entry.as_os_str()));
+    }
+    ret
+}
+
+// Where `path` goes in the output: the same place relative to the source
+// root, or under `external` for files from outside of it.
+fn relative_to_root(path: &Path, source_directory: &Path, external: &Path) -> PathBuf {
+    match path.strip_prefix(source_directory) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => external.join(relative_components(path)),
+    }
+}
+
+// The directive `include` was written with, "import" or "include", which
+// rewritten includes keep since #import only ever includes a file once.
+// #include_next becomes a plain #include, it only works on search paths.
+fn directive(include: &Entity) -> &'static str {
+    let tokens = include.get_range().map(|range| range.tokenize()).unwrap_or_default();
+    match tokens.get(1).map(|token| token.get_spelling()) {
+        Some(ref name) if name == "import" => "import",
+        _ => "include",
+    }
+}
+
+// How `to` is spelled in an include directive of `from`, both being paths in
+// the output. Always with `/`, which every compiler takes.
+fn include_spelling(from: &Path, to: &Path) -> String {
+    let directory = from.parent().unwrap();
+    let common = directory.components().zip(to.components()).take_while(|(a, b)| a == b).count();
+
+    let mut ret = vec![];
+    for _ in directory.components().skip(common) {
+        ret.push(String::from(".."));
+    }
+    ret.extend(to.components().skip(common).map(|c| String::from(c.as_os_str().to_str().unwrap())));
+    ret.join("/")
+}
+
+// A total order over entities that doesn't depend on hashing, used wherever
+// iteration order leaks into the output.
+fn sort_key(entity: &Entity) -> (PathBuf, u32, u32, String, Option<String>) {
+    let location = entity.get_location().unwrap().get_file_location();
+    let path = location.file.map(|f| f.get_path()).unwrap_or_default();
+    (path, location.line, location.column, format!("{:?}", entity.get_kind()), entity.get_name())
+}
+
+fn sorted<'a, 'e, I: IntoIterator<Item = &'e Entity<'a>>>(entities: I) -> Vec<Entity<'a>> where 'a: 'e {
+    let mut ret = entities.into_iter().cloned().collect::<Vec<_>>();
+    ret.sort_by_cached_key(sort_key);
+    ret
+}
+
+// The macro expansion that produced all of `entity`, like `DEFINE_GETTER(int, count)`
+// for the getter it defines. Such a symbol is only written down as the expansion.
+pub fn defining_expansion<'a>(entity: &Entity<'a>) -> Option<Entity<'a>> {
+    let range = entity.get_range()?;
+    let (start, end) = (range.get_start(), range.get_end());
+    let expansion = start.get_expansion_location();
+
+    // The end comes out of a macro body, and the same expansion as the start
+    let spelling = end.get_spelling_location();
+    let end = end.get_expansion_location();
+    if (end.file, end.offset) == (spelling.file, spelling.offset) || (end.file, end.offset) != (expansion.file, expansion.offset) {
+        return None
+    }
+
+    entity.get_translation_unit().get_entity().get_children().into_iter()
+        .filter(|e| e.get_kind() == EntityKind::MacroExpansion)
+        .find(|e| e.get_location().map(|l| l.get_expansion_location()).is_some_and(|l| (l.file, l.offset) == (expansion.file, expansion.offset)))
+}
+
+// The range `entity` is written in, the whole expansion for what a macro defined.
+pub fn symbol_range<'a>(entity: &Entity<'a>) -> SourceRange<'a> {
+    defining_expansion(entity).unwrap_or(*entity).get_range().unwrap()
+}
+
+// Returns the (possibly renamed) lines spanned by `entity` together with
+// their original 1-based line numbers.
+fn symbol_text(entity: &Entity, source: &SourceFile, renamer: Option<&Renamer>) -> (Vec<String>, (u32, u32)) {
+    let range = symbol_range(entity);
+    let start = range.get_start().get_file_location();
+    let end = range.get_end().get_file_location();
+    let path = CanonicalPath::new(get_path(entity));
+
+    // Columns count bytes, so this is where the first line starts
+    let line_start = start.offset - (start.column - 1);
+
+    let lines = source.lines_between(line_start as u64, end.offset as u64).into_iter()
+        .enumerate()
+        .map(|(i, text)| match renamer {
+            Some(renamer) => renamer.apply(&path.0, start.line + i as u32, &text),
+            None => text,
+        })
+        .collect();
+
+    (lines, (start.line, end.line))
+}
+
+// Macro expansions and includes by where they are, several of them can share a spot.
+type PreprocessorEntities<'a> = BTreeMap<(CanonicalPath, u32), Vec<Entity<'a>>>;
+// Macro definitions by name.
+type MacroDefinitions<'a> = HashMap<String, Entity<'a>>;
+
+// Byte ranges of the macro expansions stripped by --strip-macro, per file.
+type StrippedRanges = HashMap<CanonicalPath, Vec<(u32, u32)>>;
+
+// The symbol `def` belongs to. Enumerators and static members aren't in the
+// symbol table, the enum or the record declaring them is, and so constant
+// expressions like `char buf[SIZE]` or `N = BASE + 4` still lead to them.
+fn declaring_symbol<'a>(def: Entity<'a>, sym_table: &SymbolTable<'a>) -> Option<Entity<'a>> {
+    let is_scope = |e: &Entity| matches!(e.get_kind(),
+        EntityKind::EnumDecl | EntityKind::StructDecl | EntityKind::UnionDecl | EntityKind::ClassDecl);
+
+    let mut current = def;
+    while !sym_table.contains_key(&current) {
+        if !matches!(current.get_kind(), EntityKind::EnumConstantDecl | EntityKind::VarDecl) && !is_scope(&current) {
+            return None
+        }
+        current = current.get_semantic_parent().filter(is_scope)?;
+    }
+    Some(current)
+}
+
+// Whether `entity` is a sizeof or an alignof, which libclang only knows as a unary expression.
+fn is_size_query(entity: &Entity) -> bool {
+    entity.get_kind() == EntityKind::UnaryExpr && entity.get_range().is_some_and(|range| {
+        let tokens = range.tokenize();
+        tokens.first().is_some_and(|t| matches!(t.get_spelling().as_str(), "sizeof" | "alignof" | "_Alignof" | "__alignof" | "__alignof__"))
+    })
+}
+
+// The parameters of a function-like macro `definition`, with `__VA_ARGS__`
+// standing for `...`. Their names in the body are not references to anything.
+pub fn macro_parameters(definition: &Entity) -> Vec<String> {
+    let tokens = definition.get_range().map(|range| range.tokenize()).unwrap_or_default();
+    let offset = |t: &Token| t.get_location().get_file_location().offset;
+
+    // Only a parenthesis right after the name makes it function-like
+    match (tokens.first(), tokens.get(1)) {
+        (Some(name), Some(paren)) if paren.get_spelling() == "(" &&
+            offset(paren) == offset(name) + name.get_spelling().len() as u32 => (),
+        _ => return vec![],
+    }
+
+    tokens[2..].iter()
+        .map(|t| t.get_spelling())
+        .take_while(|s| s != ")")
+        .filter(|s| s != ",")
+        .map(|s| if s == "..." { String::from("__VA_ARGS__") } else { s })
+        .collect()
+}
+
+// Identifiers `expansion` makes with ## out of its arguments, like CONFIG_SIZE
+// out of `CFG(SIZE)` given `#define CFG(x) CONFIG_##x`.
+fn pasted_names(expansion: &Entity, definition: &Entity) -> Vec<String> {
+    let parameters = macro_parameters(definition);
+    if parameters.is_empty() {
+        return vec![]
+    }
+    let spellings = |e: &Entity| e.get_range().map(|range| range.tokenize()).unwrap_or_default()
+        .iter().map(|t| t.get_spelling()).collect::<Vec<_>>();
+
+    // NAME ( PARAMETERS ) BODY
+    let definition = spellings(definition);
+    let body = match definition.iter().position(|s| s == ")") {
+        Some(end) => &definition[end + 1 ..],
+        None => return vec![],
+    };
+
+    // NAME ( ARGUMENTS ), split at the commas outside of parentheses
+    let mut arguments = vec![String::new()];
+    let mut depth = 0;
+    for spelling in spellings(expansion).into_iter().skip(2) {
+        match spelling.as_str() {
+            "(" => depth += 1,
+            ")" if depth == 0 => break,
+            ")" => depth -= 1,
+            "," if depth == 0 && arguments.len() < parameters.len() => {
+                arguments.push(String::new());
+                continue
+            },
+            _ => (),
+        }
+        arguments.last_mut().unwrap().push_str(&spelling);
+    }
+    let substitute = |token: &String| match parameters.iter().position(|p| p == token) {
+        Some(i) => arguments.get(i).cloned().unwrap_or_default(),
+        None => token.clone(),
+    };
+
+    let mut names = vec![];
+    let mut i = 0;
+    while i < body.len() {
+        // #x makes a string, not a name
+        if body[i] == "#" {
+            i += 2;
+            continue
+        }
+        // Arguments merely substituted are references clang already sees
+        let mut name = substitute(&body[i]);
+        let mut pasted = false;
+        while body.get(i + 1).is_some_and(|s| s == "##") && i + 2 < body.len() {
+            name.push_str(&substitute(&body[i + 2]));
+            i += 2;
+            pasted = true;
+        }
+        if pasted && name.starts_with(|c: char| c.is_alphabetic() || c == '_') &&
+            name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            names.push(name);
+        }
+        i += 1;
+    }
+    names
+}
+
+// The declaration of the type `t` is made of, under any pointers and arrays.
+fn type_declaration(t: Type) -> Option<Entity> {
+    let mut t = t;
+    while let Some(inner) = t.get_pointee_type().or_else(|| t.get_element_type()) {
+        t = inner;
+    }
+    t.get_declaration()
+}
+
 fn visit<'a>(
     entity: Entity<'a>,
-    sym_table: &mut HashMap<Entity<'a>, SymbolDesc<'a>>,
-    macros: &BTreeMap<u32, Entity<'a>>
+    sym_table: &mut SymbolTable<'a>,
+    macros: &PreprocessorEntities<'a>,
+    macro_definitions: &MacroDefinitions<'a>,
+    stripped: &StrippedRanges
 ) -> SymbolDesc<'a> 
 {
     let mut desc: SymbolDesc = Default::default();
@@ -60,15 +524,44 @@ fn visit<'a>(
         desc.definitions.insert(def);
     }
 
+    let mut references = vec![];
+
+    // Whatever a stripped expansion refers to goes away with it
+    let is_stripped = |e: &Entity| {
+        let location = match e.get_location() {
+            Some(location) => location.get_file_location(),
+            None => return false,
+        };
+        location.file.and_then(|f| stripped.get(&CanonicalPath::new(f.get_path())))
+            .is_some_and(|ranges| ranges.iter().any(|&(start, end)| start <= location.offset && location.offset < end))
+    };
+
     entity.visit_children(|_, child| {
+        if !stripped.is_empty() && is_stripped(&child) {
+            return EntityVisitResult::Continue
+        }
         for def in child.get_definition().into_iter().chain(child.get_reference()) {
-            if sym_table.contains_key(&def) {
-                desc.deps.insert(def);
+            match declaring_symbol(def, sym_table) {
+                Some(symbol) if symbol == def => references.push((child, def)),
+                // Not an edge to itself when it's one of its own enumerators
+                Some(symbol) if symbol != entity => references.push((child, symbol)),
+                _ => (),
             }
             for t in def.get_type().into_iter().chain(def.get_typedef_underlying_type()) {
                 if let Some(t) = t.get_declaration() {
                     if sym_table.contains_key(&t) {
-                        desc.deps.insert(t);
+                        desc.add_dep(t, EdgeKind::Type);
+                    }
+                }
+            }
+        }
+
+        // `sizeof(*msg)` may be all there is to say that the layout of the struct matters
+        if is_size_query(&child) {
+            for operand in child.get_children() {
+                if let Some(t) = operand.get_type().and_then(type_declaration) {
+                    if sym_table.contains_key(&t) {
+                        desc.add_dep(t, EdgeKind::Type);
                     }
                 }
             }
@@ -76,26 +569,45 @@ fn visit<'a>(
         EntityVisitResult::Recurse
     });
 
+    // The callee of a call is referenced by the call itself and again by the
+    // expression naming it, which starts where the call does
+    let offset = |e: &Entity| e.get_location().map(|l| l.get_file_location().offset);
+    let calls = references.iter()
+        .filter(|(child, _)| child.get_kind() == EntityKind::CallExpr)
+        .map(|(child, def)| (offset(child), *def))
+        .collect::<HashSet<_>>();
+
+    for (child, def) in references {
+        let kind = match def.get_kind() {
+            EntityKind::FunctionDecl if calls.contains(&(offset(&child), def)) => EdgeKind::Call,
+            EntityKind::FunctionDecl => EdgeKind::AddressTaken,
+            EntityKind::StructDecl | EntityKind::UnionDecl | EntityKind::EnumDecl | EntityKind::TypedefDecl =>
+                EdgeKind::Type,
+            _ => EdgeKind::Use,
+        };
+        desc.add_dep(def, kind);
+    }
+
     // Here we want to see if there is any macro expansion within this function
     // so that we can add it as dependency
     // Expansion of the macro could happen in include directive as well
-    let range = entity.get_range().unwrap();
-    let start_line = range.get_start().get_file_location().line;
-    let end_line = range.get_end().get_file_location().line;
+    // A symbol defined by a macro expansion covers the expansion itself
+    let range = symbol_range(&entity);
+    let start = range.get_start().get_file_location().offset;
+    let end = range.get_end().get_file_location().offset;
+    let file = CanonicalPath::new(get_path(&entity));
 
     let mut includes = vec![];
 
-    for (_, &child) in macros.range((Included(start_line), Included(end_line))) {
-        if child.get_location().unwrap().get_file_location().file == 
-                entity.get_location().unwrap().get_file_location().file 
-        {
+    for (_, children) in macros.range((file.clone(), start) ..= (file, end)) {
+        for &child in children {
             match child.get_kind() {
                 EntityKind::MacroExpansion => {
-                    desc.deps.insert(child);
+                    desc.add_dep(child, EdgeKind::Macro);
                 },
                 EntityKind::InclusionDirective => {
                     includes.push(child);
-                    desc.deps.insert(child);
+                    desc.add_dep(child, EdgeKind::Include);
                 },
                 _ => panic!("Should not happen"),
             }
@@ -103,16 +615,63 @@ fn visit<'a>(
     }
 
     // In case that there was an include inside of the function
-    // we need to see if there are any macros that happen to expand inside that file
-    for include in includes {
-        let include_name = include.get_name().unwrap();
-        for child in macros.values() {
-            // This is really inefficient, but should happen rarely
-            let file_path = get_path(child);
-            let file_path = file_path.to_str().unwrap();
+    // we need to see if there are any macros that happen to expand inside that file,
+    // or inside whatever that file includes in turn
+    let mut seen = HashSet::new();
+    while let Some(include) = includes.pop() {
+        let included = match include.get_file() {
+            Some(file) => CanonicalPath::new(file.get_path()),
+            None => continue,
+        };
+        if !seen.insert(included.clone()) {
+            continue
+        }
 
-            if file_path.contains(include_name.as_str()) {
-                desc.deps.insert(child.clone());
+        for (_, children) in macros.range((included.clone(), 0) ..= (included, u32::MAX)) {
+            for &child in children {
+                match child.get_kind() {
+                    EntityKind::MacroExpansion => desc.add_dep(child, EdgeKind::Macro),
+                    EntityKind::InclusionDirective => includes.push(child),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Macros used only in the bodies of other macros, like offsetof inside
+    // container_of, never expand in a file and have to be followed by name
+    let expansions = desc.deps.keys()
+        .filter(|d| d.get_kind() == EntityKind::MacroExpansion)
+        .filter_map(|e| e.get_reference().map(|definition| (*e, definition)))
+        .collect::<Vec<_>>();
+    let mut bodies = vec![];
+    for (expansion, definition) in expansions {
+        bodies.push(definition);
+        // Names pasted together from the arguments aren't spelled out anywhere
+        for name in pasted_names(&expansion, &definition) {
+            if let Some(&pasted) = macro_definitions.get(&name) {
+                desc.add_dep(pasted, EdgeKind::Macro);
+                bodies.push(pasted);
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    while let Some(definition) = bodies.pop() {
+        if !seen.insert(definition) {
+            continue
+        }
+        let tokens = definition.get_range().map(|range| range.tokenize()).unwrap_or_default();
+        let parameters = macro_parameters(&definition);
+        for token in tokens.iter().skip(1).filter(|t| t.get_kind() == TokenKind::Identifier) {
+            let spelling = token.get_spelling();
+            if parameters.contains(&spelling) {
+                continue
+            }
+            if let Some(&nested) = macro_definitions.get(&spelling) {
+                if nested != definition {
+                    desc.add_dep(nested, EdgeKind::Macro);
+                    bodies.push(nested);
+                }
             }
         }
     }
@@ -120,81 +679,313 @@ fn visit<'a>(
     desc
 }
 
+#[derive(Default)]
+struct Closure<'a> {
+    symbols: HashSet<Entity<'a>>,
+    // Functions whose definitions were left out on purpose; their prototypes stay
+    excluded: HashSet<Entity<'a>>,
+    // Dependencies that were cut by the depth limit
+    frontier: HashSet<Entity<'a>>,
+}
+
+// Whether `entity` is one of `targets`, which name symbols or, for those
+// picked by location, give their USR.
+fn is_target(entity: &Entity, targets: &[String]) -> bool {
+    entity.get_name().is_some_and(|name| targets.contains(&name)) ||
+        entity.get_usr().is_some_and(|usr| targets.contains(&usr.0))
+}
+
+// The name in `target`, which for a USR like c:@F@name or c:file.c@F@name
+// comes last.
+fn target_name(target: &str) -> &str {
+    match target.strip_prefix("c:") {
+        Some(usr) => usr.rsplit('@').next().unwrap().split('#').next().unwrap(),
+        None => target,
+    }
+}
+
+// The top-level symbol around `line` of `file` as a target, a definition if
+// there is one.
+fn target_at<'a>(sym_table: &SymbolTable<'a>, file: &Path, line: u32) -> Option<String> {
+    let file = CanonicalPath::new(file.to_path_buf());
+    let mut enclosing = sorted(sym_table.keys().filter(|e| {
+        let range = match e.get_range() {
+            Some(range) => range,
+            None => return false,
+        };
+        let (start, end) = (range.get_start().get_file_location(), range.get_end().get_file_location());
+        start.file.is_some_and(|f| CanonicalPath::new(f.get_path()) == file) && start.line <= line && line <= end.line
+    }));
+    enclosing.sort_by_key(|e| !e.is_definition());
+
+    let entity = enclosing.first()?;
+    entity.get_usr().map(|usr| usr.0).filter(|usr| !usr.is_empty()).or_else(|| entity.get_name())
+}
+
+// Every function and type declared in `header`, its public API, as targets.
+fn api_targets<'a>(sym_table: &SymbolTable<'a>, header: &Path) -> BTreeSet<String> {
+    let header = CanonicalPath::new(header.to_path_buf());
+    sym_table.keys()
+        .filter(|e| matches!(e.get_kind(),
+            EntityKind::FunctionDecl | EntityKind::StructDecl | EntityKind::UnionDecl |
+            EntityKind::EnumDecl | EntityKind::TypedefDecl))
+        .filter(|e| CanonicalPath::new(get_path(e)) == header)
+        .filter_map(|e| e.get_usr().map(|usr| usr.0).filter(|usr| !usr.is_empty()).or_else(|| e.get_name()))
+        .collect()
+}
+
 fn extract_symbols<'a>(
     targets: Vec<String>, 
-    sym_table: HashMap<Entity<'a>, SymbolDesc<'a>>
-) -> HashSet<Entity<'a>>
+    excludes: &HashSet<String>,
+    max_depth: Option<usize>,
+    boundaries: &Boundaries,
+    sym_table: &SymbolTable<'a>
+) -> Closure<'a>
 {
     // Now we can do a flood fill starting with all target symbols
     let mut visited = HashSet::new();
+    let mut excluded = HashSet::new();
+    let mut frontier = HashSet::new();
     let mut q = VecDeque::new();
 
-    {
-        let target_names: HashSet<String> = HashSet::from_iter(targets);
-
-        for entity in sym_table.keys() {
-            if let Some(name) = entity.get_name() {
-                if target_names.contains(&name) {
-                    q.push_back(entity);
-                }
-            }
-        }
+    for entity in sym_table.named(&targets) {
+        q.push_back((entity, 0));
     }
 
-    while let Some(entity) = q.pop_front() {
+    while let Some((entity, depth)) = q.pop_front() {
         if visited.contains(entity) {
             continue
         }
 
+        let is_excluded = entity.get_kind() == EntityKind::FunctionDecl &&
+            entity.get_name().is_some_and(|name| excludes.contains(&name));
+
+        if is_excluded {
+            excluded.insert(*entity);
+            if entity.is_definition() {
+                continue
+            }
+        }
+
         visited.insert(entity.clone());
 
         match entity.get_kind() {
-            EntityKind::InclusionDirective | EntityKind::MacroExpansion => continue,
+            EntityKind::InclusionDirective | EntityKind::MacroExpansion | EntityKind::MacroDefinition => continue,
             _ => (),
         }
 
+        // The real library provides whatever is behind a boundary header
+        if boundaries.contains(&get_path(entity)) {
+            continue
+        }
+
         let desc = &sym_table[&entity];
 
-        for dep in desc.deps.iter().chain(desc.definitions.iter()) {
-            if !visited.contains(dep) {
-                q.push_back(dep);
+        // A definition is the same symbol as its declaration, so it doesn't
+        // count as a hop and goes to the front to keep the depths in order
+        if !is_excluded {
+            for def in desc.definitions.iter().filter(|d| !visited.contains(d)) {
+                q.push_front((def, depth));
+            }
+        }
+
+        for dep in desc.deps.keys().filter(|d| !visited.contains(d)) {
+            if max_depth.is_some_and(|max| depth >= max) {
+                frontier.insert(*dep);
+            } else {
+                q.push_back((dep, depth + 1));
             }
         }
     }
 
+    // Builtin macros like __FILE__ have no definition to take along
     let used_macros = visited.iter()
         .filter(|e| e.get_kind() == EntityKind::MacroExpansion)
-        .map(|e| e.clone().get_reference().unwrap())
+        .filter_map(|e| e.get_reference())
         .collect::<HashSet<_>>();
 
     for entity in used_macros {
         visited.insert(entity.clone());
     }
 
-    visited
+    let frontier = frontier.into_iter().filter(|e| !visited.contains(e)).collect();
+
+    Closure { symbols: visited, excluded, frontier }
 }
 
 fn main() {
+    let options = Options::from_args();
+    options.path_map.install();
+    SYMLINKS.set(options.symlinks).ok();
+    CASE_INSENSITIVE.store(options.case_insensitive_includes, AtomicOrdering::Relaxed);
+    if !options.treat_as_system.is_empty() || !options.treat_as_project.is_empty() {
+        let classes = (Boundaries::new(&options.treat_as_system), Boundaries::new(&options.treat_as_project));
+        HEADER_CLASSES.set(classes).ok();
+    }
+    let mut timings = Timings::new();
+
+    if let Command::Update(ref upstream) = options.command {
+        vendor::update(&options.output, upstream.as_deref());
+        return
+    }
+
+    let cache = options.cache.as_deref().map(cache::Store::new);
+
+    if let (Command::Rdeps(name), Some(store)) = (&options.command, &cache) {
+        if let Some(analysis) = cache::load(store, &options.sources, &options.clang_args) {
+            eprintln!("Using the cached analysis in {:?}", store);
+            match analysis.rdeps(name) {
+                Some(labels) => for label in labels {
+                    println!("{}", label);
+                },
+                None => {
+                    eprintln!("Symbol {} not found", name);
+                    process::exit(1);
+                },
+            }
+            return
+        }
+    }
+    if let (true, Some(store)) = (options.replayable(), &cache) {
+        if let Some(outputs) = cache::load_extraction(store, &options) {
+            eprintln!("Restoring the extraction of {} files from the cache in {:?}", outputs.len(), store);
+            for (path, contents) in outputs {
+                let path = options.output.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                emit::write_if_changed(&path, contents);
+            }
+            return
+        }
+    }
+
+    if options.c2rust && options.staged() {
+        // compile_commands.json points into the output directory
+        eprintln!("--c2rust needs the tree written to a directory");
+        process::exit(1);
+    }
+
+    let up_to_date = options.incremental && !options.staged() &&
+        incremental::State::load(&options.output).is_some_and(|state| state.is_fresh());
+    if up_to_date {
+        eprintln!("{:?} is up to date", options.output);
+        return
+    }
+
+    if (options.lazy || options.prepass) && (!matches!(options.command, Command::Extract | Command::Diff(_) | Command::Vendor) ||
+        !options.target_locations.is_empty() || !options.api_headers.is_empty() ||
+        options.direction == Direction::Callers || options.kernel || options.roots_from_ld.is_some())
+    {
+        // Those look for their symbols in everything that was parsed
+        eprintln!("--lazy and --prepass need every target to be named with --target");
+        process::exit(1);
+    }
+
     let clang = Clang::new().unwrap();
-    let index = Index::new(&clang, false, true);
 
-    let sources = vec!["examples/simple.c", "examples/simple_impl.c"]; ////vec!["../libart/src/art.c"]; //
-    let targets = vec![String::from("main")];
+    let selected;
+    let sources = if options.lazy {
+        let names = options.targets.iter().map(|t| String::from(target_name(t))).collect::<Vec<_>>();
+        selected = lazy::select(&options.sources, &names);
+        &selected
+    } else {
+        &options.sources
+    };
+    let mut targets = options.targets.clone();
+    let mut boundaries = Boundaries::new(&options.boundaries);
+    boundaries.add_roots(&options.external_roots);
+    if options.tracked_only {
+        let directory = canonicalize(Path::new(&sources[0]).parent().unwrap()).unwrap();
+        match git::root(&directory) {
+            Some(root) => {
+                let tracked = git::tracked_files(&root);
+                boundaries.tracked_only(root, tracked);
+            },
+            None => {
+                eprintln!("--tracked-only needs the sources to be in a git checkout");
+                process::exit(1);
+            },
+        }
+    }
+
+    let jobs = options.jobs.min(sources.len()).max(1);
+    let indexes = parse::indexes(&clang, jobs);
+    let parse_args = match options.pch {
+        Some(ref header) => {
+            let args = pch::arguments(&indexes[0], header, &options.clang_args);
+            timings.lap("precompiled header");
+            args
+        },
+        None => options.clang_args.clone(),
+    };
+
+    let parsed = if options.prepass {
+        let names = options.targets.iter().map(|t| String::from(target_name(t))).collect::<Vec<_>>();
+        prepass::parse_needed(&indexes, sources, &parse_args, &names, options.keep_going)
+    } else if options.incremental {
+        // Sources none of whose files changed come from the parses saved last time
+        let mut parsed = vec![];
+        let mut changed = vec![];
+        for source in sources {
+            let start = Instant::now();
+            match incremental::load_ast(&indexes[0], source, &parse_args) {
+                Some(tu) => {
+                    eprintln!("Reusing the parse of {}", source);
+                    parsed.push((source.clone(), tu, start.elapsed()));
+                },
+                None => changed.push(source.clone()),
+            }
+        }
+        for (i, tu, elapsed) in parse::parse_all(&indexes, &changed, &parse_args, false, options.keep_going) {
+            incremental::save_ast(&tu, &changed[i], &parse_args);
+            parsed.push((changed[i].clone(), tu, elapsed));
+        }
+        parsed.sort_by_key(|(source, _, _)| sources.iter().position(|s| s == source));
+        parsed
+    } else {
+        parse::parse_all(&indexes, sources, &parse_args, false, options.keep_going).into_iter()
+            .map(|(i, tu, elapsed)| (sources[i].clone(), tu, elapsed))
+            .collect()
+    };
 
     let mut tus = vec![];
-    let mut sym_table = HashMap::new();
-    let mut includes = HashSet::new();
-    let mut system_includes = HashMap::new();
+    let mut parsed_sources = vec![];
+    for (source, tu, elapsed) in parsed {
+        timings.add_translation_unit(&source, elapsed);
+        tus.push(tu);
+        parsed_sources.push(source);
+    }
 
-    for source in &sources {
-        println!("Parsing {}...", source);
-        tus.push(index
-            .parser(source)
-            .detailed_preprocessing_record(true)
-            .parse()
-            .unwrap());
+    if let Some(severity) = options.diagnostics {
+        parse::print_diagnostics(&tus, severity);
     }
 
+    // With --keep-going, what went wrong in the sources that didn't parse cleanly
+    let mut parse_errors = BTreeMap::new();
+    if options.keep_going {
+        if !options.prepass {
+            for source in sources.iter().filter(|s| !parsed_sources.contains(s)) {
+                parse_errors.insert(source.clone(), vec![String::from("libclang couldn't parse it at all")]);
+            }
+        }
+        for (tu, source) in tus.iter().zip(&parsed_sources) {
+            let errors = tu.get_diagnostics().into_iter()
+                .filter(|d| d.get_severity() >= Severity::Error)
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>();
+            if !errors.is_empty() {
+                eprintln!("Warning: {} has {} errors, carrying on with what could be parsed", source, errors.len());
+                parse_errors.insert(source.clone(), errors);
+            }
+        }
+    }
+    // Whatever the prepass left out is as good as not given from here on
+    let sources = &parsed_sources;
+    timings.lap("parse");
+
+    let mut sym_table = SymbolTable::default();
+    let mut includes = HashSet::new();
+    let mut system_headers = HashSet::new();
+
     {
         // Let's generate a list of 
         //    - Global symbols
@@ -206,62 +997,95 @@ fn main() {
                     sym_table.insert(child, Default::default());
                 } else if child.get_kind() == EntityKind::InclusionDirective {
                     includes.insert(child);
-                }
 
-                if child.is_in_system_header() {
-                    if let Some(location) = child.get_location() {
-                        if let Some(file) = location.get_file_location().file {
-                            let full_path = file.get_path();
-                            let file_name = full_path.file_name().unwrap();
-                            system_includes.insert(
-                                String::from(file_name.to_str().unwrap()), 
-                                CanonicalPath::new(full_path.clone()));
+                    // Whatever clang found in a system include directory
+                    if let Some(file) = child.get_file() {
+                        if is_system_file(&file.get_path(), file.get_location(1, 1).is_in_system_header()) {
+                            system_headers.insert(CanonicalPath::new(file.get_path()));
                         }
                     }
                 }
             }
         }
     }
+    timings.lap("symbol table");
 
     // Let's generate a dependency graph of symbols
-    for tu in &tus {
+    let mut stripped_expansions = vec![];
+    for (tu, source) in tus.iter().zip(sources) {
+        let start = Instant::now();
         let mut macros = BTreeMap::new();
+        let mut macro_definitions = HashMap::new();
+        let mut stripped = HashMap::new();
         for child in tu.get_entity().get_children() {
-            if child.is_in_system_header() {
+            if in_system_header(&child) {
                 continue
             }
+            if child.get_kind() == EntityKind::MacroExpansion && child.get_name().is_some_and(|n| options.strip_macros.contains(&n)) {
+                let range = child.get_range().unwrap();
+                let (start, end) = (range.get_start().get_file_location(), range.get_end().get_file_location());
+                if let Some(file) = start.file {
+                    stripped.entry(CanonicalPath::new(file.get_path())).or_insert_with(Vec::new).push((start.offset, end.offset));
+                    stripped_expansions.push(child);
+                }
+                continue
+            }
+            if child.get_kind() == EntityKind::MacroDefinition {
+                if let Some(name) = child.get_name() {
+                    macro_definitions.entry(name).or_insert(child);
+                }
+            }
             // Note: all macro expansions are top level entity
             match child.get_kind() {
                 EntityKind::MacroExpansion | EntityKind::InclusionDirective | EntityKind::MacroDefinition => {
-                    let location = child.get_location().unwrap();
-                    let location = location.get_expansion_location();
-                    macros.insert(location.line, child);
+                    let location = child.get_location().unwrap().get_expansion_location();
+                    if let Some(file) = location.file {
+                        macros.entry((CanonicalPath::new(file.get_path()), location.offset)).or_insert_with(Vec::new).push(child);
+                    }
                 },
                 _ => (),
             }
         }
+        timings.lap("macro association");
 
         for child in tu.get_entity().get_children() {
-            if child.is_in_system_header() {
+            if in_system_header(&child) {
                 continue
             }
             if child.is_definition() || child.is_declaration() {
-                let desc = visit(child, &mut sym_table, &macros);
+                let desc = visit(child, &mut sym_table, &macros, &macro_definitions, &stripped);
                 sym_table.insert(child, desc);
             }
         }
+        timings.lap("dependencies");
+        timings.add_translation_unit(source, start.elapsed());
+    }
+
+    if let Some(ref store) = cache {
+        for (tu, source) in tus.iter().zip(sources) {
+            cache::store(store, tu, source, &options.clang_args, &sym_table);
+        }
+        timings.lap("cache");
     }
 
     {
         // Now we have to attach all the definitions to the declarations.
-        // We can identify declaration by a source location.
+        // We can identify declaration by its USR, which is the same in every
+        // translation unit. Entities without one fall back to their location.
+
+        let identities = sym_table.keys()
+            .filter(|e| e.is_declaration())
+            .map(|e| match sym_table.usr_name(e) {
+                Some(usr) => (*e, (Some(usr), None)),
+                None => (*e, (None, Some(e.get_location().unwrap().get_file_location()))),
+            })
+            .collect::<HashMap<_, _>>();
 
         let mut decl_to_def_table = HashMap::new();
 
         for (entity, desc) in sym_table.iter() {
             if entity.is_declaration() {
-                let location = entity.get_location().unwrap().get_file_location();
-                let entry = decl_to_def_table.entry(location).or_insert(HashSet::<Entity>::new());
+                let entry = decl_to_def_table.entry(&identities[entity]).or_insert(HashSet::<Entity>::new());
 
                 for def in &desc.definitions {
                     entry.insert(def.clone());
@@ -271,8 +1095,7 @@ fn main() {
 
         for (entity, desc) in sym_table.iter_mut() {
             if entity.is_declaration() {
-                let location = entity.get_location().unwrap().get_file_location();
-                let defintions = &decl_to_def_table[&location];
+                let defintions = &decl_to_def_table[&identities[entity]];
 
                 for def in defintions {
                     desc.definitions.insert(def.clone());
@@ -281,9 +1104,226 @@ fn main() {
         }
     }
 
-    let extracted_symbols = extract_symbols(targets, sym_table);
+    if let Err(why) = conflicts::resolve(&mut sym_table, &options.prefer_definitions) {
+        eprintln!("{}", why);
+        process::exit(1);
+    }
+    timings.lap("definitions");
+
+    if options.direction == Direction::Callers {
+        // Everything on a path from an entry point down to the targets becomes a target itself
+        let reverse = query::reverse_edges(&sym_table);
+        let seeds = sym_table.named(&targets).cloned().collect::<Vec<_>>();
+        let callers = query::users(&reverse, &seeds).into_iter()
+            .filter(|e| matches!(e.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl))
+            .filter_map(|e| e.get_name())
+            .collect::<BTreeSet<_>>();
+
+        eprintln!("{} callers lead to the targets", callers.len());
+        for caller in callers {
+            if !targets.contains(&caller) {
+                targets.push(caller);
+            }
+        }
+    }
+
+    if options.kernel {
+        for root in kernel::roots(&tus) {
+            if !targets.contains(&root) {
+                targets.push(root);
+            }
+        }
+    }
+
+    if let Some(ref script) = options.roots_from_ld {
+        for root in ld::roots(script, &sym_table) {
+            if !targets.contains(&root) {
+                targets.push(root);
+            }
+        }
+    }
+
+    for header in &options.api_headers {
+        let api = api_targets(&sym_table, header);
+        eprintln!("{} declares {} functions and types", header.display(), api.len());
+        for target in api {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+
+    for (file, line) in &options.target_locations {
+        match target_at(&sym_table, file, *line) {
+            Some(target) => {
+                eprintln!("{}:{} is in {}", file.display(), line, target);
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            },
+            None => {
+                eprintln!("No symbol at {}:{}", file.display(), line);
+                process::exit(1);
+            },
+        }
+    }
+
+    if let Command::Rdeps(ref name) = options.command {
+        let reverse = query::reverse_edges(&sym_table);
+        let seeds = query::find(&sym_table, &reverse, name);
+
+        if seeds.is_empty() {
+            eprintln!("Symbol {} not found", name);
+            process::exit(1);
+        }
+        for label in query::rdeps(&reverse, &seeds) {
+            println!("{}", label);
+        }
+        return
+    }
+
+    if options.command == Command::Server {
+        server::serve(&tus, &sym_table, &options, &boundaries);
+        return
+    }
+    if let Command::Serve(ref socket) = options.command {
+        #[cfg(unix)]
+        {
+            server::serve_socket(socket, &tus, &sym_table, &options, &boundaries);
+            return
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("Can't listen on {:?}, serve needs unix sockets; `ccthief server` works over stdin and stdout", socket);
+            process::exit(1);
+        }
+    }
+
+    for name in &options.explain {
+        let seeds = sym_table.named(&targets).cloned().collect::<Vec<_>>();
+
+        match query::explain(&sym_table, &seeds, name) {
+            Some(path) => {
+                eprintln!("{} is needed by:", name);
+                eprintln!("  {}", graph::node_label(&path[0].0));
+                for &(entity, kind) in &path[1..] {
+                    eprintln!("  -{}-> {}", kind, graph::node_label(&entity));
+                }
+            },
+            None => eprintln!("{} is not reachable from the targets", name),
+        }
+    }
 
     {
+        // A target nothing matches would silently make for an empty closure
+        let known = sym_table.keys()
+            .flat_map(|e| sym_table.name(e).into_iter().chain(sym_table.usr(e)))
+            .collect::<HashSet<_>>();
+        let (listed, named): (Vec<_>, Vec<_>) = options.targets.iter()
+            .filter(|t| !known.contains(t.as_str()))
+            .partition(|t| options.listed_targets.contains(*t));
+
+        if !listed.is_empty() {
+            eprintln!("Warning: no source declares {} of the listed symbols: {}", listed.len(),
+                listed.iter().map(|t| t.as_str()).collect::<Vec<_>>().join(", "));
+        }
+        for target in &named {
+            eprintln!("{}: target {} not found", if options.allow_missing_targets { "Warning" } else { "Error" }, target);
+            let similar = query::similar_names(&sym_table, target_name(target));
+            if !similar.is_empty() {
+                eprintln!("  did you mean: {}", similar.join(", "));
+            }
+        }
+        if !named.is_empty() && !options.allow_missing_targets {
+            process::exit(1);
+        }
+    }
+
+    let closure = extract_symbols(targets.clone(), &options.excludes, options.max_depth, &boundaries, &sym_table);
+    timings.lap("flood fill");
+    let extracted_symbols = &closure.symbols;
+
+    if !closure.frontier.is_empty() {
+        // Expansions are reported as the macro they expand
+        let cut = closure.frontier.iter()
+            .filter(|e| e.get_kind() != EntityKind::InclusionDirective)
+            .filter_map(|e| if e.get_kind() == EntityKind::MacroExpansion { e.get_reference() } else { Some(*e) })
+            .map(|e| graph::node_label(&e))
+            .collect::<BTreeSet<_>>();
+
+        eprintln!("Stopped after {} hops, {} symbols were cut:", options.max_depth.unwrap(), cut.len());
+        for label in cut {
+            eprintln!("  {}", label);
+        }
+    }
+
+    // Declarations nothing defines and that no library is allowed to provide
+    let undefined = extracted_symbols.iter()
+        .filter(|e| matches!(e.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl))
+        .filter(|e| !e.is_definition() && sym_table[*e].definitions.is_empty())
+        .filter(|e| e.get_linkage() == Some(Linkage::External) && !closure.excluded.contains(*e))
+        .filter(|e| !in_system_header(e) && !boundaries.contains(&get_path(e)))
+        .collect::<Vec<_>>();
+
+    let asm_sources = asm::providing(&options.asm_sources, &undefined.iter().filter_map(|e| e.get_name()).collect());
+    for (source, names) in &asm_sources {
+        eprintln!("{:?} defines {}", source, names.join(", "));
+    }
+    let provided = asm_sources.values().flatten().collect::<HashSet<_>>();
+
+    if options.strict {
+        let unresolved = undefined.iter()
+            .filter(|e| !e.get_name().is_some_and(|name| provided.contains(&name)))
+            .map(|e| format!("{} ({})", graph::node_label(e), get_location(e)))
+            .collect::<BTreeSet<_>>();
+
+        if !unresolved.is_empty() {
+            eprintln!("Error: {} dependencies have no definition to extract and aren't allowed from outside:", unresolved.len());
+            for dependency in unresolved {
+                eprintln!("  {}", dependency);
+            }
+            process::exit(1);
+        }
+    }
+
+    if let Some(ref path) = options.emit_callgraph {
+        graph::Graph::call_graph(&sym_table).write(path);
+    }
+
+    if let Some(ref path) = options.emit_db {
+        db::write(path, &sym_table);
+    }
+
+    if let Some(ref path) = options.dead_symbols {
+        dead::write(path, &sym_table, extracted_symbols);
+    }
+
+    if let Some(ref path) = options.unused_includes {
+        iwyu::write(path, &sym_table, &includes);
+    }
+
+    if !options.emit_graph.is_empty() {
+        let graph = graph::Graph::new(extracted_symbols, &sym_table, &options.graph_edges);
+        for path in &options.emit_graph {
+            graph.write(path);
+        }
+    }
+    timings.lap("reports");
+
+    let mut verified = true;
+
+    // Every group gets a directory of its own, sharing everything up to here
+    let groups = if options.groups.is_empty() {
+        vec![(options.output.clone(), targets.clone())]
+    } else {
+        // Roots found in the sources go along with every group
+        let found = targets.iter().filter(|t| !options.targets.contains(t)).cloned().collect::<Vec<_>>();
+        options.groups.iter()
+            .map(|(name, group)| (options.output.join(name), group.iter().chain(&found).cloned().collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+    };
+
+    for (output, targets) in &groups {
         // Now we have to walk the extracted symbols and recreate the diractory structure.
         #[derive(Eq, Debug, Clone)]
         struct OrdSymbol<'a>(Entity<'a>);
@@ -310,16 +1350,39 @@ fn main() {
             }
         }
 
+        let group_closure;
+        let closure = if options.groups.is_empty() {
+            &closure
+        } else {
+            eprintln!("Extracting into {:?}", output);
+            group_closure = extract_symbols(targets.clone(), &options.excludes, options.max_depth, &boundaries, &sym_table);
+            &group_closure
+        };
+        let extracted_symbols = &closure.symbols;
+
+        // Dependencies the closure cut, to be marked where the emitted code uses them
+        let mut cuts = HashMap::new();
+        if options.mark_cuts {
+            gaps::add(&mut cuts, &closure.excluded, "excluded");
+            gaps::add(&mut cuts, &closure.frontier, "past --max-depth");
+            let behind_boundaries = extracted_symbols.iter().filter(|e| {
+                !matches!(e.get_kind(), EntityKind::InclusionDirective | EntityKind::MacroExpansion) &&
+                    e.get_location().and_then(|l| l.get_file_location().file).is_some_and(|f| boundaries.contains(&f.get_path()))
+            });
+            gaps::add(&mut cuts, behind_boundaries, "left to the library behind a boundary");
+        }
+
         let (symbols_per_file, unparsable_includes) = {
             let mut ret = HashMap::new();
             let mut ui = HashSet::new();
 
-            for sym in &extracted_symbols {
+            // Symbols sharing a line collapse into one, so the iteration order decides which
+            for sym in sorted(extracted_symbols) {
                 if sym.get_kind() == EntityKind::InclusionDirective {
-                    ui.insert(sym.clone());
+                    ui.insert(sym);
                 } else {
-                    let entry = ret.entry(CanonicalPath::new(get_path(sym))).or_insert(BTreeSet::<OrdSymbol>::new());
-                    entry.insert(OrdSymbol(sym.clone()));
+                    let entry = ret.entry(CanonicalPath::new(get_path(&sym))).or_insert(BTreeSet::<OrdSymbol>::new());
+                    entry.insert(OrdSymbol(sym));
                 }
             }
 
@@ -328,97 +1391,649 @@ fn main() {
 
         let includes_per_file = {
             let mut ret = HashMap::new();
-            for include in &includes {
-                let path = CanonicalPath::new(get_path(include));
+            for include in sorted(&includes) {
+                let path = CanonicalPath::new(get_path(&include));
                 let entry = ret.entry(path).or_insert(BTreeSet::<OrdSymbol>::new());
-                entry.insert(OrdSymbol(include.clone()));
+                entry.insert(OrdSymbol(include));
             }
             ret
         };
 
-        let normalize_include_path = |entity: &Entity| -> CanonicalPath {
-            let include_name = entity.get_name().unwrap();
+        // The file clang included, None when it couldn't find it, e.g. a missing system header
+        let normalize_include_path = |entity: &Entity| -> Option<CanonicalPath> {
+            entity.get_file().and_then(|file| CanonicalPath::find(file.get_path()).ok())
+        };
 
-            if let Some(full_path) = system_includes.get(&include_name) {
-                full_path.clone()
-            } else {
-                let mut start_path = get_path(entity);
-                start_path.pop();
-                CanonicalPath::new(start_path.join(PathBuf::from(include_name)))
+        for include in sorted(&includes) {
+            if normalize_include_path(&include).is_none() {
+                let location = include.get_location().unwrap().get_file_location();
+                eprintln!("Warning: couldn't resolve include {:?} at {:?}:{}, keeping it as it is",
+                    include.get_name().unwrap(), get_path(&include), location.line);
             }
-        };
+        }
+
+        let unparsable_files = unparsable_includes.iter().filter_map(normalize_include_path).collect::<BTreeSet<_>>();
 
         let files_to_process = {
-            let uifs = unparsable_includes.iter().map(normalize_include_path).collect::<HashSet<_>>();
+            let uifs = unparsable_includes.iter().filter_map(normalize_include_path).collect::<HashSet<_>>();
 
             sources.iter()
                 .map(|s| CanonicalPath::new(PathBuf::from(s)))
-                .chain(includes.iter().map(normalize_include_path))
-                .filter(|f| !uifs.contains(f))
-                .filter(|path| {
-                    let name = path.0.file_name().unwrap();
-                    !system_includes.contains_key(&String::from(name.to_str().unwrap()))
-                })
-                .collect::<HashSet<_>>()
+                .chain(includes.iter().filter_map(normalize_include_path))
+                .filter(|f| !uifs.contains(f) && !boundaries.contains(&f.0))
+                .filter(|path| !system_headers.contains(path))
+                .collect::<BTreeSet<_>>()
+        };
+
+        // Headers given as sources went through a wrapper and stay headers
+        let translation_units = sources.iter()
+            .filter(|s| !parse::is_header(s))
+            .map(|s| CanonicalPath::new(PathBuf::from(s)))
+            .collect::<HashSet<_>>();
+        let emits = |file: &CanonicalPath| files_to_process.contains(file) && symbols_per_file.contains_key(file);
+
+        // Headers small enough to go into the one file including them, by that file. Only
+        // leaves qualify, anything they include among the emitted files would be left behind.
+        let inlined_headers = {
+            let mut includers: HashMap<CanonicalPath, BTreeSet<(CanonicalPath, u32)>> = HashMap::new();
+            for include in &includes {
+                let including = CanonicalPath::new(get_path(include));
+                if let (Some(file), true) = (normalize_include_path(include), emits(&including)) {
+                    let line = include.get_location().unwrap().get_file_location().line;
+                    includers.entry(file).or_default().insert((including, line));
+                }
+            }
+
+            let mut ret = HashMap::new();
+            for (header, includers) in includers {
+                let small = options.inline_headers.is_some_and(|n| symbols_per_file.get(&header).is_some_and(|s| s.len() <= n));
+                let leaf = includes_per_file.get(&header).into_iter().flatten()
+                    .all(|include| normalize_include_path(&include.0).is_none_or(|f| !emits(&f)));
+                if small && leaf && includers.len() == 1 && emits(&header) && !translation_units.contains(&header) {
+                    ret.insert(header, includers.into_iter().next().unwrap().0);
+                }
+            }
+            // Not into a header that goes away itself
+            let chained = ret.iter().filter(|(_, includer)| ret.contains_key(includer)).map(|(h, _)| h.clone()).collect::<Vec<_>>();
+            for header in chained {
+                ret.remove(&header);
+            }
+            ret
         };
 
-        let source_directory = PathBuf::from("examples/").canonicalize().unwrap();
-        let target_directory = PathBuf::from("target_dir/");
+        // The output tree mirrors the original one from here on
+        let source_directory = match options.source_root {
+            Some(ref root) => canonicalize(root).unwrap_or_else(|why| panic!("Couldn't find {:?}: {}", root, why)),
+            None => {
+                let emitted = files_to_process.iter()
+                    .filter(|f| symbols_per_file.contains_key(f) && !inlined_headers.contains_key(f))
+                    .chain(unparsable_files.iter());
+                common_ancestor(emitted.map(|f| &*f.0)).unwrap_or_else(|| env::current_dir().unwrap())
+            },
+        };
+        eprintln!("Source root: {:?}", source_directory);
+        let output_path = |path: &Path| relative_to_root(path, &source_directory, &options.external_directory);
+        let target_directory = &if options.staged() {
+            archive::staging_directory()
+        } else {
+            output.clone()
+        };
 
         if !target_directory.exists() {
-            fs::create_dir(&target_directory).unwrap();
+            fs::create_dir_all(target_directory).unwrap();
         }
 
-        for file in files_to_process {
+        let mut emitted_sources = vec![];
+        // System headers the emitted files end up including, when they're bundled
+        let bundled = if options.bundle_system_headers {
+            let roots = files_to_process.iter()
+                .filter(|f| symbols_per_file.contains_key(f))
+                .flat_map(|f| includes_per_file.get(f).into_iter().flatten())
+                .filter_map(|include| normalize_include_path(&include.0))
+                .filter(|f| symbols_per_file.contains_key(f) && system_headers.contains(f))
+                .collect::<BTreeSet<_>>();
+            sysroot::closure(roots, &includes)
+        } else {
+            BTreeSet::new()
+        };
+
+        let mut emitted_files = BTreeSet::new();
+        let mut source_map = SourceMap::default();
+        let writer = emit::Writer::new(options.emit_jobs);
+
+        let mut renamer = options.prefix.as_ref().map(|prefix| Renamer::new(prefix, extracted_symbols));
+
+        if options.unity || options.header_only {
+            // The first file keeps the name, the others get renamed
+            for copies in unity::colliding_statics(extracted_symbols) {
+                let renamer = renamer.get_or_insert_with(|| Renamer::new("", iter::empty()));
+                for copy in &copies[1..] {
+                    let name = renamer.renamed(copy).unwrap_or_else(|| copy.get_name().unwrap());
+                    let new_name = unity::disambiguated(&name, &get_path(copy));
+                    eprintln!("Renaming static {} in {:?} to {} so that the files can be put together", name, get_path(copy), new_name);
+                    renamer.rename(copy, new_name);
+                }
+            }
+        }
+
+        if !options.strip_macros.is_empty() {
+            renamer.get_or_insert_with(|| Renamer::new("", iter::empty()));
+        }
+        if options.line_markers {
+            if let Some(ref mut renamer) = renamer {
+                renamer.keep_function_names();
+            }
+        }
+
+        if let Some(ref mut renamer) = renamer {
+            // Edits have to be known upfront since prototypes can move between files
+            for file in files_to_process.iter().filter(|f| symbols_per_file.contains_key(f)) {
+                renamer.collect(&tus, &file.0);
+            }
+            for include in &unparsable_files {
+                renamer.collect(&tus, &include.0);
+            }
+        }
+
+        if !bundled.is_empty() {
+            sysroot::write(target_directory, &bundled, &includes, options.includes != IncludePolicy::Keep);
+            eprintln!("Bundled {} system headers into {}/", bundled.len(), sysroot::DIRECTORY);
+        }
+
+        if let Some(ref mut renamer) = renamer {
+            // Only what ends up in the output, once per spot even if several translation units saw it
+            let emitted = |e: &Entity| {
+                let line = e.get_location().unwrap().get_file_location().line;
+                symbols_per_file.get(&CanonicalPath::new(get_path(e))).is_some_and(|symbols| symbols.iter().any(|s| {
+                    let range = symbol_range(&s.0);
+                    range.get_start().get_file_location().line <= line && line <= range.get_end().get_file_location().line
+                }))
+            };
+
+            let mut stripped = BTreeMap::new();
+            for expansion in stripped_expansions.iter().filter(|e| emitted(e)) {
+                let range = expansion.get_range().unwrap();
+                let (start, end) = (range.get_start().get_file_location(), range.get_end().get_file_location());
+                renamer.replace(&get_path(expansion), (start.line, start.column), (end.line, end.column), "((void)0)");
+                stripped.entry(expansion.get_name().unwrap()).or_insert_with(BTreeSet::new).insert(get_location(expansion));
+            }
+
+            for (name, locations) in stripped {
+                eprintln!("Stripped {} expansions of {}:", locations.len(), name);
+                for location in locations {
+                    eprintln!("  {}", location);
+                }
+            }
+        }
+
+        let internalizer = if options.internalize {
+            Internalizer::new(extracted_symbols, &sym_table, targets)
+        } else {
+            Default::default()
+        };
+
+        let unneeded_includes = match options.includes {
+            IncludePolicy::Minimal => iwyu::unused(&sym_table, &includes, Some(extracted_symbols)).into_iter().collect(),
+            _ => HashSet::new(),
+        };
+
+        for file in files_to_process.iter().cloned() {
             if !symbols_per_file.contains_key(&file) {
                 continue
             }
+            if let Some(includer) = inlined_headers.get(&file) {
+                eprintln!("Inlining {:?} into {:?}", file, includer);
+                continue
+            }
 
-            println!("Processing: {:?}", file);
+            eprintln!("Processing: {:?}", file);
 
             let mut all_output_symbols = BTreeSet::new();
 
             for include in &includes_per_file[&file] {
-                let include_file = normalize_include_path(&include.0);
+                let emitted = match normalize_include_path(&include.0) {
+                    Some(include_file) => symbols_per_file.contains_key(&include_file),
+                    // Nothing to extract from it, but the sources still expect it
+                    None => true,
+                };
 
-                if unparsable_includes.contains(&include.0) || !symbols_per_file.contains_key(&include_file) {
+                if unparsable_includes.contains(&include.0) || !emitted {
+                    continue
+                }
+                if unneeded_includes.contains(&include.0) {
+                    eprintln!("  dropped include {}", get_name(&include.0));
                     continue
                 }
 
-                println!("  include {}", get_name(&include.0));
+                eprintln!("  include {}", get_name(&include.0));
                 all_output_symbols.insert(include.clone());
             }
 
             for symbol in &symbols_per_file[&file] {
-                println!("  symbol {}", get_name(&symbol.0));
+                if internalizer.is_dropped(&symbol.0) {
+                    eprintln!("  internalized {}", get_name(&symbol.0));
+                    continue
+                }
+                eprintln!("  symbol {}", get_name(&symbol.0));
                 all_output_symbols.insert(symbol.clone());
             }
 
-            let source_file = fs::File::open(&file.0).unwrap();
-            let source_lines = io::BufReader::new(source_file).lines().collect::<Vec<_>>();
-            let mut target_file = fs::File::create(target_directory.join(file.0.strip_prefix(&source_directory).unwrap())).unwrap();
+            let relative_path = output_path(&file.0);
+            let source = SourceFile::open(&file.0);
+            let mut target_file = OutputFile::create(target_directory, relative_path.clone(), &mut source_map, &writer);
+            target_file.set_line_markers(options.line_markers);
+            emitted_files.insert(relative_path.clone());
+
+            if options.ms_extensions {
+                for (line, pragma) in msvc::comment_pragmas(&file.0) {
+                    eprintln!("  pragma {}", pragma.trim());
+                    target_file.write_symbol(&[pragma], &file.0, (line, line), None);
+                }
+            }
+
+            let regions = if options.keep_regions {
+                let symbols = all_output_symbols.iter()
+                    .filter(|s| s.0.get_kind() != EntityKind::InclusionDirective)
+                    .map(|s| {
+                        let range = symbol_range(&s.0);
+                        (s.0.get_location().unwrap().get_file_location().line, range.get_end().get_file_location().line)
+                    })
+                    .collect::<Vec<_>>();
+                regions::place(&file.0, &symbols)
+            } else {
+                regions::Regions::default()
+            };
+
+            let mut moved_prototypes = internalizer.moved_to(&file);
+            let mut previous_end = None;
+            // Functions defined so far in this file, with --drop-redundant-prototypes
+            let mut defined_functions = HashSet::new();
 
             for sym in all_output_symbols {
-                let range = sym.0.get_range().unwrap();
-                let start_line = range.get_start().get_file_location().line;
-                let end_line = range.get_end().get_file_location().line;
-
-                for line in start_line - 1 .. end_line {
-                    match source_lines[line as usize] {
-                        Ok(ref line) => {
-                            target_file.write_all(line.as_bytes()).unwrap();
-                            target_file.write("\n".as_bytes()).unwrap();
+                if sym.0.get_kind() != EntityKind::InclusionDirective {
+                    // Internalized prototypes go right after the includes
+                    for prototype in moved_prototypes.drain(..) {
+                        let prototype_path = get_path(&prototype);
+                        let (mut lines, original_lines) = symbol_text(&prototype, &SourceFile::open(&prototype_path), renamer.as_ref());
+                        internalizer.apply(&prototype, &mut lines);
+                        target_file.write_symbol(&lines, &prototype_path, original_lines, prototype.get_name());
+                    }
+                }
+                if is_redundant_prototype(&sym.0, &defined_functions) {
+                    eprintln!("  redundant prototype {}", get_name(&sym.0));
+                    continue
+                }
+
+                let line = sym.0.get_location().unwrap().get_file_location().line;
+                for (marker_line, marker) in regions.before.get(&line).into_iter().flatten() {
+                    target_file.write_symbol(slice::from_ref(marker), &file.0, (*marker_line, *marker_line), None);
+                }
+
+                let (mut lines, original_lines) = symbol_text(&sym.0, &source, renamer.as_ref());
+                internalizer.apply(&sym.0, &mut lines);
+                if !cuts.is_empty() {
+                    gaps::annotate(&sym.0, &mut lines, original_lines.0, &cuts);
+                }
+
+                let inlined = match sym.0.get_kind() {
+                    EntityKind::InclusionDirective => normalize_include_path(&sym.0).filter(|f| inlined_headers.contains_key(f)),
+                    _ => None,
+                };
+                if let Some(header) = inlined {
+                    // What the header includes and its symbols take the place of the include
+                    eprintln!("  inlined {}", get_name(&sym.0));
+                    let header_source = SourceFile::open(&header.0);
+                    let header_includes = includes_per_file.get(&header).into_iter().flatten()
+                        .filter(|include| !unparsable_includes.contains(&include.0))
+                        .filter(|include| normalize_include_path(&include.0).is_none_or(|f| symbols_per_file.contains_key(&f)));
+                    for header_sym in header_includes.chain(&symbols_per_file[&header]) {
+                        if internalizer.is_dropped(&header_sym.0) || is_redundant_prototype(&header_sym.0, &defined_functions) {
+                            continue
+                        }
+                        let (mut lines, original_lines) = symbol_text(&header_sym.0, &header_source, renamer.as_ref());
+                        internalizer.apply(&header_sym.0, &mut lines);
+                        target_file.write_symbol(&lines, &header.0, original_lines, header_sym.0.get_name());
+                        if options.drop_redundant_prototypes && header_sym.0.is_definition() {
+                            defined_functions.extend(function_usr(&header_sym.0));
+                        }
+                    }
+                    previous_end = Some(original_lines.1);
+                    continue
+                }
+
+                let included = match sym.0.get_kind() {
+                    EntityKind::InclusionDirective if options.includes != IncludePolicy::Keep => normalize_include_path(&sym.0),
+                    _ => None,
+                };
+                if let Some(included) = included {
+                    let bundled_header = bundled.contains(&included);
+                    let included = if bundled_header { sysroot::location(&included.0) } else { output_path(&included.0) };
+
+                    // Files from outside the source root moved, so includes from or to them have to follow
+                    if bundled_header || relative_path.starts_with(&options.external_directory) || included.starts_with(&options.external_directory) {
+                        lines = vec![format!("#{} \"{}\"", directive(&sym.0), include_spelling(&relative_path, &included))];
+                    }
+                }
+
+                if let Some(previous_end) = previous_end {
+                    match options.spacing {
+                        Spacing::Compact => (),
+                        Spacing::Preserve => {
+                            // Keep the run of blank lines right above the symbol, if it wasn't emitted already
+                            let blank = (previous_end + 1 .. original_lines.0).rev()
+                                .take_while(|&line| source.line(line).trim().is_empty())
+                                .count();
+                            target_file.write_synthetic(&vec![String::new(); blank]);
                         },
-                        Err(ref why) => panic!("Couldn't read line: {}", why),
-                    };
+                        Spacing::Separator(ref separator) => target_file.write_synthetic(slice::from_ref(separator)),
+                    }
+                }
+
+                target_file.write_symbol(&lines, &file.0, original_lines, sym.0.get_name());
+                previous_end = Some(original_lines.1);
+                for (marker_line, marker) in regions.after.get(&line).into_iter().flatten() {
+                    target_file.write_symbol(slice::from_ref(marker), &file.0, (*marker_line, *marker_line), None);
+                }
+                if options.drop_redundant_prototypes && sym.0.is_definition() {
+                    defined_functions.extend(function_usr(&sym.0));
                 }
             }
+
+            if translation_units.contains(&file) {
+                emitted_sources.push(relative_path);
+            }
+        }
+
+        for include in &unparsable_files {
+            let source_path = include.0.to_path_buf();
+            let relative_path = output_path(&source_path);
+            let lines = read_lines(&source_path);
+            emitted_files.insert(relative_path.clone());
+            let line_count = lines.len() as u32;
+
+            match renamer {
+                Some(ref renamer) if renamer.has_edits(&source_path) => {
+                    let lines = lines.iter().enumerate()
+                        .map(|(n, line)| renamer.apply(&source_path, n as u32 + 1, line))
+                        .collect::<Vec<_>>();
+                    OutputFile::create(target_directory, relative_path, &mut source_map, &writer)
+                        .write_symbol(&lines, &source_path, (1, line_count), None);
+                },
+                _ => {
+                    writer.write(target_directory.join(&relative_path), fs::read(&source_path).unwrap());
+
+                    // Copied verbatim, so the whole file maps onto itself
+                    source_map.add(Mapping {
+                        output: relative_path,
+                        output_lines: (1, line_count),
+                        original: source_path,
+                        original_lines: (1, line_count),
+                        symbol: None,
+                    });
+                },
+            }
+        }
+
+        // Everything after this reads the emitted files
+        drop(writer);
+
+        // Assembly is built and linked like the sources, but isn't C to check or merge
+        let mut emitted_asm = vec![];
+        for source in asm_sources.keys() {
+            let relative_path = output_path(&canonicalize(source).unwrap());
+            let path = target_directory.join(&relative_path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            emit::write_if_changed(&path, fs::read(source).unwrap());
+            emitted_asm.push(relative_path);
         }
 
-        for include in unparsable_includes {
-            let source_path = normalize_include_path(&include).0;
-            let target_path = target_directory.join(source_path.strip_prefix(&source_directory).unwrap());
-            fs::copy(source_path, target_path).unwrap();
+        if !closure.excluded.is_empty() {
+            let headers = extracted_symbols.iter()
+                .filter(|e| e.get_kind() == EntityKind::FunctionDecl && closure.excluded.contains(e))
+                .map(|e| CanonicalPath::new(get_path(e)))
+                .filter(|path| path.0.extension().is_some_and(|ext| ext == "h"))
+                .map(|path| output_path(&path.0))
+                .collect::<BTreeSet<_>>();
+
+            stubs::write(target_directory, &closure.excluded, &headers, renamer.as_ref());
+            emitted_sources.push(PathBuf::from(stubs::FILE_NAME));
         }
+
+        // Generated sources need whatever the emitted sources include to spell their prototypes
+        let include_lines = translation_units.iter()
+            .filter(|tu| symbols_per_file.contains_key(tu))
+            .flat_map(|tu| includes_per_file.get(tu).into_iter().flatten())
+            .filter(|include| {
+                normalize_include_path(&include.0).is_none_or(|f| system_headers.contains(&f) || symbols_per_file.contains_key(&f))
+            })
+            .map(|include| {
+                let line = include.0.get_location().unwrap().get_file_location().line;
+                SourceFile::open(&get_path(&include.0)).line(line)
+            })
+            .collect::<BTreeSet<_>>();
+
+        if options.mocks {
+            mocks::write(target_directory, extracted_symbols, &boundaries, &include_lines, renamer.as_ref());
+            emitted_sources.push(PathBuf::from(mocks::FILE_NAME));
+        }
+
+        if let Some(ref name) = options.fuzz {
+            // Its own main, so it isn't one of the emitted sources
+            let function = sorted(extracted_symbols).into_iter()
+                .filter(|e| e.get_kind() == EntityKind::FunctionDecl && e.get_name().as_ref() == Some(name))
+                .max_by_key(|e| e.is_definition());
+            match function {
+                Some(function) if function.get_linkage() == Some(Linkage::External) =>
+                    fuzz::write(target_directory, &function, &include_lines, renamer.as_ref()),
+                Some(_) => eprintln!("Can't fuzz static function {} from {}", name, fuzz::FILE_NAME),
+                None => eprintln!("No function {} to fuzz", name),
+            }
+        }
+
+        // Which emitted file needs which other one, for putting them all in one file
+        let mut file_deps = BTreeMap::new();
+        if options.unity || options.header_only {
+            let relative = |e: &Entity| output_path(&CanonicalPath::new(get_path(e)).0);
+
+            for sym in extracted_symbols.iter().filter(|s| sym_table.contains_key(s) && !in_system_header(s)) {
+                let file = relative(sym);
+                let desc = &sym_table[sym];
+                for dep in desc.deps.keys().chain(desc.definitions.iter()).filter(|d| !in_system_header(d)).map(&relative) {
+                    if dep != file {
+                        file_deps.entry(file.clone()).or_insert_with(BTreeSet::new).insert(dep);
+                    }
+                }
+            }
+        }
+
+        if options.unity {
+            // Mocks are weak duplicates of other definitions and can't share a translation unit
+            let files = emitted_sources.iter()
+                .filter(|f| f.as_path() != Path::new(mocks::FILE_NAME))
+                .cloned()
+                .collect::<BTreeSet<_>>();
+
+            unity::write(target_directory, &files, &file_deps);
+        }
+
+        if options.header_only {
+            let originals = files_to_process.iter()
+                .filter(|f| symbols_per_file.contains_key(f))
+                .map(|f| (output_path(&f.0), f))
+                .collect::<BTreeMap<_, _>>();
+            let files = originals.keys().cloned().collect::<BTreeSet<_>>();
+
+            // Includes of anything that isn't part of the header stay as they are
+            let includes = files_to_process.iter()
+                .filter(|f| symbols_per_file.contains_key(f))
+                .flat_map(|f| includes_per_file.get(f).into_iter().flatten())
+                .filter(|include| normalize_include_path(&include.0).is_none_or(|f| !symbols_per_file.contains_key(&f)))
+                .map(|include| {
+                    let line = include.0.get_location().unwrap().get_file_location().line;
+                    SourceFile::open(&get_path(&include.0)).line(line)
+                })
+                .collect::<BTreeSet<_>>();
+
+            let files = unity::order(&files, &file_deps).into_iter()
+                .map(|file| {
+                    let original = originals[&file];
+                    let symbols = symbols_per_file[original].iter().map(|s| s.0).collect::<Vec<_>>();
+                    (original.0.to_path_buf(), symbols)
+                })
+                .collect::<Vec<_>>();
+
+            header_only::write(target_directory, &files, &includes, renamer.as_ref(), options.drop_redundant_prototypes);
+        }
+
+        let mut manifest = manifest::Manifest::default();
+        for target in targets {
+            let closure = extract_symbols(vec![target.clone()], &options.excludes, options.max_depth, &boundaries, &sym_table);
+            for sym in closure.symbols.iter().filter(|s| !in_system_header(s)) {
+                let path = CanonicalPath::new(get_path(sym));
+                if !boundaries.contains(&path.0) {
+                    manifest.add(sym, output_path(&path.0), target);
+                }
+            }
+        }
+        manifest.cuts = cuts.into_values().map(|cut| (cut.name, cut.reason)).collect();
+        manifest.provenance = git::provenance(&source_directory);
+        if let Some(ref provenance) = manifest.provenance {
+            if provenance.dirty {
+                eprintln!("warning: {} has uncommitted changes, the manifest only records its HEAD", provenance.commit);
+            }
+        }
+        manifest.write(target_directory);
+
+        let built_sources = emitted_sources.iter().chain(&emitted_asm).cloned().collect::<Vec<_>>();
+
+        if options.c2rust {
+            // Mocks are weak duplicates of other definitions, which Rust has no notion of
+            let sources = emitted_sources.iter()
+                .filter(|f| f.as_path() != Path::new(mocks::FILE_NAME))
+                .cloned()
+                .collect::<Vec<_>>();
+            compile_db::write(target_directory, &sources, &options.clang_args, true);
+        } else {
+            compile_db::write(target_directory, &built_sources, &options.clang_args, false);
+        }
+
+        if options.run {
+            makefile::write(target_directory, &built_sources, &options.clang_args, &options.link_flags());
+        }
+
+        if let Some(ref test) = options.reduce {
+            reduce::reduce(target_directory, test, &mut source_map, &sym_table, extracted_symbols);
+        }
+        source_map.write(target_directory);
+
+        if options.verify {
+            verified &= verify::verify(target_directory, &emitted_sources, &options.clang_args, &source_map);
+        }
+
+        if options.check_link {
+            // Whatever comes from system or boundary headers is linked from outside
+            let allowed = extracted_symbols.iter()
+                .filter(|e| in_system_header(e) || boundaries.contains(&get_path(e)))
+                .filter_map(|e| e.get_name())
+                .collect::<HashSet<_>>();
+            verified &= verify::check_link(target_directory, &built_sources, &options.clang_args, &allowed);
+        }
+
+        if options.run {
+            // The libraries go after the sources needing them
+            let original_args = options.clang_args.iter().cloned().chain(options.link_flags()).collect::<Vec<_>>();
+            let original_sources = sources.iter().cloned()
+                .chain(asm_sources.keys().map(|source| String::from(source.to_str().unwrap())))
+                .collect::<Vec<_>>();
+            let original = if options.run_compare { Some((&original_sources[..], &original_args[..])) } else { None };
+            verified &= run::run(target_directory, original);
+        }
+
+        if options.bindgen {
+            let is_header = |path: &Path| path.extension().is_some_and(|ext| ext == "h");
+            let headers = emitted_files.iter().filter(|f| is_header(f)).cloned().collect::<BTreeSet<_>>();
+
+            // Targets that are only declared in sources need a prototype of their own
+            let declared = extracted_symbols.iter()
+                .filter(|e| is_header(&get_path(e)))
+                .filter_map(|e| e.get_usr())
+                .collect::<HashSet<_>>();
+            let functions = sorted(extracted_symbols).into_iter()
+                .filter(|e| e.get_kind() == EntityKind::FunctionDecl && e.get_linkage() == Some(Linkage::External))
+                .filter(|e| e.get_name().is_some_and(|name| name != "main") && is_target(e, targets))
+                .filter(|e| e.get_usr().is_none_or(|usr| !declared.contains(&usr)))
+                .collect::<Vec<_>>();
+
+            bindgen::write_header(target_directory, &headers, &functions, renamer.as_ref());
+            bindgen::run(target_directory, &options.clang_args);
+        }
+
+        if options.c2rust_transpile {
+            c2rust::transpile(target_directory);
+        }
+
+        if options.incremental && !options.staged() {
+            let outputs = emitted_files.iter().chain(&built_sources).cloned().collect();
+            let state = incremental::State::new(&tus, sources, outputs);
+            if let Some(previous) = incremental::State::load(target_directory) {
+                state.remove_stale(&previous, target_directory);
+            }
+            state.write(target_directory);
+        }
+
+        let considered = files_to_process.iter()
+            .chain(unparsable_files.iter())
+            .filter_map(|f| f.0.strip_prefix(&source_directory).ok().map(Path::to_path_buf))
+            .collect::<BTreeSet<_>>();
+        // Where each of them went, which for files from elsewhere is under the external directory
+        let trimmed = considered.iter()
+            .map(|file| (file.clone(), output_path(&source_directory.join(file))))
+            .filter(|(_, output)| emitted_files.contains(output))
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(ref patch) = options.emit_patch {
+            trim::write_patch(target_directory, &source_directory, &trimmed, &considered, patch);
+        }
+
+        if options.in_place {
+            trim::apply(target_directory, &source_directory, &trimmed, &considered, options.force);
+        } else if options.emit_patch.is_some() {
+            fs::remove_dir_all(target_directory).unwrap();
+        } else if let Some(ref path) = options.out_archive {
+            archive::write(target_directory, path);
+        } else if options.emit_stdout {
+            archive::stream(target_directory);
+        }
+
+        if options.command == Command::Vendor {
+            vendor::Spec::current(&source_directory).write(target_directory);
+        }
+
+        if let Command::Diff(ref baseline) = options.command {
+            diff::report(target_directory, baseline);
+            fs::remove_dir_all(target_directory).unwrap();
+        }
+
+        timings.lap("emission");
+
+        let mut stats = stats::Stats::new(&sym_table, extracted_symbols, &source_map, options.stats_top);
+        stats.parse_errors = parse_errors.clone();
+        stats.print();
+        if let Some(ref path) = options.stats {
+            stats.write_json(path);
+        }
+    }
+
+    if let (true, Some(store)) = (options.replayable(), &cache) {
+        cache::store_extraction(store, &options, &tus, sources);
+        timings.lap("cache");
+    }
+
+    if options.timings {
+        timings.print();
+    }
+
+    if !verified {
+        process::exit(1);
     }
 }
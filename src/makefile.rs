@@ -0,0 +1,29 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use compile_db::absolute_args;
+use emit;
+
+pub const FILE_NAME: &str = "Makefile";
+pub const PROGRAM: &str = "program";
+
+// Writes a Makefile building the emitted sources into `program` with the
+// flags of the extraction, linked with `link_flags`.
+pub fn write(target_directory: &Path, emitted_sources: &[PathBuf], clang_args: &[String], link_flags: &[String]) {
+    let sources = emitted_sources.iter().map(|s| s.to_str().unwrap()).collect::<Vec<_>>().join(" ");
+
+    let mut contents = String::from("# Generated by ccthief.\n\n");
+    contents.push_str("CC ?= cc\n");
+    contents.push_str(&format!("CFLAGS += -I. {}\n", absolute_args(clang_args, &env::current_dir().unwrap()).join(" ")));
+    if !link_flags.is_empty() {
+        contents.push_str(&format!("LDLIBS += {}\n", link_flags.join(" ")));
+    }
+    contents.push_str(&format!("SOURCES = {}\n", sources));
+    // Assembly sources build with make's own .S and .s rules
+    contents.push_str("OBJECTS = $(addsuffix .o,$(basename $(SOURCES)))\n\n");
+    contents.push_str(&format!("{}: $(OBJECTS)\n\t$(CC) $(LDFLAGS) -o $@ $(OBJECTS) $(LDLIBS)\n\n", PROGRAM));
+    contents.push_str(&format!("clean:\n\trm -f {} $(OBJECTS)\n\n", PROGRAM));
+    contents.push_str(".PHONY: clean\n");
+
+    emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+}
@@ -0,0 +1,85 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use clang::*;
+
+use emit;
+use git::Provenance;
+use graph::node_key;
+use json;
+use {get_location, symbol_range};
+
+pub const FILE_NAME: &str = "ccthief-manifest.json";
+
+pub struct Entry {
+    pub name: String,
+    pub kind: String,
+    pub usr: Option<String>,
+    // Relative to the source root so the manifest survives moving the checkout
+    pub file: PathBuf,
+    pub lines: (u32, u32),
+    pub targets: BTreeSet<String>,
+}
+
+// Every emitted symbol together with the targets whose closure needed it.
+#[derive(Default)]
+pub struct Manifest {
+    // Declarations of one symbol stay apart, each one was emitted
+    pub entries: BTreeMap<(String, String), Entry>,
+    // Dependencies left out on purpose with --mark-cuts, and why
+    pub cuts: BTreeMap<String, &'static str>,
+    pub provenance: Option<Provenance>,
+}
+
+impl Manifest {
+    pub fn add(&mut self, entity: &Entity, file: PathBuf, target: &str) {
+        let entry = self.entries.entry((node_key(entity), get_location(entity))).or_insert_with(|| {
+            let range = symbol_range(entity);
+            Entry {
+                name: entity.get_name().unwrap_or_default(),
+                kind: format!("{:?}", entity.get_kind()),
+                usr: entity.get_usr().map(|usr| usr.0),
+                file,
+                lines: (range.get_start().get_file_location().line, range.get_end().get_file_location().line),
+                targets: BTreeSet::new(),
+            }
+        });
+        entry.targets.insert(String::from(target));
+    }
+
+    pub fn write(&self, target_directory: &Path) {
+        let mut entries = self.entries.values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| (&a.file, a.lines, &a.name).cmp(&(&b.file, b.lines, &b.name)));
+
+        let symbols = entries.iter().map(|e| {
+            json::object(&[
+                ("name", json::string(&e.name)),
+                ("kind", json::string(&e.kind)),
+                ("usr", json::optional(e.usr.as_ref().map(|usr| json::string(usr)))),
+                ("file", json::string(e.file.to_str().unwrap())),
+                ("lines", json::array(vec![e.lines.0.to_string(), e.lines.1.to_string()])),
+                ("targets", json::array(e.targets.iter().map(|t| json::string(t)))),
+            ])
+        });
+
+        let source = self.provenance.as_ref().map(|p| json::object(&[
+            ("remote", json::optional(p.remote.as_ref().map(|remote| json::string(remote)))),
+            ("commit", json::string(&p.commit)),
+            ("dirty", p.dirty.to_string()),
+        ]));
+
+        let mut fields = vec![
+            ("source", json::optional(source)),
+            ("symbols", json::array(symbols)),
+        ];
+        if !self.cuts.is_empty() {
+            let cuts = self.cuts.iter().map(|(name, reason)| json::object(&[
+                ("name", json::string(name)),
+                ("reason", json::string(reason)),
+            ]));
+            fields.push(("cuts", json::array(cuts)));
+        }
+        let contents = json::object(&fields);
+        emit::write_if_changed(&target_directory.join(FILE_NAME), contents + "\n");
+    }
+}
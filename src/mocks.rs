@@ -0,0 +1,92 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use clang::*;
+
+use boundary::Boundaries;
+use emit;
+use rename::Renamer;
+use stubs::{declarator, prototype};
+use {get_path, in_system_header};
+
+pub const FILE_NAME: &str = "mocks.c";
+pub const HEADER_NAME: &str = "mocks.h";
+
+// Every function the closure calls but does not carry a definition for.
+// Those of libc and the boundary headers are linked for real, mocking them
+// would take their place.
+fn external_functions<'a>(extracted: &HashSet<Entity<'a>>, boundaries: &Boundaries) -> Vec<Entity<'a>> {
+    let mut functions = BTreeMap::new();
+
+    for entity in extracted {
+        if entity.get_kind() != EntityKind::FunctionDecl || entity.get_linkage() != Some(Linkage::External) {
+            continue
+        }
+        if in_system_header(entity) || boundaries.contains(&get_path(entity)) {
+            continue
+        }
+        let usr = entity.get_usr().unwrap();
+        let defined = entity.is_definition() || functions.get(&usr).is_some_and(|&(_, defined)| defined);
+        functions.insert(usr, (*entity, defined));
+    }
+
+    functions.into_iter()
+        .filter(|&(_, (_, defined))| !defined)
+        .map(|(_, (entity, _))| entity)
+        .collect()
+}
+
+// Writes weak mock implementations of the closure's external API together
+// with a header exposing the calls they recorded, counted in `calls_<name>`.
+pub fn write(
+    target_directory: &Path,
+    extracted: &HashSet<Entity>,
+    boundaries: &Boundaries,
+    includes: &BTreeSet<String>,
+    renamer: Option<&Renamer>
+) {
+    let functions = external_functions(extracted, boundaries).into_iter()
+        .map(|f| (renamer.and_then(|r| r.renamed(&f)).unwrap_or_else(|| f.get_name().unwrap()), f))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut header = String::from("/* Calls recorded by the mocks in mocks.c. */\n\n#pragma once\n\n");
+    header.push_str("#define CCTHIEF_MOCK_LOG_SIZE 256\n\n");
+    header.push_str("struct ccthief_mock_calls {\n");
+    for name in functions.keys() {
+        header.push_str(&format!("    unsigned calls_{};\n", name));
+    }
+    header.push_str("    unsigned log_length;\n");
+    header.push_str("    const char *log[CCTHIEF_MOCK_LOG_SIZE];\n");
+    header.push_str("};\n\nextern struct ccthief_mock_calls ccthief_mock_calls;\n");
+
+    let mut source = String::from("/* Weak mocks for functions the extraction calls but does not include. */\n\n");
+    for include in includes {
+        source.push_str(&format!("{}\n", include));
+    }
+    source.push_str(&format!("#include \"{}\"\n\n", HEADER_NAME));
+    source.push_str("struct ccthief_mock_calls ccthief_mock_calls;\n\n");
+    source.push_str("static void ccthief_mock_record(const char *name) {\n");
+    source.push_str("    if (ccthief_mock_calls.log_length < CCTHIEF_MOCK_LOG_SIZE)\n");
+    source.push_str("        ccthief_mock_calls.log[ccthief_mock_calls.log_length++] = name;\n");
+    source.push_str("}\n");
+
+    for (name, function) in functions {
+        let (prototype, params) = prototype(&function, &name);
+        let result = function.get_result_type().unwrap();
+
+        source.push_str(&format!("\n__attribute__((weak)) {} {{\n", prototype));
+        for param in params {
+            source.push_str(&format!("    (void){};\n", param));
+        }
+        source.push_str(&format!("    ccthief_mock_calls.calls_{}++;\n", name));
+        source.push_str(&format!("    ccthief_mock_record(\"{}\");\n", name));
+        if result.get_kind() != TypeKind::Void {
+            source.push_str(&format!("    {} = {{0}};\n", declarator(&result.get_display_name(), "ret")));
+            source.push_str("    return ret;\n");
+        }
+        source.push_str("}\n");
+    }
+
+    emit::write_if_changed(&target_directory.join(HEADER_NAME), header);
+    emit::write_if_changed(&target_directory.join(FILE_NAME), source);
+}
@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use source_file;
+
+// SAL annotations, which sal.h defines away for any compiler but MSVC. Without
+// the Windows SDK around they have to be defined away here.
+const SAL_ANNOTATIONS: &[&str] = &[
+    "_In_", "_In_opt_", "_In_z_", "_In_opt_z_", "_In_reads_(size)", "_In_reads_opt_(size)",
+    "_In_reads_bytes_(size)", "_In_reads_bytes_opt_(size)", "_In_range_(low,high)",
+    "_Out_", "_Out_opt_", "_Out_writes_(size)", "_Out_writes_opt_(size)", "_Out_writes_bytes_(size)",
+    "_Out_writes_bytes_opt_(size)", "_Out_writes_z_(size)", "_Out_writes_to_(size,count)",
+    "_Inout_", "_Inout_opt_", "_Inout_z_", "_Inout_updates_(size)", "_Inout_updates_bytes_(size)",
+    "_Outptr_", "_Outptr_opt_", "_Outptr_result_maybenull_", "_Outptr_result_buffer_(size)",
+    "_Ret_maybenull_", "_Ret_notnull_", "_Ret_z_", "_Check_return_", "_Must_inspect_result_",
+    "_Success_(expression)", "_Use_decl_annotations_", "_Printf_format_string_", "_Null_terminated_",
+    "_Field_size_(size)", "_Field_size_bytes_(size)", "_Field_z_", "_Frees_ptr_", "_Frees_ptr_opt_",
+    "_Post_invalid_", "_Post_z_", "_Pre_valid_", "_Deref_out_", "_Reserved_", "_Analysis_assume_(expression)",
+    "_When_(condition,annotation)", "_Acquires_lock_(lock)", "_Releases_lock_(lock)",
+    "_Requires_lock_held_(lock)", "_Guarded_by_(lock)", "_IRQL_requires_max_(level)",
+];
+
+// The flags clang takes code written for MSVC with: __declspec, MS-style
+// inline assembly, #pragma comment and the rest of the extensions.
+pub fn clang_args() -> Vec<String> {
+    let mut args = vec![
+        String::from("-fms-extensions"),
+        String::from("-fms-compatibility"),
+        String::from("-fdeclspec"),
+        String::from("-fms-compatibility-version=19.29"),
+        String::from("-Wno-microsoft"),
+    ];
+    if !cfg!(windows) {
+        args.extend(SAL_ANNOTATIONS.iter().map(|annotation| format!("-D{}=", annotation)));
+    }
+    args
+}
+
+// The `#pragma comment(...)` lines of `file`, with their line numbers. They
+// aren't part of any symbol, yet they tell the linker which libraries the
+// code needs, so they go along with the file.
+pub fn comment_pragmas(file: &Path) -> Vec<(u32, String)> {
+    source_file::read_lines(file).into_iter().enumerate()
+        .filter(|(_, line)| {
+            let mut words = line.trim_start().strip_prefix('#').unwrap_or("").split_whitespace();
+            words.next() == Some("pragma") && words.next().is_some_and(|word| word.starts_with("comment"))
+        })
+        .map(|(n, line)| (n as u32 + 1, line))
+        .collect()
+}
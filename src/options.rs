@@ -0,0 +1,674 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use clang::diagnostic::Severity;
+
+use bazel;
+use cmake;
+use graph::EdgeKind;
+use kernel;
+use msvc;
+use path_map::PathMap;
+use pkg_config;
+use symbol_list;
+use target_name;
+
+#[derive(PartialEq)]
+pub enum Command {
+    Extract,
+    // Compare a fresh extraction against a previous output directory
+    Diff(PathBuf),
+    // List everything that transitively depends on a symbol
+    Rdeps(String),
+    // Answer closure queries of an editor on stdin
+    Server,
+    // Answer JSON-RPC closure and emit requests on a unix socket
+    Serve(PathBuf),
+    // Extract and record how, so that the output can be updated later on
+    Vendor,
+    // Redo a vendoring extraction, optionally from another checkout
+    Update(Option<PathBuf>),
+}
+
+// Which way the closure is taken from the targets.
+#[derive(PartialEq)]
+pub enum Direction {
+    // What the targets need
+    Dependencies,
+    // What needs the targets, up to the entry points, and what that needs in turn
+    Callers,
+}
+
+// How files reached through symlinks are told apart.
+#[derive(PartialEq, Clone, Copy)]
+pub enum Symlinks {
+    // By where they lead, so that every way to the same file is the same file
+    Follow,
+    // By how they're spelled, so that the output keeps the layout the build sees
+    Logical,
+}
+
+// What goes between two symbols that follow each other in an emitted file.
+#[derive(PartialEq)]
+pub enum Spacing {
+    Compact,
+    // Blank lines that preceded the symbol in the original file
+    Preserve,
+    Separator(String),
+}
+
+// How the emitted files spell their include directives.
+#[derive(PartialEq)]
+pub enum IncludePolicy {
+    // Byte for byte as in the original, even where the layout moved the file
+    Keep,
+    // Respelled wherever the included or the including file moved
+    Rewrite,
+    // Respelled, and the includes none of the extracted symbols needs dropped
+    Minimal,
+}
+
+pub struct Options {
+    pub command: Command,
+    pub sources: Vec<String>,
+    pub targets: Vec<String>,
+    // Targets taken from --targets-from and --link-object lists, which may well be defined elsewhere
+    pub listed_targets: HashSet<String>,
+    // Whether targets that aren't there are only a warning
+    pub allow_missing_targets: bool,
+    // Whether dependencies without a definition or an allowed library to come from are an error
+    pub strict: bool,
+    // Whether sources that fail to parse are reported instead of stopping everything
+    pub keep_going: bool,
+    // The least severe clang diagnostics to show, if any
+    pub diagnostics: Option<Severity>,
+    // Files and lines within the symbols to take as targets
+    pub target_locations: Vec<(PathBuf, u32)>,
+    // Headers whose declarations are all targets
+    pub api_headers: Vec<PathBuf>,
+    // Subdirectories of the output with the targets extracted into each
+    pub groups: Vec<(String, Vec<String>)>,
+    pub clang_args: Vec<String>,
+    pub path_map: PathMap,
+    pub jobs: usize,
+    pub emit_jobs: usize,
+    pub pch: Option<PathBuf>,
+    pub lazy: bool,
+    pub prepass: bool,
+    pub output: PathBuf,
+    pub source_root: Option<PathBuf>,
+    pub external_directory: PathBuf,
+    pub symlinks: Symlinks,
+    pub case_insensitive_includes: bool,
+    pub bundle_system_headers: bool,
+    pub out_archive: Option<PathBuf>,
+    pub emit_stdout: bool,
+    pub in_place: bool,
+    // Let --in-place delete the files the closure doesn't need
+    pub force: bool,
+    pub emit_patch: Option<PathBuf>,
+    pub spacing: Spacing,
+    pub includes: IncludePolicy,
+    // Headers contributing at most this many symbols go into the file including them
+    pub inline_headers: Option<usize>,
+    pub drop_redundant_prototypes: bool,
+    pub keep_regions: bool,
+    pub line_markers: bool,
+    pub mark_cuts: bool,
+    pub prefix: Option<String>,
+    pub internalize: bool,
+    pub excludes: HashSet<String>,
+    pub strip_macros: HashSet<String>,
+    pub max_depth: Option<usize>,
+    pub direction: Direction,
+    pub roots_from_ld: Option<PathBuf>,
+    pub tracked_only: bool,
+    pub boundaries: Vec<String>,
+    // Globs overriding whether clang takes a header for a system one
+    pub treat_as_system: Vec<String>,
+    pub treat_as_project: Vec<String>,
+    pub kernel: bool,
+    pub ms_extensions: bool,
+    // Include directories of the --pkg-config packages
+    pub external_roots: Vec<PathBuf>,
+    // Libraries behind the --external-lib headers, to link with -l
+    pub libraries: Vec<String>,
+    // Assembly sources that may define what the extracted code calls
+    pub asm_sources: Vec<PathBuf>,
+    pub mocks: bool,
+    pub fuzz: Option<String>,
+    pub unity: bool,
+    pub header_only: bool,
+    pub bindgen: bool,
+    pub c2rust: bool,
+    pub c2rust_transpile: bool,
+    // Interestingness test to reduce the extracted tree against
+    pub reduce: Option<PathBuf>,
+    pub verify: bool,
+    pub check_link: bool,
+    pub run: bool,
+    pub run_compare: bool,
+    pub emit_graph: Vec<PathBuf>,
+    pub graph_edges: BTreeSet<EdgeKind>,
+    pub stats: Option<PathBuf>,
+    pub stats_top: usize,
+    pub explain: Vec<String>,
+    pub dead_symbols: Option<PathBuf>,
+    pub unused_includes: Option<PathBuf>,
+    pub emit_callgraph: Option<PathBuf>,
+    pub emit_db: Option<PathBuf>,
+    // Symbol name -> file whose definition wins when several translation units define it
+    pub prefer_definitions: HashMap<String, String>,
+    pub cache: Option<String>,
+    pub incremental: bool,
+    pub timings: bool,
+}
+
+const USAGE: &str = "\
+usage: ccthief [OPTIONS] [SOURCE]... [-- CLANG_ARGS...]
+       ccthief diff --baseline DIR [OPTIONS] [SOURCE]... [-- CLANG_ARGS...]
+       ccthief rdeps --symbol NAME [SOURCE]... [-- CLANG_ARGS...]
+       ccthief server [OPTIONS] [SOURCE]... [-- CLANG_ARGS...]
+       ccthief serve --socket PATH [OPTIONS] [SOURCE]... [-- CLANG_ARGS...]
+       ccthief vendor [OPTIONS] [SOURCE]... [-- CLANG_ARGS...]
+       ccthief update [-o DIR] [--upstream DIR]
+
+A SOURCE can be a header too, it's then parsed as if a source file included it.
+
+options:
+    -t, --target NAME         extract the closure of NAME (repeatable, default: main)
+    --targets-from FILE       extract the closure of the symbols listed in FILE: `nm` output, e.g. `nm -u` of an
+                              object file, a GNU ld map or one name per line (repeatable)
+    --link-object FILE        extract what the object file or archive FILE needs to link (repeatable); its symbols
+                              are read by running the `nm` on PATH, which has to understand FILE's format
+    --diagnostics none|note|warning|error|fatal
+                              show the clang diagnostics from that severity up (default: error)
+    --keep-going              carry on past sources that fail to parse, extracting what could be parsed and
+                              listing them and their errors in the report
+    --strict                  fail, listing them, on dependencies with neither a definition to extract nor a
+                              system, --boundary or --external-lib header to come from
+    --allow-missing-targets   only warn about targets no source declares instead of failing
+    --target-usr USR          extract the closure of the symbol with the clang USR, e.g. c:util.c@F@helper (repeatable)
+    --target-at FILE:LINE     extract the closure of the top-level symbol around LINE of FILE (repeatable)
+    --api-header HEADER       extract what it takes to provide every function and type declared in HEADER (repeatable)
+    --group DIR=NAME,...      extract the closure of the NAMEs into the subdirectory DIR of the output (repeatable)
+    --split-targets           extract every target into a subdirectory of the output named after it
+    -o, --output DIR          write the extracted tree to DIR (default: target_dir/)
+    --src-root DIR            lay the output out relative to DIR (default: the deepest directory holding every
+                              extracted file)
+    --follow-symlinks         identify files by where their symlinks lead (default)
+    --logical-paths           identify files by their paths as spelled, keeping symlinks in the layout
+    --case-insensitive-includes
+                              match include names regardless of case, as macOS and Windows do
+    --bundle-system-headers   copy the needed system headers under sysroot/ and include them from there
+    --external-dir DIR        where files from outside the source root go in the output (default: _external)
+    -j, --jobs N              parse N sources at once (default: number of CPUs)
+    --emit-jobs N             write N emitted files at once (default: number of CPUs)
+    --lazy                    only parse the sources that, going by their text, can add to the closure
+    --prepass                 find the definitions with a parse skipping function bodies, then fully parse only
+                              the sources the closure goes through
+    --pch HEADER              parse the sources on top of a precompiled HEADER, kept around for the next runs
+    --bazel FILE              take the sources and flags from `bazel aquery --output=jsonproto` or a Bazel
+                              compile_commands.json
+    --bazel-execroot DIR      resolve Bazel's relative paths against DIR (default: `bazel info execution_root`)
+    --cmake-build-dir DIR     take the sources and flags from the CMake File API replies in DIR
+    --cmake-target NAME       only take the sources of the CMake target NAME
+    --path-map FROM=TO        read paths starting with FROM, e.g. from a container build, from TO instead (repeatable)
+    --emit DIR|-              like --output, `-` streams the files to stdout between `==> path <==` lines
+    --out-archive FILE        write the extracted tree into a .tar[.gz|.xz|.bz2], .tgz or .zip instead
+    --in-place                trim the original sources down to the closure instead of copying it
+    --force                   let --in-place delete the files the closure doesn't need, which it only lists otherwise
+    --emit-patch FILE         write the trimming as a patch against the original tree instead
+    --includes keep|rewrite|minimal
+                              emit the include directives as they are, respelled where files moved (default),
+                              or respelled and only those the extracted symbols need
+    --inline-headers N        paste the symbols of headers contributing at most N of them into the one file
+                              including them instead of emitting the header
+    --drop-redundant-prototypes
+                              leave out prototypes of functions defined further up the same emitted file
+    --mark-cuts               comment the uses of dependencies left out by --exclude, --boundary or --max-depth
+                              and list them in the manifest
+    --line-markers            put #line markers in the emitted files so that __FILE__, __LINE__ and compiler
+                              messages point at the original sources, and __func__ keeps the original name
+    --keep-regions            carry over the #pragma region and // MARK: style markers around extracted symbols
+    --preserve-blank-lines    keep the blank lines that separated symbols in the original files
+    --separator TEXT          put a TEXT line between consecutive symbols instead
+    --prefix PREFIX           prepend PREFIX to every extracted global name
+    --internalize             make helpers that are not targets static
+    --roots-from-ld SCRIPT    also extract the ENTRY of the linker SCRIPT and whatever is in its KEEP() sections
+    --exclude NAME            leave the definition of NAME out and stub it (repeatable)
+    --strip-macro NAME        replace expansions of NAME by a no-op instead of extracting what they use (repeatable)
+    --boundary GLOB           keep headers matching GLOB as includes and extract nothing behind them (repeatable)
+    --treat-as-system GLOB    take headers matching GLOB for system ones, e.g. a vendor SDK outside the standard
+                              include directories (repeatable)
+    --treat-as-project GLOB   take headers matching GLOB for project ones even in a system include directory (repeatable)
+    --external-lib PREFIX[=LIB,...]
+                              treat the headers under PREFIX, e.g. openssl/ or zlib.h, like --boundary ones and
+                              link the LIBs into the generated build (repeatable)
+    --asm-source FILE         copy the .S or .s FILE into the output and its build files when it defines a symbol
+                              the extracted code declares but nothing extracted defines (repeatable)
+    --tracked-only            treat the files git doesn't track like --boundary headers
+    --kernel TREE             parse with the flags of the configured and prepared Linux TREE and extract what
+                              the sources export or register with module_init and friends as well
+    --kernel-arch ARCH        architecture to take the kernel headers of (default: the one of this machine)
+    --ms-extensions           parse MSVC code: __declspec, SAL annotations and #pragma comment, which is kept
+    --pkg-config PKG          find headers in the include directories of PKG and treat them like --boundary ones (repeatable)
+    --direction deps|callers  extract what the targets need (default), or their callers up to the entry points too
+    --max-depth N             stop the closure after N hops and list the symbols that were cut
+    --mocks                   generate weak mocks for the external functions
+    --fuzz NAME               extract NAME as well and write fuzz_target.c feeding it libFuzzer input
+    --unity                   generate unity.c including every extracted source
+    --header-only             generate ccthief_single.h holding the whole closure as a header-only library
+    --bindgen                 write the umbrella header ccthief.h and run bindgen on it into bindings.rs
+    --c2rust                  write compile_commands.json the way c2rust expects it
+    --c2rust-transpile        like --c2rust, and run `c2rust transpile` into DIR/rust
+    --reduce --test SCRIPT    drop extracted symbols for as long as SCRIPT, run in the output, keeps succeeding
+    --verify                  compile the emitted sources and report diagnostics against the original lines
+    --check-link              compile the emitted sources and list the symbols nothing defines
+    --run                     build the extracted main with a generated Makefile and run it
+    --run-compare             like --run, and check that the original build prints the same
+    --emit-graph FILE         write the dependency graph as DOT, or JSON for *.json (repeatable)
+    --graph-edges KINDS       only export the comma separated edge KINDS (call, address-taken, use, type, macro, include)
+    --emit-callgraph FILE     write the call graph of all parsed functions as DOT, or JSON for *.json
+    --emit-db FILE            write every parsed symbol and edge into the SQLite database FILE
+    --stats FILE              write extraction statistics as JSON
+    --stats-top N             number of largest symbols to report (default: 10)
+    --dead-symbols FILE       list the parsed symbols that were left out, grouped by file
+    --unused-includes FILE    list the includes of the original sources that provide nothing used
+    --prefer-definition NAME=FILE
+                              emit the definition of NAME found in FILE when several are found (repeatable)
+    --incremental             skip the run when nothing changed since the last one into the same DIR,
+                              otherwise only parse the sources whose files changed and rewrite the
+                              files whose contents changed
+    --timings                 print the time spent in every phase and on every source
+    --cache DIR|URL           save the analysis of every source and the extracted files in DIR or an HTTP cache
+                              taking PUT, keyed by content so that it can be shared; while it is fresh `rdeps`
+                              and extractions that only write the output directory
+                              skip parsing
+    --explain NAME            print the shortest dependency path from a target to NAME (repeatable)
+";
+
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    process::exit(1);
+}
+
+impl Options {
+    // Whether the extraction goes through a temporary directory instead of straight into `output`
+    pub fn staged(&self) -> bool {
+        self.out_archive.is_some() || self.emit_stdout || self.in_place ||
+            self.emit_patch.is_some() || !matches!(self.command, Command::Extract | Command::Vendor)
+    }
+
+    // Whether the run only writes the extraction into `output`, which the
+    // cache can then hand out again: no reports, checks or commands besides,
+    // and every source parsed
+    pub fn replayable(&self) -> bool {
+        self.command == Command::Extract && !self.staged() && !self.incremental && !self.lazy && !self.prepass &&
+            !self.verify && !self.check_link && !self.run && !self.bindgen && !self.c2rust_transpile &&
+            self.reduce.is_none() && self.emit_graph.is_empty() && self.emit_db.is_none() &&
+            self.emit_callgraph.is_none() && self.dead_symbols.is_none() && self.unused_includes.is_none() &&
+            self.stats.is_none() && self.explain.is_empty()
+    }
+
+    // The -l flags of the --external-lib libraries
+    pub fn link_flags(&self) -> Vec<String> {
+        self.libraries.iter().map(|library| format!("-l{}", library)).collect()
+    }
+
+    pub fn from_args() -> Self {
+        let mut options = Options {
+            command: Command::Extract,
+            sources: vec![],
+            targets: vec![],
+            listed_targets: HashSet::new(),
+            allow_missing_targets: false,
+            strict: false,
+            keep_going: false,
+            diagnostics: Some(Severity::Error),
+            target_locations: vec![],
+            api_headers: vec![],
+            groups: vec![],
+            clang_args: vec![],
+            path_map: PathMap::default(),
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            emit_jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            pch: None,
+            lazy: false,
+            prepass: false,
+            output: PathBuf::from("target_dir/"),
+            source_root: None,
+            external_directory: PathBuf::from("_external"),
+            symlinks: Symlinks::Follow,
+            case_insensitive_includes: false,
+            bundle_system_headers: false,
+            out_archive: None,
+            emit_stdout: false,
+            in_place: false,
+            force: false,
+            emit_patch: None,
+            spacing: Spacing::Compact,
+            includes: IncludePolicy::Rewrite,
+            inline_headers: None,
+            drop_redundant_prototypes: false,
+            keep_regions: false,
+            line_markers: false,
+            mark_cuts: false,
+            prefix: None,
+            internalize: false,
+            excludes: HashSet::new(),
+            strip_macros: HashSet::new(),
+            max_depth: None,
+            direction: Direction::Dependencies,
+            roots_from_ld: None,
+            tracked_only: false,
+            boundaries: vec![],
+            treat_as_system: vec![],
+            treat_as_project: vec![],
+            kernel: false,
+            ms_extensions: false,
+            external_roots: vec![],
+            libraries: vec![],
+            asm_sources: vec![],
+            mocks: false,
+            fuzz: None,
+            unity: false,
+            header_only: false,
+            bindgen: false,
+            c2rust: false,
+            c2rust_transpile: false,
+            reduce: None,
+            verify: false,
+            check_link: false,
+            run: false,
+            run_compare: false,
+            emit_graph: vec![],
+            graph_edges: EdgeKind::ALL.iter().cloned().collect(),
+            stats: None,
+            stats_top: 10,
+            explain: vec![],
+            dead_symbols: None,
+            unused_includes: None,
+            emit_callgraph: None,
+            emit_db: None,
+            prefer_definitions: HashMap::new(),
+            cache: None,
+            incremental: false,
+            timings: false,
+        };
+
+        let mut args = env::args().skip(1).peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("diff") | Some("rdeps") | Some("server") | Some("serve") | Some("vendor") | Some("update") => args.next(),
+            _ => None,
+        };
+        let mut baseline = None;
+        let mut symbol = None;
+        let mut upstream = None;
+        let mut socket = None;
+        let mut bazel = None;
+        let mut execution_root = None;
+        let mut cmake_build_directory = None;
+        let mut cmake_target = None;
+        let mut packages = vec![];
+        let mut kernel_tree = None;
+        let mut split_targets = false;
+        let mut kernel_arch = None;
+        let mut reduce = false;
+        let mut test = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--target" | "-t" => options.targets.push(args.next().unwrap_or_else(|| usage())),
+                "--api-header" => options.api_headers.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--targets-from" => {
+                    let path = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                    for name in symbol_list::load(&path) {
+                        options.listed_targets.insert(name.clone());
+                        if !options.targets.contains(&name) {
+                            options.targets.push(name);
+                        }
+                    }
+                },
+                "--link-object" => {
+                    let object = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                    let needed = symbol_list::undefined(&object).unwrap_or_else(|why| {
+                        eprintln!("{}", why);
+                        process::exit(1);
+                    });
+                    eprintln!("{} needs {} symbols: {}", object.display(), needed.len(), needed.join(", "));
+                    for name in needed {
+                        options.listed_targets.insert(name.clone());
+                        if !options.targets.contains(&name) {
+                            options.targets.push(name);
+                        }
+                    }
+                },
+                "--diagnostics" => options.diagnostics = match args.next().unwrap_or_else(|| usage()).as_str() {
+                    "none" => None,
+                    "note" => Some(Severity::Note),
+                    "warning" => Some(Severity::Warning),
+                    "error" => Some(Severity::Error),
+                    "fatal" => Some(Severity::Fatal),
+                    _ => usage(),
+                },
+                "--keep-going" => options.keep_going = true,
+                "--strict" => options.strict = true,
+                "--allow-missing-targets" => options.allow_missing_targets = true,
+                "--target-usr" => options.targets.push(args.next().unwrap_or_else(|| usage())),
+                "--target-at" => {
+                    let location = args.next().unwrap_or_else(|| usage());
+                    let (file, line) = location.rsplit_once(':').unwrap_or_else(|| usage());
+                    let line = line.parse().unwrap_or_else(|_| usage());
+                    options.target_locations.push((PathBuf::from(file), line));
+                },
+                "--group" => {
+                    let group = args.next().unwrap_or_else(|| usage());
+                    let (directory, names) = group.split_once('=').unwrap_or_else(|| usage());
+                    let names = names.split(',').filter(|n| !n.is_empty()).map(String::from).collect::<Vec<_>>();
+                    for name in &names {
+                        if !options.targets.contains(name) {
+                            options.targets.push(name.clone());
+                        }
+                    }
+                    options.groups.push((String::from(directory), names));
+                },
+                "--split-targets" => split_targets = true,
+                "--path-map" => options.path_map.add(&args.next().unwrap_or_else(|| usage())).unwrap_or_else(|| usage()),
+                "--bazel" => bazel = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--bazel-execroot" => execution_root = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--cmake-build-dir" => cmake_build_directory = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--cmake-target" => cmake_target = Some(args.next().unwrap_or_else(|| usage())),
+                "--jobs" | "-j" => options.jobs = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+                "--emit-jobs" => options.emit_jobs = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+                "--lazy" => options.lazy = true,
+                "--prepass" => options.prepass = true,
+                "--pch" => options.pch = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--output" | "-o" => options.output = PathBuf::from(args.next().unwrap_or_else(|| usage())),
+                "--src-root" => options.source_root = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--follow-symlinks" => options.symlinks = Symlinks::Follow,
+                "--logical-paths" => options.symlinks = Symlinks::Logical,
+                "--case-insensitive-includes" => options.case_insensitive_includes = true,
+                "--bundle-system-headers" => options.bundle_system_headers = true,
+                "--external-dir" => options.external_directory = PathBuf::from(args.next().unwrap_or_else(|| usage())),
+                "--emit" => match args.next().unwrap_or_else(|| usage()).as_str() {
+                    "-" => options.emit_stdout = true,
+                    dir => options.output = PathBuf::from(dir),
+                },
+                "--in-place" => options.in_place = true,
+                "--force" => options.force = true,
+                "--emit-patch" => options.emit_patch = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--out-archive" => options.out_archive = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--includes" => options.includes = match args.next().unwrap_or_else(|| usage()).as_str() {
+                    "keep" => IncludePolicy::Keep,
+                    "rewrite" => IncludePolicy::Rewrite,
+                    "minimal" => IncludePolicy::Minimal,
+                    _ => usage(),
+                },
+                "--inline-headers" => options.inline_headers = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())),
+                "--drop-redundant-prototypes" => options.drop_redundant_prototypes = true,
+                "--mark-cuts" => options.mark_cuts = true,
+                "--line-markers" => options.line_markers = true,
+                "--keep-regions" => options.keep_regions = true,
+                "--preserve-blank-lines" => options.spacing = Spacing::Preserve,
+                "--separator" => options.spacing = Spacing::Separator(args.next().unwrap_or_else(|| usage())),
+                "--prefix" => options.prefix = Some(args.next().unwrap_or_else(|| usage())),
+                "--internalize" => options.internalize = true,
+                "--strip-macro" => { options.strip_macros.insert(args.next().unwrap_or_else(|| usage())); },
+                "--exclude" => { options.excludes.insert(args.next().unwrap_or_else(|| usage())); },
+                "--kernel" => kernel_tree = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--ms-extensions" => options.ms_extensions = true,
+                "--kernel-arch" => kernel_arch = Some(args.next().unwrap_or_else(|| usage())),
+                "--pkg-config" => packages.push(args.next().unwrap_or_else(|| usage())),
+                "--boundary" => options.boundaries.push(args.next().unwrap_or_else(|| usage())),
+                "--treat-as-system" => options.treat_as_system.push(args.next().unwrap_or_else(|| usage())),
+                "--treat-as-project" => options.treat_as_project.push(args.next().unwrap_or_else(|| usage())),
+                "--external-lib" => {
+                    let spec = args.next().unwrap_or_else(|| usage());
+                    let (prefix, libraries) = spec.split_once('=').unwrap_or((&spec, ""));
+                    // A directory covers everything under it, e.g. openssl/
+                    let pattern = if prefix.ends_with('/') { format!("{}**", prefix) } else { String::from(prefix) };
+                    options.boundaries.push(pattern);
+                    for library in libraries.split(',').filter(|l| !l.is_empty()) {
+                        if !options.libraries.iter().any(|l| l == library) {
+                            options.libraries.push(String::from(library));
+                        }
+                    }
+                },
+                "--asm-source" => options.asm_sources.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--direction" => options.direction = match args.next().unwrap_or_else(|| usage()).as_str() {
+                    "deps" => Direction::Dependencies,
+                    "callers" => Direction::Callers,
+                    _ => usage(),
+                },
+                "--roots-from-ld" => options.roots_from_ld = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--tracked-only" => options.tracked_only = true,
+                "--max-depth" => options.max_depth = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())),
+                "--mocks" => options.mocks = true,
+                "--fuzz" => {
+                    let name = args.next().unwrap_or_else(|| usage());
+                    options.targets.push(name.clone());
+                    options.fuzz = Some(name);
+                },
+                "--unity" => options.unity = true,
+                "--header-only" => options.header_only = true,
+                "--bindgen" => options.bindgen = true,
+                "--c2rust" => options.c2rust = true,
+                "--c2rust-transpile" => {
+                    options.c2rust = true;
+                    options.c2rust_transpile = true;
+                },
+                "--emit-graph" => options.emit_graph.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--graph-edges" => options.graph_edges = args.next().unwrap_or_else(|| usage()).split(',')
+                    .map(|kind| EdgeKind::from_name(kind).unwrap_or_else(|| usage()))
+                    .collect(),
+                "--stats" => options.stats = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--stats-top" => options.stats_top = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+                "--verify" => options.verify = true,
+                "--check-link" => options.check_link = true,
+                "--run" => options.run = true,
+                "--run-compare" => {
+                    options.run = true;
+                    options.run_compare = true;
+                },
+                "--reduce" => reduce = true,
+                "--test" => test = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--baseline" => baseline = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--symbol" => symbol = Some(args.next().unwrap_or_else(|| usage())),
+                "--socket" => socket = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--upstream" => upstream = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--explain" => options.explain.push(args.next().unwrap_or_else(|| usage())),
+                "--dead-symbols" => options.dead_symbols = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--unused-includes" => options.unused_includes = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--emit-db" => options.emit_db = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--emit-callgraph" => options.emit_callgraph = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+                "--prefer-definition" => {
+                    let arg = args.next().unwrap_or_else(|| usage());
+                    let (name, file) = arg.split_once('=').unwrap_or_else(|| usage());
+                    options.prefer_definitions.insert(String::from(name), String::from(file));
+                },
+                "--incremental" => options.incremental = true,
+                "--timings" => options.timings = true,
+                "--cache" => options.cache = Some(args.next().unwrap_or_else(|| usage())),
+                "--help" | "-h" => usage(),
+                // Everything after `--` is handed to clang verbatim
+                "--" => options.clang_args.extend(args.by_ref()),
+                _ if arg.starts_with('-') => usage(),
+                _ => options.sources.push(arg),
+            }
+        }
+
+        options.command = match command.as_deref() {
+            Some("diff") => Command::Diff(baseline.unwrap_or_else(|| usage())),
+            Some("rdeps") => Command::Rdeps(symbol.unwrap_or_else(|| usage())),
+            Some("server") => Command::Server,
+            Some("serve") => Command::Serve(socket.unwrap_or_else(|| usage())),
+            Some("vendor") => Command::Vendor,
+            Some("update") => Command::Update(upstream),
+            _ => Command::Extract,
+        };
+
+        if reduce {
+            options.reduce = Some(test.unwrap_or_else(|| usage()));
+        }
+
+        if let Some(path) = bazel {
+            let (sources, clang_args) = bazel::load(&path, execution_root.as_deref());
+            options.sources.extend(sources);
+            // Flags given on the command line come last so that they win
+            options.clang_args.splice(0..0, clang_args);
+        }
+
+        if let Some(directory) = cmake_build_directory {
+            let (sources, clang_args) = cmake::load(&directory, cmake_target.as_deref());
+            options.sources.extend(sources);
+            options.clang_args.splice(0..0, clang_args);
+        }
+
+        if options.ms_extensions {
+            options.clang_args.splice(0..0, msvc::clang_args());
+        }
+
+        if let Some(tree) = kernel_tree {
+            let arch = kernel_arch.unwrap_or_else(|| String::from(kernel::host_arch()));
+            options.kernel = true;
+            options.clang_args.splice(0..0, kernel::clang_args(&tree, &arch));
+            options.boundaries.extend(kernel::GENERATED_HEADERS.iter().map(|p| String::from(*p)));
+        }
+
+        if !packages.is_empty() {
+            options.external_roots = pkg_config::include_roots(&packages);
+            for root in &options.external_roots {
+                options.clang_args.push(format!("-I{}", root.to_str().unwrap()));
+            }
+        }
+
+        if !options.path_map.is_empty() {
+            options.sources = options.sources.iter().map(|s| options.path_map.map_arg(s)).collect();
+            options.clang_args = options.clang_args.iter().map(|a| options.path_map.map_arg(a)).collect();
+        }
+
+        if options.sources.is_empty() {
+            options.sources = vec![String::from("examples/simple.c"), String::from("examples/simple_impl.c")];
+        }
+        if options.targets.is_empty() && options.target_locations.is_empty() && options.api_headers.is_empty() {
+            options.targets = vec![String::from("main")];
+        }
+        if split_targets {
+            options.groups.extend(options.targets.iter().map(|t| (String::from(target_name(t)), vec![t.clone()])));
+        }
+        if !options.groups.is_empty() && options.staged() {
+            eprintln!("--group and --split-targets need the trees written to a directory");
+            process::exit(1);
+        }
+        if options.run && !options.targets.iter().any(|t| t == "main") {
+            eprintln!("--run needs main among the targets");
+            process::exit(1);
+        }
+
+        options
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clang::*;
+use clang::diagnostic::Severity;
+
+use canonicalize;
+use pch;
+use sha256;
+
+// libclang can parse on several threads at once as long as every thread has
+// an index of its own. clang-rs can't express that, so indexes are handed to
+// the workers and translation units brought back through this wrapper; no
+// two threads ever touch the same index or translation unit.
+struct Unshared<T>(T);
+
+unsafe impl<T> Send for Unshared<T> {}
+unsafe impl<T> Sync for Unshared<T> {}
+
+impl<T> Unshared<T> {
+    fn get(&self) -> &T {
+        &self.0
+    }
+}
+
+// Every file `tu` was made of: `source` and whatever it includes, however
+// deep, following clang's own record of each file's includes.
+pub fn files(tu: &TranslationUnit, source: &Path) -> BTreeSet<PathBuf> {
+    let mut ret = BTreeSet::new();
+    let mut pending = tu.get_file(source).into_iter().collect::<Vec<_>>();
+    ret.insert(source.to_path_buf());
+
+    while let Some(file) = pending.pop() {
+        for include in file.get_includes() {
+            if let Some(included) = include.get_file() {
+                if ret.insert(included.get_path()) {
+                    pending.push(included);
+                }
+            }
+        }
+    }
+    ret
+}
+
+pub fn is_header(source: &str) -> bool {
+    Path::new(source).extension().is_some_and(|ext| ext == "h" || ext == "hh" || ext == "hpp" || ext == "hxx")
+}
+
+// Headers given as sources are parsed through a file that only includes them,
+// named so that clang takes it for the language the header is written in.
+fn wrapper(header: &str, clang_args: &[String]) -> PathBuf {
+    let header = canonicalize(Path::new(header)).unwrap_or_else(|why| panic!("Couldn't find {}: {}", header, why));
+    let extension = if pch::language(&header, clang_args).starts_with("c++") { "cpp" } else { "c" };
+
+    let digest = sha256::digest(header.to_str().unwrap().as_bytes());
+    let stem = header.file_stem().unwrap().to_str().unwrap();
+    let path = env::temp_dir().join("ccthief-wrappers").join(format!("{}-{}.{}", stem, &digest[..16], extension));
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, format!("#include \"{}\"\n", header.to_str().unwrap().replace('\\', "/"))).unwrap();
+    path
+}
+
+pub fn indexes(clang: &Clang, jobs: usize) -> Vec<Index<'_>> {
+    (0..jobs).map(|_| Index::new(clang, false, true)).collect()
+}
+
+// Parses `sources` on one thread per index, returning the translation units
+// in the order of `sources` together with their index in it and how long each
+// one took. `skip_bodies` leaves out everything inside functions. With
+// `keep_going`, sources libclang can't parse at all are left out instead of
+// stopping everything.
+pub fn parse_all<'c>(
+    indexes: &'c [Index<'c>],
+    sources: &[String],
+    clang_args: &[String],
+    skip_bodies: bool,
+    keep_going: bool
+) -> Vec<(usize, TranslationUnit<'c>, Duration)>
+{
+    let shared = Unshared(indexes);
+
+    let mut parsed = thread::scope(|scope| {
+        let workers = (0..indexes.len()).map(|worker| {
+            let shared = &shared;
+            scope.spawn(move || {
+                let index = &shared.get()[worker];
+                sources.iter().enumerate()
+                    .skip(worker)
+                    .step_by(shared.get().len())
+                    .filter_map(|(i, source)| {
+                        eprintln!("Parsing {}...", source);
+                        let start = Instant::now();
+                        let path = if is_header(source) { wrapper(source, clang_args) } else { PathBuf::from(source) };
+                        let tu = index
+                            .parser(path)
+                            .arguments(clang_args)
+                            .detailed_preprocessing_record(!skip_bodies)
+                            .skip_function_bodies(skip_bodies)
+                            .parse();
+                        match tu {
+                            Ok(tu) => Some((i, Unshared((tu, start.elapsed())))),
+                            Err(why) if keep_going => {
+                                eprintln!("Warning: couldn't parse {}: {}, leaving it out", source, why);
+                                None
+                            },
+                            Err(why) => panic!("Couldn't parse {}: {}", source, why),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+        }).collect::<Vec<_>>();
+
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    parsed.sort_by_key(|&(i, _)| i);
+    parsed.into_iter().map(|(i, Unshared((tu, elapsed)))| (i, tu, elapsed)).collect()
+}
+
+// Prints the diagnostics of `tus` from `severity` up, each one once however
+// many translation units run into it, e.g. a broken header.
+pub fn print_diagnostics(tus: &[TranslationUnit], severity: Severity) {
+    let mut seen = HashSet::new();
+    let mut errors = false;
+
+    for tu in tus {
+        for diagnostic in tu.get_diagnostics().into_iter().filter(|d| d.get_severity() >= severity) {
+            let text = diagnostic.to_string();
+            if seen.insert(text.clone()) {
+                eprintln!("{}", text);
+                errors |= diagnostic.get_severity() >= Severity::Error;
+            }
+        }
+    }
+
+    if errors {
+        eprintln!("Warning: the sources didn't parse cleanly, the closure may be missing or misplace symbols");
+    }
+}
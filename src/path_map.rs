@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Prefix rewrites for paths recorded somewhere else than where the files are
+// now, like the /build of a compile database made inside a container.
+#[derive(Clone, Default)]
+pub struct PathMap {
+    prefixes: Vec<(PathBuf, PathBuf)>,
+}
+
+// The map of the run, for the places paths come from that aren't options
+static CURRENT: OnceLock<PathMap> = OnceLock::new();
+
+impl PathMap {
+    // `FROM=TO`
+    pub fn add(&mut self, spec: &str) -> Option<()> {
+        let (from, to) = spec.split_once('=')?;
+        self.prefixes.push((PathBuf::from(from), PathBuf::from(to)));
+        Some(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    // `path` with the first matching prefix replaced.
+    pub fn map(&self, path: &Path) -> PathBuf {
+        self.prefixes.iter()
+            .find_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| to.join(rest)))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    // A compiler argument with its path mapped, be it the whole argument or
+    // attached to a flag like `-I/build/include`.
+    pub fn map_arg(&self, arg: &str) -> String {
+        let flag_length = if arg.starts_with('-') { arg.find('/').unwrap_or(arg.len()) } else { 0 };
+        let (flag, path) = arg.split_at(flag_length);
+        if path.is_empty() {
+            return String::from(arg)
+        }
+        format!("{}{}", flag, self.map(Path::new(path)).to_str().unwrap())
+    }
+
+    pub fn install(&self) {
+        CURRENT.set(self.clone()).ok();
+    }
+}
+
+// Maps `path` with the map of the run.
+pub fn map(path: &Path) -> PathBuf {
+    CURRENT.get().map_or_else(|| path.to_path_buf(), |current| current.map(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_to_src() -> PathMap {
+        let mut map = PathMap::default();
+        map.add("/build=/src").unwrap();
+        map
+    }
+
+    #[test]
+    fn whole_argument() {
+        assert_eq!(build_to_src().map_arg("/build/foo.c"), "/src/foo.c");
+        assert_eq!(build_to_src().map_arg("/elsewhere/foo.c"), "/elsewhere/foo.c");
+    }
+
+    #[test]
+    fn attached_to_a_flag() {
+        assert_eq!(build_to_src().map_arg("-I/build/include"), "-I/src/include");
+        assert_eq!(build_to_src().map_arg("-isystem/build/sys"), "-isystem/src/sys");
+        assert_eq!(build_to_src().map_arg("-I/opt/include"), "-I/opt/include");
+    }
+
+    #[test]
+    fn without_a_path() {
+        assert_eq!(build_to_src().map_arg("-O2"), "-O2");
+        assert_eq!(build_to_src().map_arg("-DFOO=1"), "-DFOO=1");
+        assert_eq!(build_to_src().map_arg("foo.c"), "foo.c");
+    }
+
+    #[test]
+    fn prefixes_match_whole_components() {
+        assert_eq!(build_to_src().map_arg("/builder/foo.c"), "/builder/foo.c");
+    }
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clang::*;
+
+use canonicalize;
+use parse;
+use sha256;
+
+// Precompiled headers are kept around between runs, next to the list of
+// files each one was made from.
+fn directory() -> PathBuf {
+    env::temp_dir().join("ccthief-pch")
+}
+
+pub fn language(header: &Path, clang_args: &[String]) -> &'static str {
+    let cxx_header = header.extension().is_some_and(|ext| ext == "hpp" || ext == "hh" || ext == "hxx");
+    let cxx_args = clang_args.windows(2).any(|pair| pair[0] == "-x" && pair[1].starts_with("c++")) ||
+        clang_args.iter().any(|arg| arg.starts_with("-std=c++") || arg.starts_with("-std=gnu++"));
+    if cxx_header || cxx_args { "c++-header" } else { "c-header" }
+}
+
+// Whether none of the files listed in `inputs` changed since `pch` was made.
+fn is_fresh(pch: &Path, inputs: &Path) -> bool {
+    let built = match fs::metadata(pch).and_then(|m| m.modified()) {
+        Ok(built) => built,
+        Err(_) => return false,
+    };
+    match fs::read_to_string(inputs) {
+        Ok(files) => files.lines().all(|file| fs::metadata(file).and_then(|m| m.modified()).is_ok_and(|m| m <= built)),
+        Err(_) => false,
+    }
+}
+
+// The arguments to parse the sources with so that they start from a
+// precompiled `header`, which gets built by libclang itself the first time
+// so that it always matches the libclang reading it.
+pub fn arguments(index: &Index, header: &Path, clang_args: &[String]) -> Vec<String> {
+    let header = canonicalize(header).unwrap_or_else(|why| panic!("Couldn't find {:?}: {}", header, why));
+
+    // A precompiled header only works with the libclang that made it
+    let version = get_version();
+    let fields = iter::once(version.as_str())
+        .chain(iter::once(header.to_str().unwrap()))
+        .chain(clang_args.iter().map(String::as_str));
+    let pch = directory().join(format!("{}.pch", sha256::digest_all(fields.map(str::as_bytes))));
+    let inputs = pch.with_extension("inputs");
+
+    if is_fresh(&pch, &inputs) {
+        eprintln!("Reusing the precompiled {:?}", header);
+    } else {
+        eprintln!("Precompiling {:?}...", header);
+        let mut args = clang_args.to_vec();
+        args.extend([String::from("-x"), String::from(language(&header, clang_args))]);
+
+        let tu = index.parser(&header)
+            .arguments(&args)
+            .detailed_preprocessing_record(true)
+            .incomplete(true)
+            .parse()
+            .unwrap_or_else(|why| panic!("Couldn't parse {:?}: {}", header, why));
+
+        // Other runs may be using them, so they only ever appear whole
+        fs::create_dir_all(directory()).unwrap();
+        let temporary = pch.with_extension(format!("{}.tmp", process::id()));
+        tu.save(&temporary).unwrap_or_else(|why| panic!("Couldn't save {:?}: {:?}", temporary, why));
+        fs::rename(&temporary, &pch).unwrap();
+
+        let files = parse::files(&tu, &header).into_iter()
+            .map(|path| String::from(path.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        fs::write(&temporary, files.join("\n")).unwrap();
+        fs::rename(&temporary, &inputs).unwrap();
+    }
+
+    let mut args = clang_args.to_vec();
+    args.extend([String::from("-include-pch"), String::from(pch.to_str().unwrap())]);
+    args
+}
@@ -0,0 +1,22 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use canonicalize;
+
+// The include directories of the pkg-config `packages`, e.g. glib-2.0 for
+// <glib.h>. Headers under them belong to the installed library.
+pub fn include_roots(packages: &[String]) -> Vec<PathBuf> {
+    let mut command = Command::new("pkg-config");
+    command.arg("--cflags-only-I").args(packages);
+
+    let output = command.output().unwrap_or_else(|why| panic!("Couldn't run pkg-config: {}", why));
+    if !output.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        panic!("{:?} exited with {}", command, output.status);
+    }
+
+    String::from_utf8_lossy(&output.stdout).split_whitespace()
+        .filter_map(|flag| flag.strip_prefix("-I"))
+        .map(|path| canonicalize(Path::new(path)).unwrap_or_else(|_| PathBuf::from(path)))
+        .collect()
+}
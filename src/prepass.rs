@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use clang::*;
+
+use in_system_header;
+use parse;
+
+// Where every function and variable is defined, going by a parse that skips
+// the function bodies and so takes a fraction of the time of a full one.
+fn definitions(indexes: &[Index], sources: &[String], clang_args: &[String], keep_going: bool) -> HashMap<String, Vec<usize>> {
+    let mut ret: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for (i, tu, _) in parse::parse_all(indexes, sources, clang_args, true, keep_going) {
+        for child in tu.get_entity().get_children() {
+            let defined = matches!(child.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl) &&
+                child.is_definition() && !in_system_header(&child);
+            if let (true, Some(name)) = (defined, child.get_name()) {
+                ret.entry(name).or_default().push(i);
+            }
+        }
+    }
+
+    ret
+}
+
+// The functions and variables used anywhere in `tu` outside of system headers.
+fn referenced(tu: &TranslationUnit) -> HashSet<String> {
+    let mut ret = HashSet::new();
+    tu.get_entity().visit_children(|entity, _| {
+        if in_system_header(&entity) {
+            return EntityVisitResult::Continue
+        }
+        if let Some(referenced) = entity.get_reference() {
+            if matches!(referenced.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl) {
+                ret.extend(referenced.get_name());
+            }
+        }
+        EntityVisitResult::Recurse
+    });
+    ret
+}
+
+// Fully parses the sources defining the targets, then the ones defining what
+// those use and so on, returning them in the order of `sources` with how long
+// each one took. Everything else only gets the quick parse.
+pub fn parse_needed<'c>(
+    indexes: &'c [Index<'c>],
+    sources: &[String],
+    clang_args: &[String],
+    targets: &[String],
+    keep_going: bool
+) -> Vec<(String, TranslationUnit<'c>, Duration)>
+{
+    let definitions = definitions(indexes, sources, clang_args, keep_going);
+
+    let mut parsed = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut wanted = targets.iter().cloned().collect::<VecDeque<_>>();
+
+    loop {
+        let mut batch = BTreeSet::<usize>::new();
+        while let Some(name) = wanted.pop_front() {
+            if seen.insert(name.clone()) {
+                batch.extend(definitions.get(&name).into_iter().flatten().filter(|i| !parsed.contains_key(*i)));
+            }
+        }
+        if batch.is_empty() {
+            break
+        }
+
+        let batch = batch.into_iter().collect::<Vec<_>>();
+        let batch_sources = batch.iter().map(|&i| sources[i].clone()).collect::<Vec<_>>();
+        for (j, tu, elapsed) in parse::parse_all(indexes, &batch_sources, clang_args, false, keep_going) {
+            wanted.extend(referenced(&tu));
+            parsed.insert(batch[j], (tu, elapsed));
+        }
+    }
+
+    if parsed.is_empty() {
+        eprintln!("Warning: none of the sources defines the targets, parsing all of them");
+        return parse::parse_all(indexes, sources, clang_args, false, keep_going).into_iter()
+            .map(|(i, tu, elapsed)| (sources[i].clone(), tu, elapsed))
+            .collect()
+    }
+
+    eprintln!("Fully parsed {} of {} sources", parsed.len(), sources.len());
+    parsed.into_iter().map(|(i, (tu, elapsed))| (sources[i].clone(), tu, elapsed)).collect()
+}
@@ -0,0 +1,166 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+
+use clang::*;
+
+use graph::{EdgeKind, node_key, node_label};
+use symbol_table::SymbolTable;
+
+pub type ReverseEdges<'a> = HashMap<Entity<'a>, HashSet<Entity<'a>>>;
+
+// Inverts the dependency graph built by `visit`: maps an entity to the
+// entities that depend on it.
+pub fn reverse_edges<'a>(sym_table: &SymbolTable<'a>) -> ReverseEdges<'a> {
+    let mut ret: ReverseEdges = HashMap::new();
+
+    for (entity, desc) in sym_table.iter() {
+        for dep in desc.deps.keys().chain(desc.definitions.iter()) {
+            ret.entry(*dep).or_default().insert(*entity);
+
+            // An expansion depends on the macro it expands
+            if dep.get_kind() == EntityKind::MacroExpansion {
+                if let Some(definition) = dep.get_reference() {
+                    ret.entry(definition).or_default().insert(*dep);
+                }
+            }
+        }
+    }
+
+    ret
+}
+
+// Entities called `name`, including macro definitions which aren't part of the symbol table.
+pub fn find<'a>(sym_table: &SymbolTable<'a>, reverse: &ReverseEdges<'a>, name: &str) -> Vec<Entity<'a>> {
+    let macros = reverse.keys()
+        .filter(|e| e.get_kind() == EntityKind::MacroDefinition && e.get_name().as_deref() == Some(name));
+    sym_table.keys()
+        .filter(|e| sym_table.name(e) == Some(name))
+        .chain(macros)
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// The names in the symbol table closest to `name`, for when it isn't there:
+// a few typos away, or the same but for the case or a prefix.
+pub fn similar_names(sym_table: &SymbolTable, name: &str) -> Vec<String> {
+    let lowercase = name.to_lowercase();
+    let mut candidates = sym_table.keys()
+        .filter_map(|e| sym_table.name(e))
+        .map(String::from)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(&lowercase, &candidate.to_lowercase());
+            let contains = candidate.to_lowercase().contains(&lowercase) || lowercase.contains(&candidate.to_lowercase());
+            if distance <= (name.len() / 3).max(2) || (contains && candidate.len() > 2) {
+                Some((distance, candidate))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    candidates.sort();
+    candidates.into_iter().take(5).map(|(_, candidate)| candidate).collect()
+}
+
+// Every entity that transitively depends on one of the `seeds`, the seeds included.
+pub fn users<'a>(reverse: &ReverseEdges<'a>, seeds: &[Entity<'a>]) -> HashSet<Entity<'a>> {
+    let mut visited = seeds.iter().cloned().collect::<HashSet<_>>();
+    let mut q = seeds.iter().cloned().collect::<VecDeque<_>>();
+
+    while let Some(entity) = q.pop_front() {
+        for user in reverse.get(&entity).into_iter().flatten() {
+            if visited.insert(*user) {
+                q.push_back(*user);
+            }
+        }
+    }
+
+    visited.retain(|e| !matches!(e.get_kind(), EntityKind::MacroExpansion | EntityKind::InclusionDirective));
+    visited
+}
+
+// Every symbol that transitively depends on one of the `seeds`.
+pub fn rdeps<'a>(reverse: &ReverseEdges<'a>, seeds: &[Entity<'a>]) -> BTreeSet<String> {
+    let seed_keys = seeds.iter().map(node_key).collect::<HashSet<_>>();
+
+    users(reverse, seeds).iter()
+        .filter(|user| !seed_keys.contains(&node_key(user)))
+        .map(node_label)
+        .collect()
+}
+
+// Forward edges of `entity` together with the reason each one exists.
+pub fn edges<'a>(sym_table: &SymbolTable<'a>, entity: &Entity<'a>) -> Vec<(Entity<'a>, &'static str)> {
+    if entity.get_kind() == EntityKind::MacroExpansion {
+        return entity.get_reference().into_iter().map(|d| (d, EdgeKind::Macro.name())).collect();
+    }
+
+    match sym_table.get(entity) {
+        Some(desc) => desc.deps.iter().flat_map(|(d, kinds)| kinds.iter().map(move |k| (*d, k.name())))
+            .chain(desc.definitions.iter().filter(|d| *d != entity).map(|d| (*d, "definition")))
+            .collect(),
+        None => vec![],
+    }
+}
+
+// The shortest chain of dependencies leading from one of the `seeds` to a
+// symbol called `name`, as (entity, kind of the edge leading to it) pairs.
+// The first entity is the seed itself and comes with a "target" kind.
+pub fn explain<'a>(
+    sym_table: &SymbolTable<'a>,
+    seeds: &[Entity<'a>],
+    name: &str
+) -> Option<Vec<(Entity<'a>, &'static str)>>
+{
+    let mut parents: HashMap<Entity<'a>, Option<(Entity<'a>, &'static str)>> = HashMap::new();
+    let mut q = VecDeque::new();
+
+    for seed in seeds {
+        parents.insert(*seed, None);
+        q.push_back(*seed);
+    }
+
+    while let Some(entity) = q.pop_front() {
+        if entity.get_name().as_deref() == Some(name) {
+            let mut path = vec![];
+            let mut current = (entity, "target");
+            while let Some(parent) = parents[&current.0] {
+                path.push((current.0, parent.1));
+                current = (parent.0, "target");
+            }
+            path.push(current);
+            path.reverse();
+            return Some(path);
+        }
+
+        for (dep, kind) in edges(sym_table, &entity) {
+            if let Entry::Vacant(parent) = parents.entry(dep) {
+                parent.insert(Some((entity, kind)));
+                q.push_back(dep);
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use clang::*;
+
+use source_file;
+use source_map::SourceMap;
+use symbol_table::SymbolTable;
+use {CanonicalPath, get_path};
+
+fn is_interesting(test: &Path, target_directory: &Path) -> bool {
+    Command::new(test)
+        .current_dir(target_directory)
+        .status()
+        .unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", test, why))
+        .success()
+}
+
+// Writes the emitted files without the lines of the `removed` mappings.
+fn write(target_directory: &Path, files: &BTreeMap<PathBuf, Vec<String>>, source_map: &SourceMap, removed: &BTreeSet<usize>) {
+    for (file, lines) in files {
+        let dropped = removed.iter()
+            .map(|&i| &source_map.mappings[i])
+            .filter(|m| &m.output == file)
+            .flat_map(|m| m.output_lines.0 ..= m.output_lines.1)
+            .collect::<HashSet<_>>();
+
+        let path = target_directory.join(file);
+        let newline = source_file::newline(&path);
+        let mut contents = String::new();
+        for (_, line) in lines.iter().enumerate().filter(|(i, _)| !dropped.contains(&(*i as u32 + 1))) {
+            contents.push_str(line);
+            contents.push_str(newline);
+        }
+        fs::write(path, source_file::encode(&contents)).unwrap();
+    }
+}
+
+// Shrinks the emitted tree to the smallest set of symbols for which `test`,
+// run inside the tree, still succeeds. A symbol is only tried once nothing
+// left in the tree depends on it, so most attempts still compile.
+pub fn reduce<'a>(
+    target_directory: &Path,
+    test: &Path,
+    source_map: &mut SourceMap,
+    sym_table: &SymbolTable<'a>,
+    extracted: &HashSet<Entity<'a>>
+) {
+    let test = test.canonicalize().unwrap_or_else(|why| panic!("Couldn't find {:?}: {}", test, why));
+
+    if !is_interesting(&test, target_directory) {
+        eprintln!("The extracted tree isn't interesting to begin with");
+        process::exit(1);
+    }
+
+    let chunks = source_map.mappings.iter().enumerate()
+        .filter(|(_, m)| m.symbol.is_some())
+        .map(|(i, m)| ((CanonicalPath::new(m.original.clone()), m.original_lines.0), i))
+        .collect::<HashMap<_, _>>();
+    let chunk = |entity: &Entity| {
+        let line = entity.get_range().unwrap().get_start().get_file_location().line;
+        chunks.get(&(CanonicalPath::new(get_path(entity)), line)).cloned()
+    };
+
+    // chunk -> chunks that depend on it
+    let mut dependents: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for entity in extracted.iter().filter(|e| sym_table.contains_key(e)) {
+        let user = match chunk(entity) {
+            Some(user) => user,
+            None => continue,
+        };
+        let desc = &sym_table[entity];
+        for dep in desc.deps.keys().chain(desc.definitions.iter()) {
+            let dep = match dep.get_kind() {
+                EntityKind::MacroExpansion => dep.get_reference(),
+                _ => Some(*dep),
+            };
+            if let Some(used) = dep.and_then(|d| chunk(&d)).filter(|&used| used != user) {
+                dependents.entry(used).or_default().insert(user);
+            }
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    for mapping in &source_map.mappings {
+        files.entry(mapping.output.clone()).or_insert_with(|| {
+            source_file::read_lines(&target_directory.join(&mapping.output))
+        });
+    }
+
+    // Later chunks tend to be the users of earlier ones
+    let mut candidates = chunks.values().cloned().collect::<Vec<_>>();
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut removed = BTreeSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &candidate in &candidates {
+            if removed.contains(&candidate) {
+                continue
+            }
+            if dependents.get(&candidate).into_iter().flatten().any(|d| !removed.contains(d)) {
+                continue
+            }
+
+            removed.insert(candidate);
+            write(target_directory, &files, source_map, &removed);
+
+            if is_interesting(&test, target_directory) {
+                eprintln!("Removed {}", source_map.mappings[candidate].symbol.as_ref().unwrap());
+                changed = true;
+            } else {
+                removed.remove(&candidate);
+            }
+        }
+    }
+
+    write(target_directory, &files, source_map, &removed);
+    eprintln!("Reduced {} symbols to {}", candidates.len(), candidates.len() - removed.len());
+
+    // Renumber what is left so that the source map matches the reduced files
+    let mut shift: HashMap<PathBuf, Vec<(u32, u32)>> = HashMap::new();
+    for &i in &removed {
+        let m = &source_map.mappings[i];
+        shift.entry(m.output.clone()).or_default().push((m.output_lines.0, m.output_lines.1 - m.output_lines.0 + 1));
+    }
+
+    let mappings = source_map.mappings.drain(..).enumerate()
+        .filter(|(i, _)| !removed.contains(i))
+        .map(|(_, mut m)| {
+            let before = shift.get(&m.output).into_iter().flatten()
+                .filter(|(start, _)| *start < m.output_lines.0)
+                .map(|(_, count)| count)
+                .sum::<u32>();
+            m.output_lines = (m.output_lines.0 - before, m.output_lines.1 - before);
+            m
+        })
+        .collect();
+    source_map.mappings = mappings;
+}
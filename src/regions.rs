@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use source_file;
+
+enum Marker {
+    // #pragma region, // region
+    Open,
+    // #pragma endregion, // endregion
+    Close,
+    // #pragma mark, // MARK:, which last until the next one
+    Section,
+}
+
+fn marker(line: &str) -> Option<Marker> {
+    let line = line.trim_start();
+    if let Some(directive) = line.strip_prefix('#') {
+        let mut words = directive.split_whitespace();
+        if words.next() != Some("pragma") {
+            return None
+        }
+        return match words.next() {
+            Some("region") => Some(Marker::Open),
+            Some("endregion") => Some(Marker::Close),
+            Some("mark") => Some(Marker::Section),
+            _ => None,
+        }
+    }
+
+    let comment = line.strip_prefix("//")?.trim_start();
+    if comment.starts_with("MARK:") {
+        Some(Marker::Section)
+    } else if comment.starts_with("endregion") {
+        Some(Marker::Close)
+    } else if comment.starts_with("region") {
+        Some(Marker::Open)
+    } else {
+        None
+    }
+}
+
+// The grouping markers to carry over, with their lines, by the line of the
+// symbol they go before or after.
+#[derive(Default)]
+pub struct Regions {
+    pub before: HashMap<u32, Vec<(u32, String)>>,
+    pub after: HashMap<u32, Vec<(u32, String)>>,
+}
+
+// Places the region and section markers of `file` that enclose any of the
+// `symbols`, given by their first and last lines, around the first and last
+// of them. The ones enclosing nothing extracted are left out.
+pub fn place(file: &Path, symbols: &[(u32, u32)]) -> Regions {
+    let lines = source_file::read_lines(file);
+    let mut symbols = symbols.to_vec();
+    symbols.sort();
+
+    // Markers inside a symbol already come along with it
+    let markers = lines.iter().enumerate()
+        .map(|(n, line)| (n as u32 + 1, line))
+        .filter(|&(n, _)| !symbols.iter().any(|&(start, end)| start <= n && n <= end))
+        .filter_map(|(n, line)| marker(line).map(|marker| (n, marker, line.clone())))
+        .collect::<Vec<_>>();
+
+    let end_of_file = lines.len() as u32 + 1;
+    let mut spans = vec![];
+    let mut open = vec![];
+    for (i, marker) in markers.iter().enumerate() {
+        match marker.1 {
+            Marker::Open => open.push(i),
+            // An unmatched one closes nothing
+            Marker::Close => spans.extend(open.pop().map(|start| (start, Some(i)))),
+            Marker::Section => spans.push((i, None)),
+        }
+    }
+    spans.extend(open.into_iter().map(|start| (start, None)));
+
+    let mut regions = Regions::default();
+    for (start, end) in spans {
+        let from = markers[start].0;
+        let to = match (end, &markers[start].1) {
+            (Some(end), _) => markers[end].0,
+            (None, &Marker::Section) => markers[start + 1..].iter().find(|m| matches!(m.1, Marker::Section)).map_or(end_of_file, |m| m.0),
+            (None, _) => end_of_file,
+        };
+
+        let enclosed = symbols.iter().filter(|&&(symbol, _)| from < symbol && symbol < to).collect::<Vec<_>>();
+        if let (Some(&&(first, _)), Some(&&(last, _))) = (enclosed.first(), enclosed.last()) {
+            regions.before.entry(first).or_default().push((from, markers[start].2.clone()));
+            if let Some(end) = end {
+                regions.after.entry(last).or_default().push((markers[end].0, markers[end].2.clone()));
+            }
+        }
+    }
+
+    for markers in regions.before.values_mut().chain(regions.after.values_mut()) {
+        markers.sort();
+    }
+    regions
+}
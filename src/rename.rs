@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clang::*;
+use clang::source::SourceRange;
+use clang::token::TokenKind;
+
+use {in_system_header, macro_parameters};
+use source_file::{SourceFile, decode, encode};
+
+// line -> (column, length, replacement)
+type LineEdits = BTreeMap<u32, BTreeSet<(u32, usize, String)>>;
+
+// Rewrites every extracted global name to `prefix + name`. Definitions and
+// references are found by annotating the tokens of each emitted file, so the
+// rename follows what clang resolved rather than plain text matching. Other
+// edits of the emitted text, like stripped macro expansions, go through it
+// as well.
+pub struct Renamer {
+    // USR -> new name
+    usrs: HashMap<String, String>,
+    // Old name -> new name, for the places clang doesn't resolve
+    names: HashMap<String, String>,
+    edits: HashMap<PathBuf, LineEdits>,
+    // Whether __func__ in renamed functions keeps saying the original name
+    keep_function_names: bool,
+}
+
+fn is_renameable(entity: &Entity) -> bool {
+    match entity.get_kind() {
+        EntityKind::FunctionDecl | EntityKind::VarDecl | EntityKind::TypedefDecl |
+        EntityKind::StructDecl | EntityKind::UnionDecl | EntityKind::EnumDecl => (),
+        _ => return false,
+    }
+
+    match entity.get_name() {
+        Some(ref name) if name != "main" => !in_system_header(entity),
+        _ => false,
+    }
+}
+
+impl Renamer {
+    pub fn new<'a, I: IntoIterator<Item = &'a Entity<'a>>>(prefix: &str, symbols: I) -> Self {
+        let mut renamer = Renamer {
+            usrs: HashMap::new(),
+            names: HashMap::new(),
+            edits: HashMap::new(),
+            keep_function_names: false,
+        };
+
+        for symbol in symbols.into_iter().filter(|s| is_renameable(s)) {
+            if let Some(usr) = symbol.get_usr() {
+                let name = symbol.get_name().unwrap();
+                renamer.usrs.insert(usr.0, format!("{}{}", prefix, name));
+                renamer.names.insert(name.clone(), format!("{}{}", prefix, name));
+            }
+        }
+
+        renamer
+    }
+
+    // Renames a single `entity` on top of whatever the prefix did. Unlike the
+    // prefix this isn't applied to macro bodies, since the old name is still
+    // taken by other symbols there.
+    pub fn rename(&mut self, entity: &Entity, name: String) {
+        self.usrs.insert(entity.get_usr().unwrap().0, name);
+    }
+
+    // Has __func__ and __FUNCTION__ spelled out as the original name of the
+    // renamed function they are in, for messages that have to match the original.
+    pub fn keep_function_names(&mut self) {
+        self.keep_function_names = true;
+    }
+
+    // Collects the edits for `file` from every translation unit that saw it.
+    pub fn collect(&mut self, tus: &[TranslationUnit], file: &Path) {
+        let length = fs::metadata(file).unwrap().len() as u32;
+
+        for tu in tus {
+            let source = match tu.get_file(file) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let range = SourceRange::new(source.get_location(1, 1), source.get_offset_location(length));
+            let tokens = range.tokenize();
+            let cursors = tu.annotate(&tokens);
+
+            // Renamed function definitions in the file by their extent
+            let functions = if self.keep_function_names {
+                tu.get_entity().get_children().into_iter()
+                    .filter(|f| f.get_kind() == EntityKind::FunctionDecl && f.is_definition() && self.renamed(f).is_some())
+                    .filter_map(|f| {
+                        let range = f.get_range()?;
+                        let (start, end) = (range.get_start().get_file_location(), range.get_end().get_file_location());
+                        (start.file.map(|f| f.get_path()).as_deref() == Some(file)).then(|| (start.offset, end.offset, f.get_name().unwrap()))
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                vec![]
+            };
+
+            // Parameters of the macros in the file, which are never renamed
+            let mut parameters = HashMap::new();
+
+            for (token, cursor) in tokens.iter().zip(cursors) {
+                let spelling = token.get_spelling();
+                if spelling == "__func__" || spelling == "__FUNCTION__" {
+                    let location = token.get_location().get_file_location();
+                    let function = functions.iter().find(|&&(start, end, _)| start <= location.offset && location.offset <= end);
+                    if let Some((_, _, name)) = function {
+                        self.edits.entry(file.to_path_buf()).or_default()
+                            .entry(location.line).or_default()
+                            .insert((location.column, spelling.len(), format!("\"{}\"", name)));
+                    }
+                    continue
+                }
+                if token.get_kind() != TokenKind::Identifier {
+                    continue
+                }
+
+                let replacement = match cursor {
+                    // Macro bodies are never resolved by clang, so fall back to names there
+                    Some(cursor) if cursor.get_kind() == EntityKind::MacroDefinition => {
+                        let parameters = parameters.entry(cursor).or_insert_with(|| macro_parameters(&cursor));
+                        if cursor.get_name().as_ref() != Some(&spelling) && !parameters.contains(&spelling) {
+                            self.names.get(&spelling).cloned()
+                        } else {
+                            None
+                        }
+                    },
+                    Some(cursor) => {
+                        let target = cursor.get_reference().unwrap_or(cursor);
+                        if target.get_name().as_ref() == Some(&spelling) {
+                            target.get_usr().and_then(|usr| self.usrs.get(&usr.0).cloned())
+                        } else {
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                if let Some(replacement) = replacement {
+                    let location = token.get_location().get_file_location();
+                    self.edits.entry(file.to_path_buf()).or_default()
+                        .entry(location.line).or_default()
+                        .insert((location.column, spelling.len(), replacement));
+                }
+            }
+        }
+    }
+
+    // Replaces the text of `file` from `start` up to `end`, both (line, column)
+    // with `end` exclusive, by `replacement`. Lines in between are emptied
+    // rather than dropped so that the source map still lines up.
+    pub fn replace(&mut self, file: &Path, start: (u32, u32), end: (u32, u32), replacement: &str) {
+        let source = SourceFile::open(file);
+        let edits = self.edits.entry(file.to_path_buf()).or_default();
+
+        for line in start.0 ..= end.0 {
+            let from = if line == start.0 { start.1 } else { 1 };
+            let to = if line == end.0 { end.1 } else { encode(&source.line(line)).len() as u32 + 1 };
+            let text = if line == start.0 { replacement } else { "" };
+
+            let line_edits = edits.entry(line).or_default();
+            line_edits.retain(|&(column, _, _)| column < from || column >= to);
+            line_edits.insert((from, (to - from) as usize, String::from(text)));
+        }
+    }
+
+    // The new name of `entity`, if it is one of the renamed symbols.
+    pub fn renamed(&self, entity: &Entity) -> Option<String> {
+        entity.get_usr().and_then(|usr| self.usrs.get(&usr.0).cloned())
+    }
+
+    pub fn has_edits(&self, file: &Path) -> bool {
+        self.edits.contains_key(file)
+    }
+
+    // Applies the edits for a single 1-based `line_number` of `file`.
+    pub fn apply(&self, file: &Path, line_number: u32, line: &str) -> String {
+        let edits = match self.edits.get(file).and_then(|lines| lines.get(&line_number)) {
+            Some(edits) => edits,
+            None => return String::from(line),
+        };
+
+        // Columns count the bytes of the file, so edit those rather than the decoded text
+        let bytes = encode(line);
+        let mut ret = Vec::with_capacity(bytes.len());
+        let mut last = 0;
+
+        for &(column, length, ref replacement) in edits {
+            let start = column as usize - 1;
+            // Overlapping edits, e.g. a rename inside a stripped expansion, go to the first one
+            if start < last || start + length > bytes.len() {
+                continue
+            }
+            ret.extend_from_slice(&bytes[last..start]);
+            ret.extend_from_slice(replacement.as_bytes());
+            last = start + length;
+        }
+        ret.extend_from_slice(&bytes[last..]);
+
+        decode(&ret)
+    }
+}
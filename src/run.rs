@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command, Output};
+
+use makefile;
+
+fn execute(command: &mut Command) -> Output {
+    command.output().unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", command, why))
+}
+
+// Builds the original sources the way they were parsed and runs the result.
+fn run_original(sources: &[String], clang_args: &[String]) -> Output {
+    let program = env::temp_dir().join(format!("ccthief-original-{}", process::id()));
+
+    let mut command = Command::new("clang");
+    command.args(sources).args(clang_args).arg("-o").arg(&program);
+    let build = execute(&mut command);
+    if !build.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&build.stderr));
+        panic!("Couldn't build the original sources: {:?} exited with {}", command, build.status);
+    }
+
+    let output = execute(&mut Command::new(&program));
+    fs::remove_file(&program).unwrap();
+    output
+}
+
+// Builds the emitted tree with its Makefile and runs it as a smoke test. With
+// `original` given, the original sources are built and run as well and both
+// runs have to agree on stdout and the exit status. Returns whether they do,
+// or whether the extracted program succeeded otherwise.
+pub fn run(target_directory: &Path, original: Option<(&[String], &[String])>) -> bool {
+    let build = execute(Command::new("make").arg("-s").arg(makefile::PROGRAM).current_dir(target_directory));
+    if !build.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&build.stderr));
+        eprintln!("Couldn't build the extracted program");
+        return false
+    }
+
+    eprintln!("Running the extracted program");
+    let extracted = execute(Command::new(Path::new(".").join(makefile::PROGRAM)).current_dir(target_directory));
+    print!("{}", String::from_utf8_lossy(&extracted.stdout));
+    eprint!("{}", String::from_utf8_lossy(&extracted.stderr));
+
+    let (sources, clang_args) = match original {
+        Some(original) => original,
+        None => {
+            eprintln!("The extracted program exited with {}", extracted.status);
+            return extracted.status.success()
+        },
+    };
+
+    let expected = run_original(sources, clang_args);
+    let mut same = true;
+
+    if expected.status.code() != extracted.status.code() {
+        eprintln!("The original program exited with {}, the extracted one with {}", expected.status, extracted.status);
+        same = false;
+    }
+
+    let expected_lines = String::from_utf8_lossy(&expected.stdout).lines().map(String::from).collect::<Vec<_>>();
+    let extracted_lines = String::from_utf8_lossy(&extracted.stdout).lines().map(String::from).collect::<Vec<_>>();
+    for i in 0..expected_lines.len().max(extracted_lines.len()) {
+        let (a, b) = (expected_lines.get(i), extracted_lines.get(i));
+        if a != b {
+            eprintln!("stdout differs at line {}:", i + 1);
+            eprintln!("- {}", a.map(String::as_str).unwrap_or("<end of output>"));
+            eprintln!("+ {}", b.map(String::as_str).unwrap_or("<end of output>"));
+            same = false;
+            break
+        }
+    }
+
+    if same {
+        eprintln!("The extracted program behaves like the original one");
+    }
+    same
+}
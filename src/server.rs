@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+#[cfg(unix)]
+use std::io::BufReader;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use clang::*;
+
+use boundary::Boundaries;
+use emit;
+use json;
+use options::Options;
+use source_file::SourceFile;
+use symbol_table::SymbolTable;
+use {CanonicalPath, Closure, common_ancestor, extract_symbols, get_path, in_system_header};
+use {is_system_file, relative_to_root, symbol_text};
+
+// The symbol an editor means by the cursor at `line`:`column` of `file`:
+// whatever is referenced there, or else the symbol the cursor is inside of.
+fn symbol_at<'a>(tus: &'a [TranslationUnit], sym_table: &SymbolTable<'a>, file: &Path, line: u32, column: u32) -> Option<Entity<'a>> {
+    let entity = tus.iter()
+        .filter_map(|tu| tu.get_file(file))
+        .filter_map(|f| f.get_location(line, column).get_entity())
+        .next()?;
+
+    if let Some(referenced) = entity.get_reference().filter(|r| sym_table.contains_key(r)) {
+        return Some(referenced)
+    }
+
+    let mut entity = Some(entity);
+    while let Some(e) = entity {
+        if sym_table.contains_key(&e) {
+            return Some(e)
+        }
+        entity = e.get_lexical_parent();
+    }
+    None
+}
+
+// Writes `closure` into `output` straight from the parsed translation units:
+// the symbols of every file in their original order, along with the
+// includes of what is written too or comes from the system. The renaming
+// and the other rewrites of a full extraction are left to one.
+fn emit(tus: &[TranslationUnit], closure: &Closure, options: &Options, boundaries: &Boundaries, output: &Path) -> Result<usize, String> {
+    let mut files = BTreeMap::new();
+    for symbol in &closure.symbols {
+        if matches!(symbol.get_kind(), EntityKind::MacroExpansion | EntityKind::InclusionDirective) ||
+            in_system_header(symbol) || boundaries.contains(&get_path(symbol)) {
+            continue
+        }
+        files.entry(CanonicalPath::new(get_path(symbol))).or_insert_with(Vec::new).push(*symbol);
+    }
+    let root = common_ancestor(files.keys().map(|f| &*f.0)).ok_or("nothing to write")?;
+
+    for (file, symbols) in &files {
+        let source = SourceFile::open(&file.0);
+        let mut chunks = BTreeMap::new();
+
+        // Nested symbols, like the struct of a typedef, come with their parent
+        let mut symbols = symbols.iter().map(|s| (s, symbol_text(s, &source, None))).collect::<Vec<_>>();
+        symbols.sort_by_key(|(_, (_, lines))| (lines.0, u32::MAX - lines.1));
+        for (_, (text, lines)) in symbols {
+            if chunks.range(..=lines.0).next_back().is_none_or(|(_, (_, end))| *end < lines.1) {
+                chunks.insert(lines.0, (text, lines.1));
+            }
+        }
+
+        let includes = tus.iter()
+            .flat_map(|tu| tu.get_entity().get_children())
+            .filter(|e| e.get_kind() == EntityKind::InclusionDirective && CanonicalPath::new(get_path(e)) == *file);
+        for include in includes {
+            let keep = match include.get_file() {
+                Some(included) => {
+                    let included = included.get_path();
+                    is_system_file(&included, include.is_in_system_header()) ||
+                        files.contains_key(&CanonicalPath::new(included))
+                },
+                None => true,
+            };
+            if keep {
+                let (text, lines) = symbol_text(&include, &source, None);
+                chunks.entry(lines.0).or_insert((text, lines.1));
+            }
+        }
+
+        let mut contents = String::new();
+        for (text, _) in chunks.values() {
+            for line in text {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+        }
+
+        let path = output.join(relative_to_root(&file.0, &root, &options.external_directory));
+        fs::create_dir_all(path.parent().unwrap()).map_err(|why| why.to_string())?;
+        emit::write_if_changed(&path, contents);
+    }
+
+    Ok(files.len())
+}
+
+// The names of the symbols in `sym_table`, gathered once for every request
+fn names<'s>(sym_table: &'s SymbolTable) -> HashSet<&'s str> {
+    sym_table.keys().filter_map(|e| sym_table.name(e)).collect()
+}
+
+// The closure of the symbol named by `symbol`, or found at `file`, `line`
+// and `column`, extracted into `output` when that is given.
+fn respond(params: &json::Value, tus: &[TranslationUnit], sym_table: &SymbolTable, names: &HashSet<&str>, options: &Options, boundaries: &Boundaries) -> Result<Vec<(&'static str, String)>, String> {
+    let name = match params.get("symbol").and_then(json::Value::as_str) {
+        Some(name) => {
+            if !names.contains(name) {
+                return Err(format!("symbol {} not found", name))
+            }
+            String::from(name)
+        },
+        None => {
+            let file = params.get("file").and_then(json::Value::as_str).ok_or("missing file")?;
+            let line = params.get("line").and_then(json::Value::as_u64).ok_or("missing line")?;
+            let column = params.get("column").and_then(json::Value::as_u64).ok_or("missing column")?;
+
+            let symbol = symbol_at(tus, sym_table, Path::new(file), line as u32, column as u32)
+                .ok_or_else(|| format!("no symbol at {}:{}:{}", file, line, column))?;
+            symbol.get_name().ok_or("the symbol has no name")?
+        },
+    };
+
+    let closure = extract_symbols(vec![name.clone()], &options.excludes, options.max_depth, boundaries, sym_table);
+    let files = closure.symbols.iter()
+        .filter(|e| !in_system_header(e))
+        .map(|e| CanonicalPath::new(get_path(e)))
+        .collect::<BTreeSet<_>>();
+
+    let mut response = vec![
+        ("symbol", json::string(&name)),
+        ("symbols", closure.symbols.len().to_string()),
+        ("files", json::array(files.iter().map(|f| json::string(f.0.to_str().unwrap())))),
+    ];
+    if let Some(output) = params.get("output").and_then(json::Value::as_str) {
+        let written = emit(tus, &closure, options, boundaries, Path::new(output))?;
+        response.push(("written", written.to_string()));
+    }
+
+    Ok(response)
+}
+
+// Answers requests for the closure of the symbol under an editor's cursor,
+// one JSON object per line on stdin and stdout:
+//   {"id": 1, "file": "a.c", "line": 10, "column": 5, "output": "DIR"}
+// `output` is optional and extracts the closure into DIR as well. A
+// `symbol` can be given by name instead of `file`, `line` and `column`.
+pub fn serve(tus: &[TranslationUnit], sym_table: &SymbolTable, options: &Options, boundaries: &Boundaries) {
+    let names = names(sym_table);
+    eprintln!("Ready");
+
+    let stdout = io::stdout();
+    for line in io::stdin().lock().lines() {
+        let line = line.unwrap();
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let (id, result) = match json::parse(&line) {
+            Ok(request) => {
+                let id = request.get("id").and_then(json::Value::as_u64).map(|id| id.to_string());
+                (id, respond(&request, tus, sym_table, &names, options, boundaries))
+            },
+            Err(why) => (None, Err(format!("invalid request: {}", why))),
+        };
+
+        let mut fields = vec![("id", json::optional(id))];
+        match result {
+            Ok(response) => fields.extend(response),
+            Err(why) => fields.push(("error", json::string(&why))),
+        }
+
+        let mut stdout = stdout.lock();
+        writeln!(stdout, "{}", json::object(&fields)).unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+// JSON-RPC 2.0 over the connections to a unix socket at `path`, one request
+// per line. The translation units stay parsed in between, so `closure` is
+// answered right away. `emit` takes the same parameters plus `output` and
+// writes the closure there from them too. `shutdown` stops the daemon.
+#[cfg(unix)]
+pub fn serve_socket(path: &Path, tus: &[TranslationUnit], sym_table: &SymbolTable, options: &Options, boundaries: &Boundaries) {
+    let names = names(sym_table);
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap_or_else(|why| panic!("Couldn't listen on {:?}: {}", path, why));
+    eprintln!("Listening on {:?}", path);
+
+    // Translation units can't be shared between threads, so connections are served one at a time
+    'connections: for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(why) => {
+                eprintln!("Couldn't accept a connection: {}", why);
+                continue
+            },
+        };
+        let mut writer = stream.try_clone().unwrap();
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue
+            }
+
+            let request = json::parse(&line);
+            let id = request.as_ref().ok().and_then(|r| r.get("id")).and_then(|id| {
+                id.as_u64().map(|id| id.to_string()).or_else(|| id.as_str().map(json::string))
+            });
+            let method = request.as_ref().ok().and_then(|r| r.get("method")).and_then(json::Value::as_str);
+
+            let result = match (&request, method) {
+                (Err(why), _) => Err((-32700, format!("parse error: {}", why))),
+                (Ok(request), Some("closure")) | (Ok(request), Some("emit")) => {
+                    let params = request.get("params").cloned().unwrap_or(json::Value::Null);
+                    if method == Some("emit") && params.get("output").is_none() {
+                        Err((-32602, String::from("missing output")))
+                    } else if method == Some("closure") && params.get("output").is_some() {
+                        Err((-32602, String::from("closure doesn't write anything, use emit")))
+                    } else {
+                        respond(&params, tus, sym_table, &names, options, boundaries).map_err(|why| (-32602, why))
+                    }
+                },
+                (Ok(_), Some("shutdown")) => Ok(vec![]),
+                (Ok(_), _) => Err((-32601, String::from("method not found"))),
+            };
+
+            let mut fields = vec![("jsonrpc", json::string("2.0")), ("id", json::optional(id))];
+            match result {
+                Ok(result) => fields.push(("result", json::object(&result))),
+                Err((code, message)) => fields.push(("error", json::object(&[
+                    ("code", code.to_string()),
+                    ("message", json::string(&message)),
+                ]))),
+            }
+            if writeln!(writer, "{}", json::object(&fields)).is_err() {
+                break
+            }
+
+            if method == Some("shutdown") {
+                break 'connections
+            }
+        }
+    }
+
+    // Whoever removed the socket meanwhile has done it for us
+    if let Err(why) = fs::remove_file(path) {
+        if why.kind() != io::ErrorKind::NotFound {
+            panic!("Couldn't remove {:?}: {}", path, why);
+        }
+    }
+}
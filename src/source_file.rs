@@ -0,0 +1,125 @@
+use std::cell::{OnceCell, RefCell};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str;
+
+// Bytes that aren't UTF-8, e.g. Latin-1 comments in old code, become these
+// private use characters so that they make it back to the output unchanged.
+const ESCAPES: u32 = 0x10FF00;
+
+// Reads text without losing whatever isn't UTF-8 in it.
+pub fn decode(mut bytes: &[u8]) -> String {
+    let mut ret = String::new();
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                ret.push_str(valid);
+                return ret
+            },
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                ret.push_str(str::from_utf8(valid).unwrap());
+                let invalid = error.error_len().unwrap_or(rest.len());
+                for &byte in &rest[..invalid] {
+                    ret.push(char::from_u32(ESCAPES + byte as u32).unwrap());
+                }
+                bytes = &rest[invalid..];
+            },
+        }
+    }
+}
+
+// The bytes `decode` got `text` from.
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c as u32 {
+            escaped if escaped >= ESCAPES => ret.push((escaped - ESCAPES) as u8),
+            _ => ret.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    ret
+}
+
+// How the lines of `path` end, going by its first one, so that CRLF files
+// stay CRLF.
+pub fn newline(path: &Path) -> &'static str {
+    let mut line = vec![];
+    if let Ok(file) = fs::File::open(path) {
+        BufReader::new(file).read_until(b'\n', &mut line).unwrap_or_default();
+    }
+    if line.ends_with(b"\r\n") { "\r\n" } else { "\n" }
+}
+
+// The lines of a whole file, without their line endings.
+pub fn read_lines(path: &Path) -> Vec<String> {
+    let bytes = fs::read(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    decode(&bytes).lines().map(String::from).collect()
+}
+
+// An original source file read piece by piece instead of all at once, since
+// generated sources can be huge and only a fraction of them gets extracted.
+pub struct SourceFile {
+    file: RefCell<BufReader<fs::File>>,
+    // Byte offset of every line, only built when lines are looked up by number
+    line_starts: OnceCell<Vec<u64>>,
+}
+
+fn trim_newline(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+impl SourceFile {
+    pub fn open(path: &Path) -> Self {
+        let file = fs::File::open(path).unwrap_or_else(|why| panic!("Couldn't open {:?}: {}", path, why));
+        SourceFile { file: RefCell::new(BufReader::new(file)), line_starts: OnceCell::new() }
+    }
+
+    // The whole lines overlapping the bytes from `start` to `end`, with
+    // `start` being the beginning of a line.
+    pub fn lines_between(&self, start: u64, end: u64) -> Vec<String> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(start)).unwrap();
+
+        let mut bytes = vec![];
+        file.by_ref().take(end - start).read_to_end(&mut bytes).unwrap();
+        // Up to the end of the line `end` is on
+        file.read_until(b'\n', &mut bytes).unwrap();
+
+        decode(&bytes).lines().map(String::from).collect()
+    }
+
+    // The 1-based line `number`.
+    pub fn line(&self, number: u32) -> String {
+        let start = self.line_starts.get_or_init(|| {
+            let mut file = self.file.borrow_mut();
+            file.seek(SeekFrom::Start(0)).unwrap();
+
+            let mut starts = vec![0];
+            let mut offset = 0;
+            let mut line = vec![];
+            loop {
+                line.clear();
+                match file.read_until(b'\n', &mut line).unwrap() {
+                    0 => break,
+                    read => offset += read as u64,
+                }
+                starts.push(offset);
+            }
+            starts
+        })[number as usize - 1];
+
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(start)).unwrap();
+        let mut line = vec![];
+        file.read_until(b'\n', &mut line).unwrap();
+        trim_newline(decode(&line))
+    }
+}
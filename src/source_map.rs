@@ -0,0 +1,42 @@
+use std::path::{Path, PathBuf};
+
+use emit;
+use json;
+
+pub const FILE_NAME: &str = "ccthief-sourcemap.json";
+
+// One contiguous run of emitted lines and where it was copied from.
+// Line numbers are 1-based and inclusive on both ends.
+pub struct Mapping {
+    pub output: PathBuf,
+    pub output_lines: (u32, u32),
+    pub original: PathBuf,
+    pub original_lines: (u32, u32),
+    pub symbol: Option<String>,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    pub fn add(&mut self, mapping: Mapping) {
+        self.mappings.push(mapping);
+    }
+
+    pub fn write(&self, target_directory: &Path) {
+        let entries = self.mappings.iter().map(|m| {
+            json::object(&[
+                ("output", json::string(m.output.to_str().unwrap())),
+                ("output_lines", json::array(vec![m.output_lines.0.to_string(), m.output_lines.1.to_string()])),
+                ("original", json::string(m.original.to_str().unwrap())),
+                ("original_lines", json::array(vec![m.original_lines.0.to_string(), m.original_lines.1.to_string()])),
+                ("symbol", json::optional(m.symbol.as_ref().map(|s| json::string(s)))),
+            ])
+        });
+
+        let contents = format!("{}\n", json::array(entries));
+        emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+    }
+}
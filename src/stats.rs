@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clang::*;
+
+use graph::{node_key, node_label};
+use json;
+use source_map::SourceMap;
+use symbol_table::SymbolTable;
+use {in_system_header, symbol_range};
+
+pub struct Stats {
+    pub considered: usize,
+    pub extracted: usize,
+    pub lines_per_file: BTreeMap<PathBuf, u32>,
+    pub largest: Vec<(String, u32)>,
+    pub kinds: BTreeMap<String, usize>,
+    // Sources that didn't parse cleanly under --keep-going, with their errors
+    pub parse_errors: BTreeMap<String, Vec<String>>,
+}
+
+impl Stats {
+    pub fn new<'a>(
+        sym_table: &SymbolTable<'a>,
+        extracted: &HashSet<Entity<'a>>,
+        source_map: &SourceMap,
+        top: usize
+    ) -> Self
+    {
+        // Entities seen by several translation units are counted once
+        let considered = sym_table.keys()
+            .filter(|e| !in_system_header(e))
+            .map(node_key)
+            .collect::<HashSet<_>>();
+
+        let mut symbols = BTreeMap::new();
+        for entity in extracted.iter().filter(|e| !in_system_header(e)) {
+            let range = symbol_range(entity);
+            let lines = range.get_end().get_file_location().line - range.get_start().get_file_location().line + 1;
+            symbols.insert(node_key(entity), (node_label(entity), format!("{:?}", entity.get_kind()), lines));
+        }
+
+        let mut kinds = BTreeMap::new();
+        for (_, kind, _) in symbols.values() {
+            *kinds.entry(kind.clone()).or_insert(0) += 1;
+        }
+
+        let mut largest = symbols.values().map(|&(ref label, _, lines)| (label.clone(), lines)).collect::<Vec<_>>();
+        largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        largest.truncate(top);
+
+        let mut lines_per_file = BTreeMap::new();
+        for mapping in &source_map.mappings {
+            let lines = lines_per_file.entry(mapping.output.clone()).or_insert(0);
+            *lines = (*lines).max(mapping.output_lines.1);
+        }
+
+        Stats {
+            considered: considered.len(),
+            extracted: symbols.len(),
+            lines_per_file,
+            largest,
+            kinds,
+            parse_errors: BTreeMap::new(),
+        }
+    }
+
+    pub fn print(&self) {
+        eprintln!("Extracted {} of {} symbols", self.extracted, self.considered);
+
+        eprintln!("Lines emitted per file:");
+        for (file, lines) in &self.lines_per_file {
+            eprintln!("  {:6} {}", lines, file.to_str().unwrap());
+        }
+
+        eprintln!("Largest symbols:");
+        for &(ref label, lines) in &self.largest {
+            eprintln!("  {:6} {}", lines, label);
+        }
+
+        eprintln!("Symbols by kind:");
+        for (kind, count) in &self.kinds {
+            eprintln!("  {:6} {}", count, kind);
+        }
+
+        if !self.parse_errors.is_empty() {
+            eprintln!("Sources with parse errors, whatever they define may be missing or incomplete:");
+            for (source, errors) in &self.parse_errors {
+                eprintln!("  {}", source);
+                for error in errors {
+                    eprintln!("    {}", error);
+                }
+            }
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) {
+        let files = self.lines_per_file.iter()
+            .map(|(file, lines)| json::object(&[
+                ("file", json::string(file.to_str().unwrap())),
+                ("lines", lines.to_string()),
+            ]));
+        let largest = self.largest.iter()
+            .map(|&(ref label, lines)| json::object(&[
+                ("symbol", json::string(label)),
+                ("lines", lines.to_string()),
+            ]));
+        let parse_errors = self.parse_errors.iter()
+            .map(|(source, errors)| json::object(&[
+                ("source", json::string(source)),
+                ("errors", json::array(errors.iter().map(|e| json::string(e)))),
+            ]));
+        let kinds = self.kinds.iter()
+            .map(|(kind, count)| (kind.as_str(), count.to_string()))
+            .collect::<Vec<_>>();
+
+        let contents = json::object(&[
+            ("considered", self.considered.to_string()),
+            ("extracted", self.extracted.to_string()),
+            ("lines_per_file", json::array(files)),
+            ("largest", json::array(largest)),
+            ("kinds", json::object(&kinds)),
+            ("parse_errors", json::array(parse_errors)),
+        ]);
+        fs::write(path, contents + "\n").unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+use clang::*;
+
+use emit;
+use rename::Renamer;
+
+pub const FILE_NAME: &str = "stubs.c";
+
+// Spells a declaration of `name` with type `ty`, taking care of function
+// pointers and arrays where the name goes inside the type.
+pub fn declarator(ty: &str, name: &str) -> String {
+    if let Some(index) = ty.find("(*)") {
+        format!("{}(*{}){}", &ty[..index], name, &ty[index + 3..])
+    } else if let Some(index) = ty.find('[') {
+        format!("{} {}{}", ty[..index].trim_end(), name, &ty[index..])
+    } else {
+        format!("{} {}", ty, name)
+    }
+}
+
+// Returns the prototype of `function` without the trailing semicolon and the
+// names given to its parameters.
+pub fn prototype(function: &Entity, name: &str) -> (String, Vec<String>) {
+    let result = function.get_result_type().unwrap().get_display_name();
+    let mut names = vec![];
+    let mut params = vec![];
+
+    for (i, arg) in function.get_arguments().unwrap_or_default().iter().enumerate() {
+        let arg_name = arg.get_name().unwrap_or_else(|| format!("arg{}", i));
+        params.push(declarator(&arg.get_type().unwrap().get_display_name(), &arg_name));
+        names.push(arg_name);
+    }
+
+    if function.is_variadic() {
+        params.push(String::from("..."));
+    } else if params.is_empty() {
+        params.push(String::from("void"));
+    }
+
+    (format!("{}({})", declarator(&result, name), params.join(", ")), names)
+}
+
+pub fn write(target_directory: &Path, excluded: &HashSet<Entity>, headers: &BTreeSet<PathBuf>, renamer: Option<&Renamer>) {
+    let mut functions = BTreeMap::new();
+
+    for function in excluded {
+        if function.get_linkage() != Some(Linkage::External) {
+            eprintln!("Can't stub static function {}, its callers will not link", function.get_name().unwrap());
+            continue
+        }
+        let name = renamer.and_then(|r| r.renamed(function)).unwrap_or_else(|| function.get_name().unwrap());
+        functions.insert(name, *function);
+    }
+
+    let mut contents = String::from("/* Stubs for dependencies excluded from the extraction. */\n\n");
+    contents.push_str("#include <assert.h>\n");
+    for header in headers {
+        contents.push_str(&format!("#include \"{}\"\n", header.to_str().unwrap()));
+    }
+
+    for (name, function) in functions {
+        let (prototype, params) = prototype(&function, &name);
+        let result = function.get_result_type().unwrap();
+
+        contents.push_str(&format!("\n{} {{\n", prototype));
+        for param in params {
+            contents.push_str(&format!("    (void){};\n", param));
+        }
+        contents.push_str(&format!("    assert(0 && \"{} is excluded from the extraction\");\n", name));
+        if result.get_kind() != TypeKind::Void {
+            contents.push_str(&format!("    {} = {{0}};\n", declarator(&result.get_display_name(), "ret")));
+            contents.push_str("    return ret;\n");
+        }
+        contents.push_str("}\n");
+    }
+
+    emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn is_symbol(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+// The symbols named in the output of `nm` (e.g. `nm -u` for what an object
+// file is missing), in the "needed by" lines of a GNU ld map or one per line.
+pub fn load(path: &Path) -> Vec<String> {
+    let contents = fs::read_to_string(path).unwrap_or_else(|why| panic!("Couldn't read {:?}: {}", path, why));
+    let mut ret = vec![];
+
+    for line in contents.lines() {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let name = match words.as_slice() {
+            // nm: [address] type name
+            [kind, name] | [_, kind, name] if kind.len() == 1 => *name,
+            // ld map: the object file and the symbol it pulled the archive member in for
+            [_, needed] if needed.starts_with('(') && needed.ends_with(')') => &needed[1..needed.len() - 1],
+            [name] => *name,
+            _ => continue,
+        };
+
+        // Versioned references, printf@GLIBC_2.2.5
+        let name = name.split('@').next().unwrap();
+        if is_symbol(name) && !ret.iter().any(|n| n == name) {
+            ret.push(String::from(name));
+        }
+    }
+
+    ret
+}
+
+// Whether the object file starting with `header` spells C names with a
+// leading underscore: Mach-O does, and so does 32-bit x86 COFF.
+fn prefixes_underscore(header: &[u8]) -> bool {
+    match header {
+        [0xfe, 0xed, 0xfa, 0xce, ..] | [0xfe, 0xed, 0xfa, 0xcf, ..] |
+        [0xce, 0xfa, 0xed, 0xfe, ..] | [0xcf, 0xfa, 0xed, 0xfe, ..] |
+        // Universal binaries
+        [0xca, 0xfe, 0xba, 0xbe, ..] => true,
+        [0x4c, 0x01, ..] => true,
+        _ => false,
+    }
+}
+
+// The first object file of `contents`, or all of it when it isn't an archive.
+// Archives are a run of 60-byte headers, each followed by a member padded to
+// an even length, with the symbol index and long names in members of their own.
+fn first_object(contents: &[u8]) -> &[u8] {
+    let mut rest = match contents.strip_prefix(b"!<arch>\n") {
+        Some(rest) => rest,
+        None => return contents,
+    };
+
+    while rest.len() >= 60 {
+        let (header, members) = rest.split_at(60);
+        let name = String::from_utf8_lossy(&header[..16]);
+        let size = String::from_utf8_lossy(&header[48..58]).trim().parse::<usize>().unwrap_or(0).min(members.len());
+        let member = &members[..size];
+
+        let index = name.starts_with("/ ") || name.starts_with("// ") || name.starts_with("__.SYMDEF") ||
+            name.starts_with("/SYM64/");
+        if !index {
+            // BSD archives put long names in front of the member
+            let skip = name.strip_prefix("#1/").and_then(|n| n.trim().parse::<usize>().ok()).unwrap_or(0);
+            return &member[skip.min(member.len())..]
+        }
+        rest = &members[(size + size % 2).min(members.len())..];
+    }
+    &[]
+}
+
+// The symbols `object`, an object file or an archive of them, needs but
+// doesn't define, i.e. what the sources have to provide for it to link.
+// Reading ELF, Mach-O, COFF and archives is left to the system's nm rather
+// than done here, keeping the build free of dependencies beyond clang; the
+// format is only looked at to know how it spells C names.
+pub fn undefined(object: &Path) -> Result<Vec<String>, String> {
+    let contents = fs::read(object).map_err(|why| format!("Couldn't read {:?}: {}", object, why))?;
+    let underscore = prefixes_underscore(first_object(&contents));
+
+    let mut command = Command::new("nm");
+    command.args(["-g", "-P"]).arg(object);
+
+    let output = command.output()
+        .map_err(|why| format!("Couldn't run nm, which --link-object needs to read {:?}: {}", object, why))?;
+    if !output.status.success() {
+        return Err(format!("Couldn't list the symbols of {:?}, nm exited with {}: {}",
+            object, output.status, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    // POSIX format: name type [value size], with a header per archive member
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let mut needed = BTreeSet::new();
+    let mut defined = BTreeSet::new();
+    for line in listing.lines() {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (name, kind) = match words.as_slice() {
+            [name, kind, ..] if kind.len() == 1 => (name.split('@').next().unwrap(), *kind),
+            _ => continue,
+        };
+        let name = if underscore { name.strip_prefix('_').unwrap_or(name) } else { name };
+        if !is_symbol(name) {
+            continue
+        }
+        match kind {
+            "U" => needed.insert(name),
+            // Weak references link without a definition
+            "w" | "v" => continue,
+            _ => defined.insert(name),
+        };
+    }
+
+    Ok(needed.difference(&defined).map(|name| String::from(*name)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut ret = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, contents.len()).into_bytes();
+        ret.extend_from_slice(contents);
+        if contents.len() % 2 == 1 {
+            ret.push(b'\n');
+        }
+        ret
+    }
+
+    #[test]
+    fn object_formats() {
+        assert!(prefixes_underscore(&[0xcf, 0xfa, 0xed, 0xfe, 7, 0, 0, 1]));
+        assert!(prefixes_underscore(&[0x4c, 0x01, 3, 0]));
+        assert!(!prefixes_underscore(b"\x7fELF\x02\x01\x01"));
+        // x86-64 COFF
+        assert!(!prefixes_underscore(&[0x64, 0x86, 3, 0]));
+    }
+
+    #[test]
+    fn archive_members() {
+        let mut archive = b"!<arch>\n".to_vec();
+        archive.extend(member("/", b"index"));
+        archive.extend(member("#1/8", b"long.o\0\0\xcf\xfa\xed\xfe"));
+        assert_eq!(first_object(&archive), b"\xcf\xfa\xed\xfe");
+
+        let mut archive = b"!<arch>\n".to_vec();
+        archive.extend(member("__.SYMDEF", b"odd"));
+        archive.extend(member("a.o/", b"\x7fELF"));
+        assert_eq!(first_object(&archive), b"\x7fELF");
+
+        assert_eq!(first_object(b"\x7fELF"), b"\x7fELF");
+        assert_eq!(first_object(b"!<arch>\n"), b"");
+    }
+}
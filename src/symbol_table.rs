@@ -0,0 +1,101 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Index;
+
+use clang::*;
+
+use intern::{Interner, Name};
+use SymbolDesc;
+
+struct Record<'a> {
+    entity: Entity<'a>,
+    name: Option<Name>,
+    usr: Option<Name>,
+    desc: SymbolDesc<'a>,
+}
+
+// Every top-level symbol with what it depends on. The records sit side by
+// side in one vector the map points into, and each symbol's name and USR are
+// asked of libclang once, on the way in, and interned.
+#[derive(Default)]
+pub struct SymbolTable<'a> {
+    slots: HashMap<Entity<'a>, usize>,
+    // Removed symbols leave a hole
+    records: Vec<Option<Record<'a>>>,
+    strings: Interner,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn insert(&mut self, entity: Entity<'a>, desc: SymbolDesc<'a>) {
+        if let Some(&slot) = self.slots.get(&entity) {
+            self.records[slot].as_mut().unwrap().desc = desc;
+            return
+        }
+
+        let name = entity.get_name().map(|name| self.strings.intern(&name));
+        let usr = entity.get_usr().filter(|usr| !usr.0.is_empty()).map(|usr| self.strings.intern(&usr.0));
+        self.slots.insert(entity, self.records.len());
+        self.records.push(Some(Record { entity, name, usr, desc }));
+    }
+
+    pub fn remove(&mut self, entity: &Entity<'a>) -> Option<SymbolDesc<'a>> {
+        let slot = self.slots.remove(entity)?;
+        self.records[slot].take().map(|record| record.desc)
+    }
+
+    fn record(&self, entity: &Entity<'a>) -> Option<&Record<'a>> {
+        self.slots.get(entity).and_then(|&slot| self.records[slot].as_ref())
+    }
+
+    pub fn contains_key(&self, entity: &Entity<'a>) -> bool {
+        self.slots.contains_key(entity)
+    }
+
+    pub fn get(&self, entity: &Entity<'a>) -> Option<&SymbolDesc<'a>> {
+        self.record(entity).map(|record| &record.desc)
+    }
+
+    pub fn keys<'s>(&'s self) -> impl Iterator<Item = &'s Entity<'a>> + 's {
+        self.records.iter().flatten().map(|record| &record.entity)
+    }
+
+    pub fn iter<'s>(&'s self) -> impl Iterator<Item = (&'s Entity<'a>, &'s SymbolDesc<'a>)> + 's {
+        self.records.iter().flatten().map(|record| (&record.entity, &record.desc))
+    }
+
+    pub fn iter_mut<'s>(&'s mut self) -> impl Iterator<Item = (&'s Entity<'a>, &'s mut SymbolDesc<'a>)> + 's {
+        self.records.iter_mut().flatten().map(|record| (&record.entity, &mut record.desc))
+    }
+
+    pub fn values_mut<'s>(&'s mut self) -> impl Iterator<Item = &'s mut SymbolDesc<'a>> + 's {
+        self.records.iter_mut().flatten().map(|record| &mut record.desc)
+    }
+
+    pub fn name(&self, entity: &Entity<'a>) -> Option<&str> {
+        self.record(entity).and_then(|record| record.name).map(|name| self.strings.resolve(name))
+    }
+
+    pub fn usr(&self, entity: &Entity<'a>) -> Option<&str> {
+        self.record(entity).and_then(|record| record.usr).map(|usr| self.strings.resolve(usr))
+    }
+
+    // The interned USR of `entity`, for telling symbols apart without the string.
+    pub fn usr_name(&self, entity: &Entity<'a>) -> Option<Name> {
+        self.record(entity).and_then(|record| record.usr)
+    }
+
+    // The symbols whose name or USR is one of `names`.
+    pub fn named<'s>(&'s self, names: &[String]) -> impl Iterator<Item = &'s Entity<'a>> + 's {
+        let wanted = names.iter().filter_map(|name| self.strings.get(name)).collect::<HashSet<_>>();
+        self.records.iter().flatten()
+            .filter(move |record| record.name.iter().chain(&record.usr).any(|name| wanted.contains(name)))
+            .map(|record| &record.entity)
+    }
+}
+
+impl<'a, 'e> Index<&'e Entity<'a>> for SymbolTable<'a> {
+    type Output = SymbolDesc<'a>;
+
+    fn index(&self, entity: &'e Entity<'a>) -> &SymbolDesc<'a> {
+        self.get(entity).unwrap_or_else(|| panic!("{:?} isn't in the symbol table", entity))
+    }
+}
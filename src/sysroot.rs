@@ -0,0 +1,82 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clang::*;
+
+use emit;
+use source_file;
+use {CanonicalPath, directive, get_path, include_spelling, relative_components};
+
+pub const DIRECTORY: &str = "sysroot";
+
+// Where a bundled system header goes in the output.
+pub fn location(header: &Path) -> PathBuf {
+    Path::new(DIRECTORY).join(relative_components(header))
+}
+
+// The headers included by `file` as clang resolved them, next to their directives.
+fn included<'tu>(file: &CanonicalPath, per_file: &HashMap<CanonicalPath, Vec<Entity<'tu>>>) -> Vec<(Entity<'tu>, CanonicalPath)> {
+    per_file.get(file).into_iter().flatten()
+        .filter_map(|include| include.get_file().map(|f| (*include, CanonicalPath::new(f.get_path()))))
+        .collect()
+}
+
+fn per_file<'tu>(includes: &HashSet<Entity<'tu>>) -> HashMap<CanonicalPath, Vec<Entity<'tu>>> {
+    let mut ret = HashMap::new();
+    for include in includes {
+        ret.entry(CanonicalPath::new(get_path(include))).or_insert_with(Vec::new).push(*include);
+    }
+    ret
+}
+
+// `roots` and every header they end up including.
+pub fn closure(roots: BTreeSet<CanonicalPath>, includes: &HashSet<Entity>) -> BTreeSet<CanonicalPath> {
+    let per_file = per_file(includes);
+    let mut queue = roots.iter().cloned().collect::<VecDeque<_>>();
+    let mut ret = roots;
+
+    while let Some(header) = queue.pop_front() {
+        for (_, file) in included(&header, &per_file) {
+            if ret.insert(file.clone()) {
+                queue.push_back(file);
+            }
+        }
+    }
+
+    ret
+}
+
+// Copies `headers` under sysroot/, with their includes of each other pointing
+// at the copies so that the search paths of the toolchain don't matter,
+// unless `rewrite` is off.
+pub fn write(target: &Path, headers: &BTreeSet<CanonicalPath>, includes: &HashSet<Entity>, rewrite: bool) {
+    let per_file = per_file(includes);
+
+    for header in headers {
+        let output = location(&header.0);
+        let mut lines = source_file::read_lines(&header.0);
+
+        for (include, file) in included(header, &per_file) {
+            if !rewrite || !headers.contains(&file) {
+                continue
+            }
+
+            // Also takes care of #include_next and computed includes, which only mean anything with the original search paths
+            let range = include.get_range().unwrap();
+            let start = range.get_start().get_file_location().line as usize;
+            let end = range.get_end().get_file_location().line as usize;
+            lines[start - 1] = format!("#{} \"{}\"", directive(&include), include_spelling(&output, &location(&file.0)));
+            for line in &mut lines[start .. end] {
+                line.clear();
+            }
+        }
+
+        let path = target.join(&output);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let newline = source_file::newline(&header.0);
+        let mut text = lines.join(newline);
+        text.push_str(newline);
+        emit::write_if_changed(&path, source_file::encode(&text));
+    }
+}
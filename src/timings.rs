@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+// Wall time spent in every phase of a run and on every translation unit.
+// Phases are measured lap by lap: `lap` charges everything since the
+// previous lap to the given phase, so repeated phases add up.
+pub struct Timings {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    translation_units: Vec<(String, Duration)>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Timings { start: now, last: now, phases: vec![], translation_units: vec![] }
+    }
+
+    pub fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+
+        match self.phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some(entry) => entry.1 += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn add_translation_unit(&mut self, source: &str, elapsed: Duration) {
+        match self.translation_units.iter_mut().find(|(s, _)| s == source) {
+            Some(entry) => entry.1 += elapsed,
+            None => self.translation_units.push((String::from(source), elapsed)),
+        }
+    }
+
+    pub fn print(&self) {
+        let total = self.last - self.start;
+
+        eprintln!("Timings:");
+        for (phase, elapsed) in &self.phases {
+            eprintln!("  {:20} {:10.1} ms {:5.1}%", phase, millis(*elapsed), 100.0 * elapsed.as_secs_f64() / total.as_secs_f64().max(1e-9));
+        }
+        eprintln!("  {:20} {:10.1} ms", "total", millis(total));
+
+        // Slowest first, that's where pathological files show up
+        let mut translation_units = self.translation_units.iter().collect::<Vec<_>>();
+        translation_units.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        eprintln!("Translation units (parse and analysis):");
+        for (source, elapsed) in translation_units {
+            eprintln!("  {:10.1} ms  {}", millis(*elapsed), source);
+        }
+    }
+}
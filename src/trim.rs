@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use source_file::{decode, encode};
+
+// Replaces the original sources with their trimmed versions from `staging`
+// and, with `force`, deletes every considered file that didn't contribute to
+// the closure. `trimmed` maps the files relative to `source_directory` to
+// where they were emitted, which for some is outside of its layout.
+pub fn apply(
+    staging: &Path,
+    source_directory: &Path,
+    trimmed: &BTreeMap<PathBuf, PathBuf>,
+    considered: &BTreeSet<PathBuf>,
+    force: bool
+) {
+    for (file, output) in trimmed {
+        eprintln!("Trimming {:?}", file);
+        fs::copy(staging.join(output), source_directory.join(file)).unwrap();
+    }
+
+    let unneeded = considered.iter().filter(|f| !trimmed.contains_key(*f)).collect::<Vec<_>>();
+    for file in &unneeded {
+        if force {
+            eprintln!("Removing {:?}", file);
+            fs::remove_file(source_directory.join(file)).unwrap();
+        } else {
+            eprintln!("Not needed: {:?}", file);
+        }
+    }
+    if !force && !unneeded.is_empty() {
+        eprintln!("Pass --force to remove the {} files not needed", unneeded.len());
+    }
+
+    fs::remove_dir_all(staging).unwrap();
+}
+
+// Unified diff of `file` going from `original` to `trimmed`, which is missing when the file is removed.
+fn diff(file: &Path, original: &Path, trimmed: Option<&Path>) -> String {
+    let null = Path::new("/dev/null");
+    let output = Command::new("diff")
+        .arg("-u")
+        .arg("--label").arg(Path::new("a").join(file))
+        .arg("--label").arg(trimmed.map_or(null.to_path_buf(), |_| Path::new("b").join(file)))
+        .arg(original)
+        .arg(trimmed.unwrap_or(null))
+        .output()
+        .unwrap_or_else(|why| panic!("Couldn't run diff: {}", why));
+
+    // diff exits with 1 when the files differ and 2 when something went wrong
+    if output.status.code() == Some(2) {
+        panic!("diff failed on {:?}: {}", file, String::from_utf8_lossy(&output.stderr));
+    }
+
+    // Sources that aren't UTF-8 have to come out of the patch as they went in
+    decode(&output.stdout)
+}
+
+// Writes the trimming as a patch against the original tree, to be applied with `patch -p1`.
+pub fn write_patch(
+    staging: &Path,
+    source_directory: &Path,
+    trimmed: &BTreeMap<PathBuf, PathBuf>,
+    considered: &BTreeSet<PathBuf>,
+    patch: &Path
+) {
+    let mut contents = String::new();
+
+    for file in considered {
+        let original = source_directory.join(file);
+        let output = trimmed.get(file).map(|output| staging.join(output));
+        contents.push_str(&diff(file, &original, output.as_deref()));
+    }
+
+    fs::write(patch, encode(&contents)).unwrap();
+}
@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+use clang::*;
+
+use emit;
+use {get_path, in_system_header};
+
+pub const FILE_NAME: &str = "unity.c";
+
+// Orders `files` so that a file comes after everything it depends on.
+// Dependency cycles are broken by taking the first remaining file by path.
+pub fn order(files: &BTreeSet<PathBuf>, deps: &BTreeMap<PathBuf, BTreeSet<PathBuf>>) -> Vec<PathBuf> {
+    let mut remaining = files.clone();
+    let mut ret = vec![];
+
+    while !remaining.is_empty() {
+        let next = remaining.iter()
+            .find(|f| deps.get(*f).is_none_or(|d| d.iter().all(|d| !remaining.contains(d))))
+            .or_else(|| remaining.iter().next())
+            .unwrap()
+            .clone();
+
+        remaining.remove(&next);
+        ret.push(next);
+    }
+
+    ret
+}
+
+// Extracted `static` symbols sharing a name, which stop compiling once unity.c
+// includes their files together. Each group is ordered by file.
+pub fn colliding_statics<'a>(extracted: &HashSet<Entity<'a>>) -> Vec<Vec<Entity<'a>>> {
+    let mut per_name: BTreeMap<String, BTreeMap<String, Entity<'a>>> = BTreeMap::new();
+
+    for entity in extracted {
+        if !matches!(entity.get_kind(), EntityKind::FunctionDecl | EntityKind::VarDecl) ||
+            entity.get_linkage() != Some(Linkage::Internal) || in_system_header(entity)
+        {
+            continue
+        }
+        let (name, usr) = match (entity.get_name(), entity.get_usr()) {
+            (Some(name), Some(usr)) => (name, usr.0),
+            _ => continue,
+        };
+
+        // Prefer the definition so that the symbol is attributed to the right file
+        let known = per_name.entry(name).or_default().entry(usr).or_insert(*entity);
+        if entity.is_definition() && !known.is_definition() {
+            *known = *entity;
+        }
+    }
+
+    per_name.into_values()
+        .filter(|copies| copies.len() > 1)
+        .map(|copies| {
+            let mut copies = copies.into_values().collect::<Vec<_>>();
+            copies.sort_by_key(get_path);
+            copies
+        })
+        .collect()
+}
+
+// Name for a colliding static from `file`, e.g. `helper` from util.c becomes `helper_util`.
+pub fn disambiguated(name: &str, file: &Path) -> String {
+    let stem = file.file_stem().unwrap().to_str().unwrap();
+    let stem = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>();
+    format!("{}_{}", name, stem)
+}
+
+pub fn write(target_directory: &Path, files: &BTreeSet<PathBuf>, deps: &BTreeMap<PathBuf, BTreeSet<PathBuf>>) {
+    let mut contents = String::from("/* Single translation unit build of the extracted sources. */\n\n");
+
+    for file in order(files, deps) {
+        contents.push_str(&format!("#include \"{}\"\n", file.to_str().unwrap()));
+    }
+
+    emit::write_if_changed(&target_directory.join(FILE_NAME), contents);
+}
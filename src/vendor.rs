@@ -0,0 +1,143 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use archive::{collect_files, staging_directory};
+use canonicalize;
+use compile_db;
+use diff;
+use git;
+use json;
+
+pub const FILE_NAME: &str = "ccthief-vendor.json";
+
+// How a vendored tree was extracted: rerunning `arguments` from `directory`
+// reproduces it, and `revision` is the upstream commit it was taken from.
+pub struct Spec {
+    pub arguments: Vec<String>,
+    pub directory: PathBuf,
+    pub revision: Option<String>,
+}
+
+impl Spec {
+    // The spec of the running `ccthief vendor`. The output directory is left
+    // out since `update` picks where to extract to.
+    pub fn current(source_directory: &Path) -> Self {
+        let mut arguments = vec![];
+        let mut args = env::args().skip(2);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" | "-o" => { args.next(); },
+                "--" => {
+                    arguments.push(arg);
+                    arguments.extend(args.by_ref());
+                },
+                _ => arguments.push(arg),
+            }
+        }
+
+        Spec {
+            arguments,
+            directory: env::current_dir().unwrap(),
+            revision: git::provenance(source_directory).map(|p| p.commit),
+        }
+    }
+
+    pub fn load(target_directory: &Path) -> Option<Self> {
+        let spec = json::parse(&fs::read_to_string(target_directory.join(FILE_NAME)).ok()?).ok()?;
+        let arguments = spec.get("arguments")?.as_array()?.iter()
+            .map(|a| a.as_str().map(String::from))
+            .collect::<Option<_>>()?;
+
+        Some(Spec {
+            arguments,
+            directory: PathBuf::from(spec.get("directory")?.as_str()?),
+            revision: spec.get("revision")?.as_str().map(String::from),
+        })
+    }
+
+    pub fn write(&self, target_directory: &Path) {
+        let contents = json::object(&[
+            ("arguments", json::array(self.arguments.iter().map(|a| json::string(a)))),
+            ("directory", json::string(self.directory.to_str().unwrap())),
+            ("revision", json::optional(self.revision.as_ref().map(|r| json::string(r)))),
+        ]);
+        fs::write(target_directory.join(FILE_NAME), contents + "\n").unwrap();
+    }
+}
+
+// Re-extracts the vendored tree in `target_directory` from `upstream`, or
+// from the checkout it was vendored from, prints what changed and replaces
+// the tree with the new extraction.
+pub fn update(target_directory: &Path, upstream: Option<&Path>) {
+    let spec = Spec::load(target_directory).unwrap_or_else(|| {
+        eprintln!("{:?} has no {}, vendor it with `ccthief vendor` first", target_directory, FILE_NAME);
+        process::exit(1);
+    });
+
+    let directory = upstream.map_or_else(|| spec.directory.clone(), Path::to_path_buf);
+    let staging = staging_directory();
+
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.arg("vendor")
+        .args(&spec.arguments)
+        .arg("--output").arg(&staging)
+        .current_dir(&directory);
+
+    eprintln!("Re-extracting from {:?}", directory);
+    let status = command.status().unwrap_or_else(|why| panic!("Couldn't run {:?}: {}", command, why));
+    if !status.success() {
+        eprintln!("The extraction failed: {:?} exited with {}", command, status);
+        process::exit(1);
+    }
+
+    let revision = Spec::load(&staging).and_then(|s| s.revision);
+    let describe = |revision: &Option<String>| revision.clone().unwrap_or_else(|| String::from("an unknown revision"));
+    println!("Updating from {} to {}", describe(&spec.revision), describe(&revision));
+
+    diff::report(&staging, target_directory);
+
+    // The full patch, for reviewing the update before committing it
+    let mut command = Command::new("diff");
+    command.arg("-ruN");
+    for metadata in diff::METADATA {
+        command.arg("-x").arg(metadata);
+    }
+    let status = command
+        .arg(target_directory)
+        .arg(&staging)
+        .status()
+        .unwrap_or_else(|why| panic!("Couldn't run diff: {}", why));
+    if status.code() == Some(0) {
+        println!("Nothing changed");
+    }
+
+    let mut old_files = vec![];
+    collect_files(target_directory, &mut old_files);
+    for file in old_files {
+        fs::remove_file(file).unwrap();
+    }
+
+    // The compile database names the directory it was written to, and with
+    // absolute paths the files in it, so the opening quote is where to match
+    let quoted = |path: &Path| {
+        let quoted = json::string(canonicalize(path).unwrap().to_str().unwrap());
+        String::from(&quoted[..quoted.len() - 1])
+    };
+    let (from, to) = (quoted(&staging), quoted(target_directory));
+
+    let mut new_files = vec![];
+    collect_files(&staging, &mut new_files);
+    for file in new_files {
+        let path = target_directory.join(file.strip_prefix(&staging).unwrap());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        if path.ends_with(compile_db::FILE_NAME) {
+            fs::write(&path, fs::read_to_string(&file).unwrap().replace(&from, &to)).unwrap();
+        } else {
+            fs::copy(&file, &path).unwrap();
+        }
+    }
+
+    fs::remove_dir_all(&staging).unwrap();
+}
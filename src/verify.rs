@@ -0,0 +1,129 @@
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use compile_db::absolute_args;
+use source_map::SourceMap;
+
+// Where the emitted `line` of `file` was copied from, if anywhere.
+fn original(source_map: &SourceMap, file: &Path, line: u32) -> Option<(PathBuf, u32)> {
+    source_map.mappings.iter()
+        .find(|m| m.output == file && m.output_lines.0 <= line && line <= m.output_lines.1)
+        .map(|m| (m.original.clone(), m.original_lines.0 + line - m.output_lines.0))
+}
+
+// Compiles every emitted source with the flags of the extraction and prints
+// the diagnostics, pointing at the original sources wherever the offending
+// line was copied from. Returns whether everything compiled without errors.
+pub fn verify(target_directory: &Path, emitted_sources: &[PathBuf], clang_args: &[String], source_map: &SourceMap) -> bool {
+    // Relative paths in the flags are relative to here, not to the output
+    let clang_args = absolute_args(clang_args, &env::current_dir().unwrap());
+    let mut errors = 0;
+    let mut warnings = 0;
+
+    for source in emitted_sources {
+        let output = Command::new("clang")
+            .arg("-fsyntax-only")
+            .arg("-fno-color-diagnostics")
+            .arg("-I.")
+            .args(&clang_args)
+            .arg(source)
+            .current_dir(target_directory)
+            .output()
+            .unwrap_or_else(|why| panic!("Couldn't run clang: {}", why));
+
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            // file:line:column: severity: message
+            let parts = line.splitn(5, ':').collect::<Vec<_>>();
+            let location = match parts.as_slice() {
+                [file, line, _, severity, _] => line.parse::<u32>().ok().map(|line| (*file, line, severity.trim())),
+                _ => None,
+            };
+
+            match location {
+                Some((file, line_number, severity)) => {
+                    match severity {
+                        "error" | "fatal error" => errors += 1,
+                        "warning" => warnings += 1,
+                        _ => (),
+                    }
+
+                    let file = Path::new(file.trim_start_matches("./"));
+                    match original(source_map, file, line_number) {
+                        Some((path, original_line)) =>
+                            eprintln!("{} (originally {}:{})", line, path.to_str().unwrap(), original_line),
+                        None => eprintln!("{}", line),
+                    }
+                },
+                None => eprintln!("{}", line),
+            }
+        }
+    }
+
+    eprintln!("Verification: {} errors, {} warnings", errors, warnings);
+    errors == 0
+}
+
+// Symbols listed by `nm` with the given `flag`.
+fn symbols(object: &Path, flag: &str) -> Vec<String> {
+    let output = Command::new("nm")
+        .arg(flag)
+        .arg(object)
+        .output()
+        .unwrap_or_else(|why| panic!("Couldn't run nm: {}", why));
+
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .map(String::from)
+        .collect()
+}
+
+// Compiles the emitted sources to objects and checks that every symbol they
+// need is either defined by one of them or `allowed` to come from outside,
+// i.e. libc and the boundary headers. Returns whether the closure is complete.
+pub fn check_link(target_directory: &Path, emitted_sources: &[PathBuf], clang_args: &[String], allowed: &HashSet<String>) -> bool {
+    let clang_args = absolute_args(clang_args, &env::current_dir().unwrap());
+    let objects = env::temp_dir().join(format!("ccthief-objects-{}", process::id()));
+    fs::create_dir_all(&objects).unwrap();
+
+    let mut defined = HashSet::new();
+    let mut undefined = BTreeSet::new();
+
+    for (i, source) in emitted_sources.iter().enumerate() {
+        let object = objects.join(format!("{}.o", i));
+        let status = Command::new("clang")
+            .arg("-c")
+            .arg("-I.")
+            .args(&clang_args)
+            .arg(source)
+            .arg("-o").arg(&object)
+            .current_dir(target_directory)
+            .status()
+            .unwrap_or_else(|why| panic!("Couldn't run clang: {}", why));
+
+        if !status.success() {
+            eprintln!("Couldn't compile {:?}, skipping it in the link check", source);
+            continue
+        }
+
+        defined.extend(symbols(&object, "--defined-only"));
+        for symbol in symbols(&object, "--undefined-only") {
+            undefined.insert((symbol, source.clone()));
+        }
+    }
+
+    fs::remove_dir_all(&objects).unwrap();
+
+    let missing = undefined.into_iter()
+        // Mach-O prefixes every C name with an underscore
+        .filter(|(symbol, _)| !defined.contains(symbol) && !allowed.contains(symbol.trim_start_matches('_')))
+        .collect::<Vec<_>>();
+
+    for (symbol, source) in &missing {
+        eprintln!("Undefined symbol {} referenced from {}", symbol, source.to_str().unwrap());
+    }
+    eprintln!("Link check: {} undefined symbols", missing.len());
+    missing.is_empty()
+}